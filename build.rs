@@ -1,9 +1,22 @@
 fn main() {
     linker_be_nice();
+    embed_ca_cert();
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
 }
 
+/// Copy the CA certificate of the MQTT broker to `OUT_DIR/ca.der`
+///
+/// `certs/ca.der` is optional, an empty file is embedded when it is missing.
+fn embed_ca_cert() {
+    const CA_PATH: &str = "certs/ca.der";
+    println!("cargo:rerun-if-changed={CA_PATH}");
+
+    let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("ca.der");
+    let cert = std::fs::read(CA_PATH).unwrap_or_default();
+    std::fs::write(out, cert).unwrap();
+}
+
 fn linker_be_nice() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
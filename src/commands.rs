@@ -0,0 +1,94 @@
+//! Downlink commands received by the MQTT task.
+//!
+//! Commands are only recorded when they arrive. Those touching the RTC state, the stored settings
+//! or the power state are carried out by the main task when the active window closes.
+
+use crate::{
+    rtc_manager::{with_rain_log, RtcManager},
+    settings::{self, settings},
+    tasks::as5600_task::last_raw_angle,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_time::{Duration, Instant};
+use log::{error, info};
+use weather_core::{command::Command, rtc::RtcMemory};
+
+static CHECK_OTA: AtomicBool = AtomicBool::new(false);
+static REBOOT: AtomicBool = AtomicBool::new(false);
+static RESET_RAIN: AtomicBool = AtomicBool::new(false);
+static CALIBRATE_NORTH: AtomicBool = AtomicBool::new(false);
+static RESET_SETTINGS: AtomicBool = AtomicBool::new(false);
+// 0 when unchanged
+static SLEEP_INTERVAL_S: AtomicU32 = AtomicU32::new(0);
+// seconds since boot, 0 when not requested
+static STAY_AWAKE_UNTIL_S: AtomicU32 = AtomicU32::new(0);
+
+/// Record a command received during the window
+pub fn schedule(command: Command) {
+    info!("Command: {:?}", command);
+
+    match command {
+        Command::CheckOta => CHECK_OTA.store(true, Ordering::Relaxed),
+        Command::SetSleepInterval(secs) => SLEEP_INTERVAL_S.store(secs as u32, Ordering::Relaxed),
+        Command::StayAwake(minutes) => {
+            let until = Instant::now() + Duration::from_secs(minutes as u64 * 60);
+            STAY_AWAKE_UNTIL_S.store(until.as_secs() as u32, Ordering::Relaxed);
+        }
+        Command::ResetRain => RESET_RAIN.store(true, Ordering::Relaxed),
+        Command::Reboot => REBOOT.store(true, Ordering::Relaxed),
+        Command::CalibrateNorth => CALIBRATE_NORTH.store(true, Ordering::Relaxed),
+        Command::ResetSettings => RESET_SETTINGS.store(true, Ordering::Relaxed),
+    }
+}
+
+/// End of the debugging period requested by a `stay_awake` command
+pub fn stay_awake_until() -> Option<Instant> {
+    match STAY_AWAKE_UNTIL_S.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(Instant::from_secs(secs as u64)),
+    }
+}
+
+/// Carry out the commands affecting RTC memory and the stored settings
+pub fn apply(rtc_manager: &RtcManager) {
+    if RESET_RAIN.swap(false, Ordering::Relaxed) {
+        rtc_manager.store_rain_tips(0);
+        rtc_manager.store_last_tip(0);
+        with_rain_log(|log| log.clear());
+    }
+
+    let sleep_interval = SLEEP_INTERVAL_S.swap(0, Ordering::Relaxed);
+    if sleep_interval != 0 {
+        rtc_manager.store_sleep_interval_s(sleep_interval as u64);
+    }
+
+    if CALIBRATE_NORTH.swap(false, Ordering::Relaxed) {
+        calibrate_north();
+    }
+
+    // last, so that it also undoes a calibration received in the same window
+    if RESET_SETTINGS.swap(false, Ordering::Relaxed) && settings::reset().is_ok() {
+        info!("Stored settings dropped, defaults apply from the next boot");
+    }
+}
+
+/// Save the last vane angle of the window as north, it takes effect on the next boot
+fn calibrate_north() {
+    let Some(raw) = last_raw_angle() else {
+        error!("No vane angle read during the window, north is not calibrated");
+        return;
+    };
+
+    let mut updated = settings().clone();
+    updated.vane_zero_raw = raw;
+    if settings::save(&updated).is_ok() {
+        info!("Vane north set to raw angle {}", raw);
+    }
+}
+
+/// Whether the station must restart instead of sleeping
+///
+/// Firmware updates are checked at boot, so restarting is also how a `ota` command is served.
+pub fn restart_requested() -> bool {
+    REBOOT.load(Ordering::Relaxed) || CHECK_OTA.load(Ordering::Relaxed)
+}
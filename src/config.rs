@@ -4,12 +4,18 @@ pub struct Config {
     ssid: &'static str,
     #[default("")]
     wifi_pass: &'static str,
-    #[default("test.mosquitto.org")]
+    #[default("")]
     broker_url: &'static str,
     #[default("192.168.1.69")]
     broker_ip: &'static str,
     #[default(1883)]
     broker_port: u16,
+    #[default(false)]
+    mqtt_tls: bool,
+    #[default(8883)]
+    broker_tls_port: u16,
+    #[default("")]
+    tls_server_name: &'static str,
     #[default("mqtt_user")]
     mqtt_user: &'static str,
     #[default("")]
@@ -28,12 +34,121 @@ pub struct Config {
     ota_url: &'static str,
     #[default(15)]
     rain_debounce_s: u64,
+    #[default(false)]
+    state_json: bool,
+    #[default(false)]
+    ha_discovery: bool,
+    #[default("homeassistant")]
+    ha_prefix: &'static str,
+    #[default("weather_station")]
+    device_id: &'static str,
+    #[default("Weather Station")]
+    device_name: &'static str,
+    #[default(true)]
+    mqtt_lwt: bool,
+    #[default(60)]
+    mqtt_keep_alive_secs: u16,
+    #[default("pool.ntp.org")]
+    ntp_server: &'static str,
+    #[default(true)]
+    mqtt_commands: bool,
+    #[default("")]
+    provision_pass: &'static str,
+    #[default("")]
+    ssid_2: &'static str,
+    #[default("")]
+    wifi_pass_2: &'static str,
+    #[default("")]
+    ssid_3: &'static str,
+    #[default("")]
+    wifi_pass_3: &'static str,
+    #[default(false)]
+    wifi_scan: bool,
+    #[default("")]
+    static_ip: &'static str,
+    #[default("")]
+    static_gateway: &'static str,
+    #[default("")]
+    static_dns: &'static str,
+    #[default(30)]
+    low_soc_percent: u8,
+    #[default(3600)]
+    low_soc_sleep_secs: u64,
+    #[default(3450)]
+    survival_mv: u16,
+    #[default(14400)]
+    survival_sleep_secs: u64,
+    #[default(50)]
+    charging_ma: u16,
+    #[default(100)]
+    ina_shunt_mohm: u16,
+    #[default(500)]
+    ina_max_current_ma: u16,
+    #[default(160)]
+    ina_bus_offset_mv: i16,
+    #[default(1000)]
+    sleep_current_ua: u16,
+    #[default("li-ion")]
+    battery_chemistry: &'static str,
+    #[default("")]
+    battery_curve: &'static str,
+    #[default(1)]
+    battery_cells: u8,
+    #[default(80)]
+    battery_internal_mohm: u16,
+    #[default(231)]
+    rain_um_per_tip: u16,
+    #[default("stock")]
+    anemo_model: &'static str,
+    #[default(1)]
+    anemo_pulses_per_rev: u8,
+    #[default(1050)]
+    anemo_mm_per_rev: u16,
+    #[default(0)]
+    anemo_offset_mms: u16,
+    #[default("")]
+    anemo_table: &'static str,
+    #[default(5)]
+    anemo_max_hz: u16,
+    #[default(false)]
+    vane_speed_weighted: bool,
+    #[default(0)]
+    vane_zero_raw: u16,
+    #[default(true)]
+    vane_mirrored: bool,
 }
 
-pub const MAX_RETRY: i32 = 5;
-pub const SOCKET_TIMEOUT: u64 = 120;
-pub const BUFFER_SIZE: usize = 2048;
+pub const SOCKET_TIMEOUT: u64 = 10; // a stalled broker must not outlive the active window
+pub const MQTT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const MQTT_MAX_RECONNECTS: u32 = 4;
+pub const MQTT_BACKOFF_INITIAL_MS: u64 = 1000;
+pub const MQTT_BACKOFF_MAX_MS: u64 = 8000;
+pub const BUFFER_SIZE: usize = 4096; // holds the largest packet, the state document
+pub const MQTT_HEADER_SIZE: usize = 32; // fixed header, topic length, packet id and properties
 pub const DEFAULT_STRING_SIZE: usize = 70;
 pub const PAYLOAD_SIZE: usize = 20;
+pub const STATE_PAYLOAD_SIZE: usize = weather_core::state::MAX_STATE_JSON_LEN;
+pub const DISCOVERY_PAYLOAD_SIZE: usize = 768;
+pub const DISCOVERY_INTERVAL_WINDOWS: u32 = 72;
+pub const TIME_SYNC_INTERVAL_WINDOWS: u32 = 72;
+pub const STATUS_SIZE: usize = 32;
+pub const POWER_PAYLOAD_SIZE: usize = 128;
+pub const DIAGNOSTICS_PAYLOAD_SIZE: usize = 512;
 pub const TOPIC_SIZE: usize = 70;
 pub const CHANNEL_SIZE: usize = 5;
+pub const BACKLOG_CAPACITY: usize = 128; // readings kept while the broker is unreachable
+pub const BACKLOG_PAYLOAD_SIZE: usize = 128;
+pub const ANEMO_MAX_PULSES: usize = 1024; // pulses timestamped per window for the gust statistics
+pub const RAIN_LOG_CAPACITY: usize = 512; // tips kept for the rain totals, 118 mm with the stock bucket
+pub const COMMAND_SIZE: usize = 128;
+pub const ACK_SIZE: usize = 192;
+pub const COMMAND_WAIT_MS: u64 = 500; // how long to wait for a retained command
+pub const COMMAND_POLL_SECS: u64 = 10; // command check period while staying awake
+pub const SETTINGS_SIZE: usize = 2048; // encoded runtime settings, see `settings`
+pub const PROVISION_AFTER_FAILURES: u8 = 3; // boots without network before opening the portal
+pub const PROVISION_TIMEOUT_SECS: u64 = 600;
+pub const NETWORK_BACKOFF_MAX_SECS: u64 = 21600; // longest sleep while the network is down
+pub const DHCP_LEASE_SECS: u64 = 3600; // lease reuse limit, embassy-net does not report the granted one
+
+// rust-mqtt encodes a whole PUBLISH in the client buffer, the state document must fit in it
+const _: () = assert!(BUFFER_SIZE >= STATE_PAYLOAD_SIZE + TOPIC_SIZE + MQTT_HEADER_SIZE);
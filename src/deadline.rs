@@ -0,0 +1,85 @@
+//! Transport bounding the wait for the next MQTT packet.
+//!
+//! `rust-mqtt` cannot wait for a packet with a timeout: cancelling `receive_message` once part of
+//! a packet was read leaves the session out of step with the broker. The deadline of
+//! `DeadlineTransport` only applies while waiting for the first byte, which clears it, so the rest
+//! of the packet is always read in full.
+
+use core::cell::Cell;
+use embassy_time::{Instant, TimeoutError, WithTimeout};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+
+/// Deadline shared between the MQTT task and its transport
+#[derive(Default)]
+pub struct ReadDeadline {
+    at: Cell<Option<Instant>>,
+    expired: Cell<bool>,
+}
+
+impl ReadDeadline {
+    /// Give up the next read at `at` unless a byte arrives first
+    pub fn arm(&self, at: Instant) {
+        self.at.set(Some(at));
+        self.expired.set(false);
+    }
+
+    /// Whether a read gave up since the deadline was armed
+    pub fn expired(&self) -> bool {
+        self.expired.get()
+    }
+}
+
+#[derive(Debug)]
+pub enum DeadlineError<E> {
+    TimedOut,
+    Io(E),
+}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for DeadlineError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DeadlineError::TimedOut => ErrorKind::TimedOut,
+            DeadlineError::Io(e) => e.kind(),
+        }
+    }
+}
+
+pub struct DeadlineTransport<'d, T> {
+    inner: T,
+    deadline: &'d ReadDeadline,
+}
+
+impl<'d, T> DeadlineTransport<'d, T> {
+    pub fn new(inner: T, deadline: &'d ReadDeadline) -> Self {
+        Self { inner, deadline }
+    }
+}
+
+impl<T: ErrorType> ErrorType for DeadlineTransport<'_, T> {
+    type Error = DeadlineError<T::Error>;
+}
+
+impl<T: Read> Read for DeadlineTransport<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let Some(at) = self.deadline.at.take() else {
+            return self.inner.read(buf).await.map_err(DeadlineError::Io);
+        };
+        match self.inner.read(buf).with_deadline(at).await {
+            Ok(res) => res.map_err(DeadlineError::Io),
+            Err(TimeoutError) => {
+                self.deadline.expired.set(true);
+                Err(DeadlineError::TimedOut)
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for DeadlineTransport<'_, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.inner.write(buf).await.map_err(DeadlineError::Io)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await.map_err(DeadlineError::Io)
+    }
+}
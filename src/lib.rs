@@ -11,43 +11,72 @@
 
 #[macro_use]
 pub mod utils;
+pub mod commands;
 pub mod config;
+pub mod deadline;
 pub mod network;
+pub mod provisioning;
 pub mod rtc_manager;
 pub mod sensors;
+pub mod settings;
 pub mod tasks;
+pub mod tls;
 
 use crate::{
-    config::CONFIG,
-    rtc_manager::RtcManager,
+    config::DISCOVERY_INTERVAL_WINDOWS,
+    rtc_manager::{
+        power_mode, store_power_mode, timestamp, unix_offset, with_rain_log,
+        with_sensor_diagnostics, RtcManager,
+    },
     sensors::Sensors,
+    settings::settings,
     tasks::{
         anemo_task::anemo_task,
         as5600_task::as5600_task,
         dht_task::dht_task,
-        ina219_task::ina210_task,
-        mqtt_task::{mqtt_task, MQTT_CHANNEL},
+        ina219_task::{ina210_task, BATTERY},
+        mqtt_task::{
+            buffer_task, mqtt_task, WindowEnd, END_OF_WINDOW, MQTT_CHANNEL, WINDOW_CLOSED,
+        },
+        wifi_task::last_rssi,
     },
 };
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
 use embassy_executor::Spawner;
 use embassy_net::Stack;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer, WithTimeout};
 use esp_hal::{
     i2c::master::I2c,
     peripherals::TIMG1,
+    rtc_cntl::wakeup_cause,
+    system::SleepSource,
     time::Duration,
     timer::timg::{TimerGroup, Wdt},
     Async,
 };
+use heapless::String;
+use log::{error, info};
+use weather_core::{
+    power::{self, BatteryState, PowerPolicy},
+    rain::RainGauge,
+    reading::{Quality, Quantity, Reading, Value},
+    rtc::RtcMemory,
+    state::StateContext,
+    status::{write_sleeping, REBOOTING},
+};
 
-type ShareI2cBus = &'static mut I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>;
+const WINDOW_CLOSE_TIMEOUT_SECS: u64 = 5;
+const WATCHDOG_FEED_SECS: u64 = 10;
+const SECONDS_PER_DAY: u64 = 86_400;
+
+pub(crate) type ShareI2cBus =
+    &'static mut I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>;
 
 pub fn init_watchdog(timer_group1: TIMG1) -> Wdt<TIMG1> {
     let mut watchdog = TimerGroup::new(timer_group1).wdt;
 
-    let watchdog_timeout = Duration::from_secs(CONFIG.main_task_dur_secs + 10);
+    let watchdog_timeout = Duration::from_secs(settings().main_task_dur_secs + 10);
     watchdog.set_timeout(esp_hal::timer::timg::MwdtStage::Stage0, watchdog_timeout);
     watchdog.enable();
 
@@ -56,16 +85,19 @@ pub fn init_watchdog(timer_group1: TIMG1) -> Wdt<TIMG1> {
 
 /// Runs the active measurement phase.
 ///
-/// This function assumes that network connectivity is already available and
-/// that OTA handling has already completed. It spawns the measurement tasks,
-/// publishes accumulated rain data stored in RTC memory, waits for the active
-/// tasks to complete, and prepare the board for the next sleep
+/// This function assumes that OTA handling has already completed. It spawns the
+/// measurement tasks, publishes accumulated rain data stored in RTC memory, waits
+/// for the active tasks to complete, and prepare the board for the next sleep.
+/// Without network (`stack` is `None`) the readings are kept in the RTC backlog
+/// and the next sleep is stretched. `window` is the number returned by
+/// `inc_window_count` for this window.
 pub async fn run_active_window(
     spawner: &Spawner,
     rtc_manager: &mut RtcManager,
     watchdog: &mut Wdt<TIMG1<'static>>,
     mut sensors: Sensors,
-    stack: Stack<'static>,
+    stack: Option<Stack<'static>>,
+    window: u32,
 ) {
     // Create communication channels
     let receiver = MQTT_CHANNEL.receiver();
@@ -76,7 +108,12 @@ pub async fn run_active_window(
 
     // spawn the tasks
     let (ina_i2c, as_i2c) = make_i2c_dev(sensors.i2c_bus);
-    spawner.spawn(mqtt_task(stack, receiver)).unwrap();
+    // discovery configs are retained, refreshing them once in a while is enough
+    let announce = settings().ha_discovery && window % DISCOVERY_INTERVAL_WINDOWS == 1;
+    match stack {
+        Some(stack) => spawner.spawn(mqtt_task(stack, receiver, announce)).unwrap(),
+        None => spawner.spawn(buffer_task(receiver)).unwrap(),
+    }
     spawner
         .spawn(dht_task(sensors.dht_pin, sender_dht))
         .unwrap();
@@ -86,23 +123,159 @@ pub async fn run_active_window(
     spawner.spawn(as5600_task(as_i2c, sender_as5600)).unwrap();
     spawner.spawn(ina210_task(ina_i2c, sender_ina219)).unwrap();
 
-    //publish accumulated rain and reset RTC memory
-    publish!(
-        MQTT_CHANNEL.sender(),
-        "rain",
-        rtc_manager.load_rain_tips() as f32 * 0.231
-    );
-    rtc_manager.store_rain_tips(0);
+    let rain_stuck = rtc_manager.check_rain_sensor().await;
+    publish_rain(rtc_manager, rain_stuck).await;
 
     // wait for tasks to perform their jobs
     watchdog.feed();
-    Timer::after_secs(CONFIG.main_task_dur_secs).await;
+    Timer::after_secs(settings().main_task_dur_secs).await;
+    stay_awake(watchdog).await;
+
+    commands::apply(rtc_manager);
+    let battery = BATTERY.try_take();
+    let policy =
+        settings()
+            .power_thresholds()
+            .policy(battery, power_mode(), rtc_manager.sleep_interval_s());
+    if policy.mode != power_mode() {
+        info!("Power mode: {}", policy.mode.name());
+        store_power_mode(policy.mode);
+    }
+    let sleep_s = rtc_manager.backoff_sleep_s(policy.sleep_s);
+    if stack.is_none() {
+        info!("No network, next attempt in {} s", sleep_s);
+    }
+    close_window(rtc_manager, sleep_s, &policy, battery.as_ref()).await;
+    watchdog.feed();
 
-    rtc_manager.set_next_full_measurement_s(CONFIG.deep_sleep_dur_secs);
-    rtc_manager.set_deep_sleep_timer(core::time::Duration::from_secs(CONFIG.deep_sleep_dur_secs));
+    rtc_manager.schedule_sleep(sleep_s);
     sensors.transistor_pin.set_low(); //turn off peripherals
 }
 
+/// Publish the rain accumulated since the last window, rain rate and totals
+///
+/// The tips counted since the last window are then cleared, the tip log keeps the history the
+/// totals are computed from. With the reed switch `stuck`, tips are missed and the figures are
+/// degraded.
+async fn publish_rain(rtc_manager: &RtcManager, stuck: bool) {
+    let gauge = settings().rain_gauge().unwrap_or_else(|| {
+        error!("Invalid rain gauge calibration, using the default one");
+        RainGauge::default()
+    });
+    let now = timestamp();
+    let midnight = unix_offset().map(|offset| now.saturating_sub((offset + now) % SECONDS_PER_DAY));
+    let rain = with_rain_log(|log| log.summary(&gauge, now, midnight));
+    // totals miss tips once the log overflowed
+    let totals_quality = if rain.complete && !stuck {
+        Quality::Good
+    } else {
+        Quality::Degraded
+    };
+    let count_quality = if stuck {
+        Quality::Degraded
+    } else {
+        Quality::Good
+    };
+
+    let tips = rtc_manager.load_rain_tips();
+    let readings = [
+        Some(Reading::new(Quantity::Rain, gauge.mm(tips)).with_quality(count_quality)),
+        Some(Reading::new(Quantity::RainRate, rain.rate_mm_h).with_quality(count_quality)),
+        Some(Reading::new(Quantity::RainLastHour, rain.last_hour_mm).with_quality(totals_quality)),
+        Some(Reading::new(Quantity::RainLast24h, rain.last_24h_mm).with_quality(totals_quality)),
+        rain.today_mm
+            .map(|mm| Reading::new(Quantity::RainToday, mm).with_quality(totals_quality)),
+        // as wall clock time, once the clock is synchronised
+        rain.last_tip_s.zip(unix_offset()).map(|(tip, offset)| {
+            Reading::new(Quantity::RainLastTip, Value::Timestamp(tip + offset))
+        }),
+        Some(Reading::new(
+            Quantity::RainSensor,
+            if stuck { "stuck" } else { "ok" },
+        )),
+    ];
+
+    for reading in readings.into_iter().flatten() {
+        MQTT_CHANNEL.send(reading.with_timestamp(now)).await;
+    }
+    rtc_manager.store_rain_tips(0);
+}
+
+/// Close the MQTT session of the window
+///
+/// Hands the state document (if enabled), the power policy and the sleeping status to the MQTT
+/// task, then waits for it to disconnect from the broker. A clean disconnect keeps the broker
+/// from publishing the last will while the station sleeps.
+async fn close_window(
+    rtc_manager: &RtcManager,
+    sleep_s: u64,
+    policy: &PowerPolicy,
+    battery: Option<&BatteryState>,
+) {
+    let state = settings().state_json.then(|| StateContext {
+        timestamp: timestamp(),
+        boot_count: rtc_manager.load_boot_count(),
+        wake_reason: wake_reason(),
+        firmware_version: env!("CARGO_PKG_VERSION"),
+        rssi: last_rssi(),
+    });
+
+    let mut status = String::new();
+    if commands::restart_requested() {
+        let _ = status.push_str(REBOOTING);
+    } else {
+        let wake_at = rtc_manager.unix_time(rtc_manager.now_s() + sleep_s);
+        let _ = write_sleeping(&mut status, wake_at, sleep_s);
+    }
+
+    let mut power = String::new();
+    let _ = power::write_json(&mut power, policy, battery);
+
+    let mut diagnostics = String::new();
+    if with_sensor_diagnostics(|d| d.write_json(&mut diagnostics)).is_err() {
+        error!("Sensor diagnostics do not fit in their payload");
+    }
+
+    END_OF_WINDOW.signal(WindowEnd {
+        state,
+        power,
+        diagnostics,
+        status,
+    });
+
+    if WINDOW_CLOSED
+        .wait()
+        .with_timeout(embassy_time::Duration::from_secs(WINDOW_CLOSE_TIMEOUT_SECS))
+        .await
+        .is_err()
+    {
+        error!("MQTT session was not closed in time");
+    }
+}
+
+/// Keep the window open while a `stay_awake` command is active
+async fn stay_awake(watchdog: &mut Wdt<TIMG1<'static>>) {
+    let Some(until) = commands::stay_awake_until() else {
+        return;
+    };
+    info!("Staying awake for debugging");
+
+    while Instant::now() < until {
+        watchdog.feed();
+        let next_feed = Instant::now() + embassy_time::Duration::from_secs(WATCHDOG_FEED_SECS);
+        Timer::at(until.min(next_feed)).await;
+    }
+}
+
+fn wake_reason() -> &'static str {
+    match wakeup_cause() {
+        SleepSource::Timer => "timer",
+        SleepSource::Ext0 => "rain",
+        SleepSource::Undefined => "power_on",
+        _ => "other",
+    }
+}
+
 /// Create sharable instance of the i2c bus
 fn make_i2c_dev(
     i2c_bus: &'static Mutex<CriticalSectionRawMutex, I2c<'static, Async>>,
@@ -15,12 +15,17 @@ use esp_hal::{
     timer::timg::TimerGroup,
 };
 use log::info;
+use weather_core::{power::PowerMode, rtc::RtcMemory};
 use weather_station_embassy::{
+    commands,
+    config::TIME_SYNC_INTERVAL_WINDOWS,
     init_watchdog,
-    network::bring_network_up,
-    rtc_manager::RtcManager,
+    network::{bring_network_up, sync_clock},
+    provisioning,
+    rtc_manager::{inc_window_count, power_mode, RtcManager},
     run_active_window,
     sensors::Sensors,
+    settings,
     tasks::ota_task::{check_for_ota, init_ota},
 };
 
@@ -39,9 +44,11 @@ async fn main(spawner: Spawner) -> ! {
     esp_println::logger::init_logger_from_env();
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
-    let p = esp_hal::init(config);
+    let mut p = esp_hal::init(config);
     esp_alloc::heap_allocator!(#[unsafe(link_section = ".dram2_uninit")] size: 98767);
 
+    settings::load(p.FLASH.reborrow());
+
     let mut watchdog = init_watchdog(p.TIMG1);
 
     //Instanciate peripherals and i2c bus
@@ -51,19 +58,60 @@ async fn main(spawner: Spawner) -> ! {
 
     let mut rtc_manager = RtcManager::new(p.GPIO25, p.LPWR);
     rtc_manager.init_next_full_measurement();
+    rtc_manager.inc_boot_count();
 
     if let SleepSource::Ext0 = wakeup_cause() {
         rtc_manager.handle_external_wakeup().await;
     }
 
-    let stack = bring_network_up(p.WIFI, &spawner).await;
+    let survival = power_mode() == PowerMode::Survival;
+    if !survival && provisioning::required() {
+        watchdog.disable();
+        provisioning::run(p.WIFI, &spawner).await;
+        rtc_manager.schedule_sleep(rtc_manager.backoff_sleep_s(rtc_manager.sleep_interval_s()));
+        info!("Going to sleep...");
+        rtc_manager.sleep();
+        panic!();
+    }
+
+    // rain wakeups and provisioning boots are not windows
+    let window = inc_window_count();
+
+    // without network the station still measures, readings wait in the backlog
+    let stack = if survival {
+        info!("Survival mode, Wi-Fi skipped");
+        None
+    } else {
+        bring_network_up(p.WIFI, &spawner).await
+    };
+    watchdog.feed();
 
-    let ota_handle = init_ota(p.FLASH);
-    check_for_ota(stack, ota_handle, &mut watchdog).await;
+    if let Some(stack) = stack {
+        if rtc_manager.load_unix_offset() == 0 || window % TIME_SYNC_INTERVAL_WINDOWS == 1 {
+            sync_clock(stack, &rtc_manager).await;
+        }
 
-    run_active_window(&spawner, &mut rtc_manager, &mut watchdog, sensors, stack).await;
+        let ota_handle = init_ota(p.FLASH);
+        check_for_ota(stack, ota_handle, &mut watchdog).await;
+    }
+
+    run_active_window(
+        &spawner,
+        &mut rtc_manager,
+        &mut watchdog,
+        sensors,
+        stack,
+        window,
+    )
+    .await;
     watchdog.disable();
 
+    if commands::restart_requested() {
+        info!("Restarting as requested...");
+        Timer::after_secs(1).await;
+        software_reset();
+    }
+
     info!("Going to sleep...");
     Timer::after_secs(1).await;
     rtc_manager.sleep();
@@ -1,52 +1,194 @@
-use crate::tasks::wifi_task::{runner_task, wifi_task};
+use crate::config::DHCP_LEASE_SECS;
+use crate::rtc_manager::{
+    store_wifi_failures, timestamp, wifi_failures, with_connection_cache, RtcManager,
+};
+use crate::settings::settings;
+use crate::tasks::wifi_task::{runner_task, wifi_task, JOINED, WIFI_STOP};
 use embassy_executor::Spawner;
-use embassy_net::{Runner, Stack, StackResources};
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    ConfigV4, IpAddress, Ipv4Address, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4,
+};
 use embassy_time::{Duration, TimeoutError, WithTimeout};
 use esp_hal::{peripherals::WIFI, rng::Rng};
 use esp_radio::{
-    wifi::{WifiController, WifiDevice},
+    wifi::{Interfaces, WifiController, WifiDevice},
     Controller,
 };
+use log::{error, info};
+use weather_core::{
+    clock::{parse_sntp_response, sntp_request, unix_offset, NTP_PORT, SNTP_PACKET_SIZE},
+    rtc::RtcMemory,
+    wifi::Addressing,
+};
+
+const SNTP_TIMEOUT_SECS: u64 = 3;
+const DNS_TIMEOUT_SECS: u64 = 5;
 
-pub async fn bring_network_up(wifi: WIFI<'static>, spawner: &Spawner) -> Stack<'static> {
-    let (controller, stack, runner) = init_network(wifi);
+/// Where the IPv4 configuration of the station comes from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Ipv4Source {
+    Static,
+    /// lease obtained on a previous wake
+    Cached,
+    Dhcp,
+}
+
+/// Join the network and wait for an IPv4 configuration
+///
+/// Returns `None` when the network did not come up in time. The radio is then turned off and
+/// the failure is counted in RTC memory: it stretches the next sleep (see
+/// `NETWORK_BACKOFF_MAX_SECS`) and, after `PROVISION_AFTER_FAILURES` boots in a row, opens the
+/// provisioning portal.
+pub async fn bring_network_up(wifi: WIFI<'static>, spawner: &Spawner) -> Option<Stack<'static>> {
+    let (mut source, config) = ipv4_config();
+    let (controller, stack, runner) = init_network(wifi, config);
     spawner.spawn(runner_task(runner)).ok();
     spawner.spawn(wifi_task(controller)).ok();
-    wait_for_stack(&stack)
-        .await
-        .expect("The network stack failed to get up");
 
-    stack
+    let mut result = wait_for_stack(&stack).await;
+    if result.is_ok() && source == Ipv4Source::Cached && !cached_lease_holds().await {
+        info!("Cached lease not valid on this network, falling back to DHCP");
+        stack.set_config_v4(ConfigV4::Dhcp(Default::default()));
+        source = Ipv4Source::Dhcp;
+        result = wait_for_stack(&stack).await;
+    }
+    if let Err(e) = result {
+        let failures = wifi_failures().saturating_add(1);
+        error!("The network stack failed to get up ({failures} in a row): {e:?}");
+        store_wifi_failures(failures);
+        WIFI_STOP.signal(());
+        return None;
+    }
+    store_wifi_failures(0);
+    if source == Ipv4Source::Dhcp {
+        cache_lease(&stack);
+    }
+
+    Some(stack)
 }
 
 pub fn init_network(
     wifi: WIFI<'static>,
+    config: embassy_net::Config,
 ) -> (
     WifiController<'static>,
     Stack<'static>,
     Runner<'static, WifiDevice<'static>>,
 ) {
-    let radio_init = mk_static!(
-        Controller<'static>,
-        esp_radio::init().expect("Failed to initialize Wi-Fi/BLE controller")
+    let (controller, interfaces) = init_radio(wifi);
+
+    let (stack, runner) = embassy_net::new(
+        interfaces.sta,
+        config,
+        mk_static!(StackResources<6>, StackResources::<6>::new()),
+        random_seed(),
     );
-    let (controller, interfaces) = esp_radio::wifi::new(radio_init, wifi, Default::default())
-        .expect("Failed to initialize Wi-Fi/BLE controller");
 
-    // Net stack
-    let rng = Rng::new();
-    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    (controller, stack, runner)
+}
+
+/// Static configuration when one is set, else the lease cached in RTC memory, else DHCP
+fn ipv4_config() -> (Ipv4Source, embassy_net::Config) {
+    let s = settings();
+    if !s.static_ip.is_empty() {
+        match Addressing::parse(&s.static_ip, &s.static_gateway, &s.static_dns) {
+            Some(addressing) => return (Ipv4Source::Static, static_config(&addressing)),
+            None => error!("Invalid static IP configuration, using DHCP"),
+        }
+    }
+
+    match with_connection_cache(|cache| cache.lease(timestamp())) {
+        Some(lease) => {
+            info!("Reusing lease for {}", lease.address);
+            (Ipv4Source::Cached, static_config(&lease))
+        }
+        None => (
+            Ipv4Source::Dhcp,
+            embassy_net::Config::dhcpv4(Default::default()),
+        ),
+    }
+}
+
+fn static_config(addressing: &Addressing) -> embassy_net::Config {
+    let mut dns_servers = heapless::Vec::new();
+    if let Some(dns) = addressing.dns {
+        let _ = dns_servers.push(dns);
+    }
+
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(addressing.address, addressing.prefix_len),
+        gateway: addressing.gateway,
+        dns_servers,
+    })
+}
+
+/// Whether the network just joined is the one the cached lease was obtained on
+async fn cached_lease_holds() -> bool {
+    // the Wi-Fi task drops the lease when it joins another network
+    let joined = JOINED.wait().with_timeout(Duration::from_secs(1)).await;
+    joined.is_ok() && with_connection_cache(|cache| cache.lease(timestamp())).is_some()
+}
+
+/// Keep the DHCP lease in RTC memory for the next wakes
+fn cache_lease(stack: &Stack<'static>) {
+    let Some(config) = stack.config_v4() else {
+        return;
+    };
+    let lease = Addressing {
+        address: config.address.address(),
+        prefix_len: config.address.prefix_len(),
+        gateway: config.gateway,
+        dns: config.dns_servers.first().copied(),
+    };
+
+    with_connection_cache(|cache| {
+        if cache.association().is_some() {
+            cache.set_lease(&lease, timestamp() + DHCP_LEASE_SECS);
+        }
+    });
+}
+
+/// Network stack of the access point used for provisioning, reachable at `ip`
+pub fn init_access_point(
+    wifi: WIFI<'static>,
+    ip: Ipv4Address,
+) -> (
+    WifiController<'static>,
+    Stack<'static>,
+    Runner<'static, WifiDevice<'static>>,
+) {
+    let (controller, interfaces) = init_radio(wifi);
 
     let (stack, runner) = embassy_net::new(
-        interfaces.sta,
-        embassy_net::Config::dhcpv4(Default::default()),
+        interfaces.ap,
+        embassy_net::Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(ip, 24),
+            gateway: Some(ip),
+            dns_servers: Default::default(),
+        }),
         mk_static!(StackResources<6>, StackResources::<6>::new()),
-        seed,
+        random_seed(),
     );
 
     (controller, stack, runner)
 }
 
+fn init_radio(wifi: WIFI<'static>) -> (WifiController<'static>, Interfaces<'static>) {
+    let radio_init = mk_static!(
+        Controller<'static>,
+        esp_radio::init().expect("Failed to initialize Wi-Fi/BLE controller")
+    );
+    esp_radio::wifi::new(radio_init, wifi, Default::default())
+        .expect("Failed to initialize Wi-Fi/BLE controller")
+}
+
+fn random_seed() -> u64 {
+    let rng = Rng::new();
+    (rng.random() as u64) << 32 | rng.random() as u64
+}
+
 pub async fn wait_for_stack(stack: &Stack<'static>) -> Result<(), TimeoutError> {
     stack
         .wait_config_up()
@@ -60,3 +202,61 @@ pub async fn wait_for_stack(stack: &Stack<'static>) -> Result<(), TimeoutError>
 
     Ok(())
 }
+
+/// Resolve `host` to its first IPv4 address
+pub async fn resolve(stack: Stack<'static>, host: &str) -> Option<IpAddress> {
+    match stack
+        .dns_query(host, DnsQueryType::A)
+        .with_timeout(Duration::from_secs(DNS_TIMEOUT_SECS))
+        .await
+    {
+        Ok(Ok(addrs)) => addrs.first().copied(),
+        Ok(Err(e)) => {
+            error!("Couldn't resolve {}: {:?}", host, e);
+            None
+        }
+        Err(_) => {
+            error!("Couldn't resolve {}: timeout", host);
+            None
+        }
+    }
+}
+
+/// Synchronise the wall clock kept in RTC memory with the configured NTP server
+pub async fn sync_clock(stack: Stack<'static>, rtc_manager: &RtcManager) {
+    let timeout = Duration::from_secs(SNTP_TIMEOUT_SECS);
+
+    let Some(server) = resolve(stack, &settings().ntp_server).await else {
+        return;
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buf = [0u8; SNTP_PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buf = [0u8; SNTP_PACKET_SIZE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+
+    if socket.bind(0).is_err()
+        || socket
+            .send_to(&sntp_request(), (server, NTP_PORT))
+            .await
+            .is_err()
+    {
+        error!("Couldn't send SNTP request");
+        return;
+    }
+
+    let mut packet = [0u8; SNTP_PACKET_SIZE];
+    let unix = match socket.recv_from(&mut packet).with_timeout(timeout).await {
+        Ok(Ok((n, _))) => parse_sntp_response(&packet[..n]),
+        _ => None,
+    };
+
+    match unix {
+        Some(unix) => {
+            rtc_manager.store_unix_offset(unix_offset(unix, rtc_manager.now_s()));
+            info!("Clock synchronised: {unix}");
+        }
+        None => error!("No valid answer from NTP server"),
+    }
+}
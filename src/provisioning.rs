@@ -0,0 +1,235 @@
+//! Wi-Fi provisioning through a captive portal.
+//!
+//! When no network is configured, or the configured ones could not be joined for
+//! `PROVISION_AFTER_FAILURES` boots in a row and `provision_pass` is set, the station opens a
+//! WPA2 access point instead of joining one. It hands out addresses over DHCP, answers every DNS
+//! query with its own address and serves a form to enter the Wi-Fi credentials, broker and topic.
+//! Submitted settings are saved to flash and the station restarts in station mode.
+
+use crate::config::{PROVISION_AFTER_FAILURES, PROVISION_TIMEOUT_SECS};
+use crate::network::init_access_point;
+use crate::rtc_manager::{store_wifi_failures, wifi_failures};
+use crate::settings::{self, settings};
+use crate::tasks::wifi_task::{ap_task, runner_task};
+use core::fmt::Write as _;
+use edge_dhcp::{
+    server::{Server, ServerOptions},
+    Options, Packet,
+};
+use embassy_executor::Spawner;
+use embassy_futures::select::select3;
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+    Ipv4Address, Stack,
+};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embedded_io_async::Write;
+use esp_hal::{peripherals::WIFI, system::software_reset};
+use heapless::String;
+use log::{error, info, warn};
+use weather_core::provision::{
+    apply_form, dns_answer, is_valid_password, parse_request, write_form, write_saved,
+    RequestError, SAVE_PATH,
+};
+
+const PORTAL_IP: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+const PORTAL_URL: &str = "http://192.168.4.1/";
+const HTTP_PORT: u16 = 80;
+const HTTP_TIMEOUT_SECS: u64 = 10;
+const DNS_PORT: u16 = 53;
+const DNS_PACKET_SIZE: usize = 512;
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_PACKET_SIZE: usize = 576;
+const DHCP_LEASES: usize = 4;
+const REQUEST_SIZE: usize = 1024;
+const PAGE_SIZE: usize = 2048;
+
+/// Whether this boot should open the provisioning portal instead of joining a network
+///
+/// During a long outage the portal is opened once, later boots keep retrying the network.
+/// Without a `provision_pass`, the portal password is derived from the MAC address, which anyone
+/// in range can read, so a configured station never opens it on its own.
+pub fn required() -> bool {
+    let configured = settings()
+        .networks()
+        .iter()
+        .any(|(ssid, _)| !ssid.is_empty());
+    !configured
+        || (wifi_failures() == PROVISION_AFTER_FAILURES
+            && is_valid_password(&settings().provision_pass))
+}
+
+/// Serve the portal until settings are submitted, which restarts the station.
+///
+/// Returns after `PROVISION_TIMEOUT_SECS` without a submission, the next boot then tries the
+/// configured networks again. The timeout counts as one more failure, so the sleep backoff
+/// keeps growing.
+pub async fn run(wifi: WIFI<'static>, spawner: &Spawner) {
+    let (controller, stack, runner) = init_access_point(wifi, PORTAL_IP);
+    spawner.spawn(runner_task(runner)).ok();
+    spawner.spawn(ap_task(controller)).ok();
+    info!("Provisioning portal on {}", PORTAL_URL);
+
+    let portal = select3(serve_dhcp(stack), serve_dns(stack), serve_http(stack));
+    if portal
+        .with_timeout(Duration::from_secs(PROVISION_TIMEOUT_SECS))
+        .await
+        .is_err()
+    {
+        info!("No settings submitted, closing the portal");
+        store_wifi_failures(wifi_failures().saturating_add(1));
+    }
+}
+
+async fn serve_dhcp(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buf = [0u8; DHCP_PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buf = [0u8; DHCP_PACKET_SIZE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if socket.bind(DHCP_SERVER_PORT).is_err() {
+        error!("Couldn't start the DHCP server");
+        return core::future::pending().await;
+    }
+
+    let mut gateway = [PORTAL_IP];
+    let dns = [PORTAL_IP];
+    let mut options = ServerOptions::new(PORTAL_IP, Some(&mut gateway));
+    options.dns = &dns;
+    options.captive_url = Some(PORTAL_URL);
+    let mut server = Server::<_, DHCP_LEASES>::new(|| Instant::now().as_secs(), PORTAL_IP);
+
+    let mut request = [0u8; DHCP_PACKET_SIZE];
+    let mut reply = [0u8; DHCP_PACKET_SIZE];
+    loop {
+        let Ok((n, _)) = socket.recv_from(&mut request).await else {
+            continue;
+        };
+        let Ok(packet) = Packet::decode(&request[..n]) else {
+            continue;
+        };
+
+        let mut opt_buf = Options::buf();
+        let Some(answer) = server.handle_request(&mut opt_buf, &options, &packet) else {
+            continue;
+        };
+        // clients have no address yet, replies are broadcast
+        match answer.encode(&mut reply) {
+            Ok(answer) => {
+                let _ = socket
+                    .send_to(answer, (Ipv4Address::BROADCAST, DHCP_CLIENT_PORT))
+                    .await;
+            }
+            Err(e) => warn!("Couldn't encode DHCP reply: {:?}", e),
+        }
+    }
+}
+
+async fn serve_dns(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buf = [0u8; DNS_PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buf = [0u8; DNS_PACKET_SIZE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if socket.bind(DNS_PORT).is_err() {
+        error!("Couldn't start the DNS server");
+        return core::future::pending().await;
+    }
+
+    let mut query = [0u8; DNS_PACKET_SIZE];
+    let mut answer = [0u8; DNS_PACKET_SIZE];
+    loop {
+        let Ok((n, remote)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(len) = dns_answer(&query[..n], PORTAL_IP, &mut answer) {
+            let _ = socket.send_to(&answer[..len], remote).await;
+        }
+    }
+}
+
+async fn serve_http(stack: Stack<'static>) {
+    let mut rx_buf = [0u8; REQUEST_SIZE];
+    let mut tx_buf = [0u8; PAGE_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        socket.set_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            error!("HTTP accept failed: {:?}", e);
+            continue;
+        }
+
+        let saved = handle_request(&mut socket).await;
+        socket.close();
+        let _ = socket.flush().await;
+
+        if saved {
+            store_wifi_failures(0);
+            info!("Restarting into station mode...");
+            Timer::after_secs(1).await;
+            software_reset();
+        }
+    }
+}
+
+/// Answer one request, returns true once new settings were saved
+async fn handle_request(socket: &mut TcpSocket<'_>) -> bool {
+    let mut buf = [0u8; REQUEST_SIZE];
+    let mut len = 0;
+    loop {
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => len += n,
+        }
+        match parse_request(&buf[..len]) {
+            Err(RequestError::Incomplete) if len < buf.len() => {}
+            _ => break,
+        }
+    }
+    let Ok(request) = parse_request(&buf[..len]) else {
+        warn!("Invalid HTTP request");
+        return false;
+    };
+
+    let mut page = String::<PAGE_SIZE>::new();
+    let mut status = "200 OK";
+    let mut saved = false;
+    if request.method == "POST" && request.path == SAVE_PATH {
+        let mut submitted = settings().clone();
+        match apply_form(&mut submitted, request.body) {
+            Ok(()) if settings::save(&submitted).is_ok() => {
+                info!("New settings submitted for {}", submitted.ssid);
+                saved = true;
+                let _ = write_saved(&mut page, &submitted.ssid);
+            }
+            Ok(()) => {
+                status = "500 Internal Server Error";
+                let _ = page.push_str("Could not save the settings");
+            }
+            Err(e) => {
+                let _ = write_form(&mut page, settings(), Some(e));
+            }
+        }
+    } else {
+        // every other path gets the form, which is what triggers captive portal detection
+        let _ = write_form(&mut page, settings(), None);
+    }
+
+    let mut header = String::<128>::new();
+    let _ = write!(
+        header,
+        "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        page.len()
+    );
+    if socket.write_all(header.as_bytes()).await.is_err()
+        || socket.write_all(page.as_bytes()).await.is_err()
+    {
+        warn!("Couldn't send HTTP response");
+    }
+
+    saved
+}
@@ -4,51 +4,275 @@
 //! configuration. It is responsible for restoring wakeup state after boot and
 //! programming the next sleep interval.
 
-use crate::config::CONFIG;
-use embassy_time::Timer;
+use crate::config::{BACKLOG_CAPACITY, NETWORK_BACKOFF_MAX_SECS, RAIN_LOG_CAPACITY};
+use crate::settings::settings;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_time::{Instant, Timer};
 use esp_hal::gpio::{Input, InputConfig, Pull};
 use esp_hal::ram;
 use esp_hal::rtc_cntl::Rtc;
 use esp_hal::{
     peripherals::{GPIO25, LPWR},
-    rtc_cntl::sleep::{Ext0WakeupSource, RtcSleepConfig, TimerWakeupSource},
+    rtc_cntl::sleep::{Ext0WakeupSource, RtcSleepConfig, TimerWakeupSource, WakeupLevel},
 };
-use log::info;
+use log::{info, warn};
+use weather_core::{
+    anemometer::WindHistory,
+    backlog::Backlog,
+    backoff::exponential,
+    charge::ChargeCounter,
+    diagnostics::SensorDiagnostics,
+    power::PowerMode,
+    rain::{RainCounter, TipLog},
+    rtc::RtcMemory,
+    sealed::{Seal, Sealed},
+    wifi::ConnectionCache,
+};
+
+const RAIN_RELEASE_MS: u64 = 500; // a tip closes the reed switch for much less than that
 
 //Variables store in RTC
 #[ram(unstable(rtc_fast), unstable(persistent))]
 static mut NEXT_FULL_MEASUREMENT_S: u64 = 0; // to calculate how much time remains before
                                              // waking up from deep sleep timer
 #[ram(unstable(rtc_fast), unstable(persistent))]
-static mut LAST_TIP: u64 = 0; // to avoid counting rain tips when sensor is stuck
+static mut BOOT_COUNT: u32 = 0;
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut UNIX_OFFSET: u64 = 0; // unix time minus RTC time, 0 until the clock is synced
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut SLEEP_INTERVAL_S: u64 = 0; // set by a `sleep` command, 0 to use the configured one
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut MQTT_FAILURE: u8 = 0; // why the broker was given up, 0 when it was not
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut WIFI_FAILURES: u8 = 0; // boots in a row that could not join the network
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut LAST_NETWORK: u8 = 0; // index + 1 of the network joined last, 0 when none
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut POWER_MODE: u8 = 0; // `PowerMode` chosen at the end of the last window
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut WINDOW_COUNT: u32 = 0; // active windows run, rain wakeups left out
+
+/// A value sealed with a CRC in RTC memory
+#[repr(transparent)]
+struct RtcSealed<T>(Sealed<T>);
+
+// SAFETY: `Seal` types only hold integers and floats, and garbage left by a power loss fails the
+// CRC check.
+unsafe impl<T: Seal> esp_hal::Persistable for RtcSealed<T> {}
+
+/// Rain tips since the last window and state of the reed switch
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut RAIN: RtcSealed<RainCounter> = RtcSealed(Sealed::new());
+
+/// Mean wind speed of the last windows, for the 10 minute average
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut WIND: RtcSealed<WindHistory> = RtcSealed(Sealed::new());
+
+/// Access point and lease of the last Wi-Fi connection
 #[ram(unstable(rtc_fast), unstable(persistent))]
-static mut RAIN_TIPS: u32 = 0;
+static mut CONNECTION: RtcSealed<ConnectionCache> = RtcSealed(Sealed::new());
+
+/// Charge that went in and out of the battery today
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut CHARGE: RtcSealed<ChargeCounter> = RtcSealed(Sealed::new());
+
+/// Error statistics of the sensors
+#[ram(unstable(rtc_fast), unstable(persistent))]
+static mut DIAGNOSTICS: RtcSealed<SensorDiagnostics> = RtcSealed(Sealed::new());
+
+/// Readings that could not be published yet
+#[repr(transparent)]
+struct RtcBacklog(Backlog<BACKLOG_CAPACITY>);
+
+// SAFETY: the backlog only holds integers and floats, and inconsistent indices left by a power
+// loss are caught by `Backlog::is_valid`.
+unsafe impl esp_hal::Persistable for RtcBacklog {}
+
+// slow memory is larger than fast memory and enough for the backlog
+#[ram(unstable(rtc_slow), unstable(persistent))]
+static mut BACKLOG: RtcBacklog = RtcBacklog(Backlog::new());
+
+/// Timestamps of the last rain tips
+#[repr(transparent)]
+struct RtcTipLog(TipLog<RAIN_LOG_CAPACITY>);
+
+// SAFETY: the log only holds integers, and inconsistent indices left by a power loss are caught
+// by `TipLog::is_valid`.
+unsafe impl esp_hal::Persistable for RtcTipLog {}
+
+#[ram(unstable(rtc_slow), unstable(persistent))]
+static mut RAIN_LOG: RtcTipLog = RtcTipLog(TipLog::new());
+
+// RTC clock value at boot, lets the tasks timestamp readings without owning the RTC
+static BOOT_RTC_S: AtomicU32 = AtomicU32::new(0);
+
+/// Current time on the RTC clock in seconds.
+///
+/// The RTC keeps counting during deep sleep, so timestamps stay ordered across wake cycles.
+pub fn timestamp() -> u64 {
+    BOOT_RTC_S.load(Ordering::Relaxed) as u64 + Instant::now().as_secs()
+}
+
+/// Unix time minus RTC time, `None` until the clock has been synced
+pub fn unix_offset() -> Option<u64> {
+    match unsafe { UNIX_OFFSET } {
+        0 => None,
+        offset => Some(offset),
+    }
+}
+
+/// Remember why the MQTT task gave up on the broker, reported on the next connection
+pub fn store_mqtt_failure(code: u8) {
+    unsafe {
+        MQTT_FAILURE = code;
+    }
+}
+
+/// Failure code stored by `store_mqtt_failure`, 0 when there is none
+pub fn take_mqtt_failure() -> u8 {
+    let code = unsafe { MQTT_FAILURE };
+    store_mqtt_failure(0);
+    code
+}
+
+/// Number of boots in a row that could not join the network
+pub fn wifi_failures() -> u8 {
+    unsafe { WIFI_FAILURES }
+}
+
+pub fn store_wifi_failures(count: u8) {
+    unsafe {
+        WIFI_FAILURES = count;
+    }
+}
+
+/// Index of the Wi-Fi network joined last, tried first on the next wake
+pub fn last_network() -> Option<usize> {
+    match unsafe { LAST_NETWORK } {
+        0 => None,
+        n => Some(n as usize - 1),
+    }
+}
+
+pub fn store_last_network(index: usize) {
+    unsafe {
+        LAST_NETWORK = index as u8 + 1;
+    }
+}
+
+/// Power mode chosen at the end of the last window
+pub fn power_mode() -> PowerMode {
+    PowerMode::from_u8(unsafe { POWER_MODE }).unwrap_or(PowerMode::Normal)
+}
+
+pub fn store_power_mode(mode: PowerMode) {
+    unsafe {
+        POWER_MODE = mode as u8;
+    }
+}
+
+/// Count an active window and return the new count
+///
+/// Unlike the boot count it does not move on rain wakeups, so periodic tasks keyed on it run
+/// once every so many windows.
+pub fn inc_window_count() -> u32 {
+    unsafe {
+        WINDOW_COUNT = WINDOW_COUNT.wrapping_add(1);
+        WINDOW_COUNT
+    }
+}
+
+/// Run `f` with exclusive access to the value sealed in `cell`, one of the `RtcSealed` statics
+///
+/// A value lost to a power loss starts over from empty.
+fn with_rtc<T: Seal, R>(cell: *mut RtcSealed<T>, f: impl FnOnce(&mut T) -> R) -> R {
+    critical_section::with(|_| {
+        // SAFETY: `cell` points to a static, and the critical section makes this the only live
+        // reference to it
+        unsafe { (*cell).0.update(f) }
+    })
+}
+
+/// Run `f` with exclusive access to the Wi-Fi connection cache kept in RTC memory.
+pub fn with_connection_cache<R>(f: impl FnOnce(&mut ConnectionCache) -> R) -> R {
+    with_rtc(&raw mut CONNECTION, f)
+}
+
+/// Run `f` with exclusive access to the charge counter kept in RTC memory.
+pub fn with_charge_counter<R>(f: impl FnOnce(&mut ChargeCounter) -> R) -> R {
+    with_rtc(&raw mut CHARGE, f)
+}
+
+/// Run `f` with exclusive access to the sensor error statistics kept in RTC memory.
+pub fn with_sensor_diagnostics<R>(f: impl FnOnce(&mut SensorDiagnostics) -> R) -> R {
+    with_rtc(&raw mut DIAGNOSTICS, f)
+}
+
+/// Run `f` with exclusive access to the wind speed history kept in RTC memory.
+pub fn with_wind_history<R>(f: impl FnOnce(&mut WindHistory) -> R) -> R {
+    with_rtc(&raw mut WIND, f)
+}
+
+/// Run `f` with exclusive access to the store-and-forward backlog kept in RTC memory.
+pub fn with_backlog<R>(f: impl FnOnce(&mut Backlog<BACKLOG_CAPACITY>) -> R) -> R {
+    critical_section::with(|_| {
+        // SAFETY: the critical section makes this the only live reference to BACKLOG
+        let backlog = unsafe { &mut (*&raw mut BACKLOG).0 };
+        if !backlog.is_valid() {
+            backlog.clear();
+        }
+        f(backlog)
+    })
+}
+
+/// Run `f` with exclusive access to the rain counter kept in RTC memory.
+fn with_rain_counter<R>(f: impl FnOnce(&mut RainCounter) -> R) -> R {
+    with_rtc(&raw mut RAIN, f)
+}
+
+/// Whether the reed switch of the rain gauge was found stuck closed
+pub fn rain_sensor_stuck() -> bool {
+    with_rain_counter(|c| c.stuck())
+}
+
+/// Run `f` with exclusive access to the rain tip log kept in RTC memory.
+pub fn with_rain_log<R>(f: impl FnOnce(&mut TipLog<RAIN_LOG_CAPACITY>) -> R) -> R {
+    critical_section::with(|_| {
+        // SAFETY: the critical section makes this the only live reference to RAIN_LOG
+        let log = unsafe { &mut (*&raw mut RAIN_LOG).0 };
+        if !log.is_valid() {
+            log.clear();
+        }
+        f(log)
+    })
+}
 
 pub struct RtcManager {
     rtc: Rtc<'static>,
     rtc_cfg: RtcSleepConfig,
-    ext0: Ext0WakeupSource<GPIO25<'static>>,
+    // the reed switch of the rain gauge, closed (low) while the magnet passes by
+    rain_gpio: GPIO25<'static>,
     deep_sleep_timer: TimerWakeupSource,
 }
 
 impl RtcManager {
-    pub fn new(mut rain_gpio: GPIO25<'static>, lpwr: LPWR<'static>) -> Self {
+    pub fn new(rain_gpio: GPIO25<'static>, lpwr: LPWR<'static>) -> Self {
         let mut rtc_cfg = RtcSleepConfig::deep();
         rtc_cfg.set_rtc_fastmem_pd_en(false); // RTC fast memory must stay powered so rain-tip counters survive deep sleep.
+        rtc_cfg.set_rtc_slowmem_pd_en(false); // same for the backlog and the rain tip log
         let rtc = Rtc::new(lpwr);
-
-        //config rain pin which is our external wake up source
-        let _rain_pin = Input::new(
-            rain_gpio.reborrow(),
-            InputConfig::default().with_pull(Pull::Up),
-        );
+        let boot_rtc_s = rtc
+            .time_since_boot()
+            .as_secs()
+            .saturating_sub(Instant::now().as_secs());
+        BOOT_RTC_S.store(boot_rtc_s as u32, Ordering::Relaxed);
 
         RtcManager {
             rtc,
             rtc_cfg,
-            ext0: Ext0WakeupSource::new(rain_gpio, esp_hal::rtc_cntl::sleep::WakeupLevel::Low),
+            rain_gpio,
             deep_sleep_timer: TimerWakeupSource::new(core::time::Duration::from_secs(
-                CONFIG.deep_sleep_dur_secs,
+                settings().deep_sleep_dur_secs,
             )),
         }
     }
@@ -60,31 +284,71 @@ impl RtcManager {
     /// sensor)
     pub fn init_next_full_measurement(&self) {
         let now = self.rtc.time_since_boot().as_secs();
-        let mut next_full = self.load_next_full_measurement_s();
+        RtcMemory::init_next_full_measurement(self, now, self.sleep_interval_s());
+    }
 
-        if next_full == 0 {
-            next_full = now + CONFIG.deep_sleep_dur_secs;
-            self.store_next_full_measurement_s(next_full);
-        }
+    /// Deep sleep interval between two full measurements
+    pub fn sleep_interval_s(&self) -> u64 {
+        RtcMemory::sleep_interval_s(self, settings().deep_sleep_dur_secs)
+    }
+
+    /// `interval` doubled for every boot in a row without network
+    pub fn backoff_sleep_s(&self, interval: u64) -> u64 {
+        let max = NETWORK_BACKOFF_MAX_SECS.max(interval);
+        exponential(interval, max, wifi_failures() as u32)
     }
 
     /// Handle wake ups from the rain sensor
     ///
     /// calculate the remaining sleep time before a full measurement window and set the RTC memory
-    /// accordingly
+    /// accordingly. A switch found stuck closed woke the board by itself, no tip is counted.
     pub async fn handle_external_wakeup(&mut self) {
         let now = self.rtc.time_since_boot().as_secs();
-        let next_full = self.load_next_full_measurement_s();
-        let remaining = next_full - now;
-        let sleep_secs = core::cmp::max(remaining, 1); //avoid 0
+        let sleep_secs = self.remaining_sleep_s(now);
 
-        self.set_deep_sleep_timer(core::time::Duration::from_secs(sleep_secs as u64));
+        self.set_deep_sleep_timer(core::time::Duration::from_secs(sleep_secs));
 
-        self.inc_rain_tips(now);
-        Timer::after_millis(500).await;
+        if !self.check_rain_sensor().await {
+            self.inc_rain_tips(now);
+        }
         self.sleep();
     }
 
+    /// Check whether the reed switch of the rain gauge is stuck closed and record it
+    ///
+    /// A tip only closes the switch for a fraction of a second, one still closed
+    /// `RAIN_RELEASE_MS` later is stuck, for instance with the bucket jammed under the magnet.
+    /// Rain wakeups are then disabled, the level would wake the board right away, until the
+    /// switch is found open again.
+    pub async fn check_rain_sensor(&mut self) -> bool {
+        if self.rain_switch_closed() {
+            Timer::after_millis(RAIN_RELEASE_MS).await;
+        }
+        let stuck = self.rain_switch_closed();
+
+        if with_rain_counter(|c| c.replace_stuck(stuck)) != stuck {
+            if stuck {
+                warn!("Rain sensor stuck closed, rain wakeups disabled");
+            } else {
+                info!("Rain sensor released, rain wakeups enabled");
+            }
+        }
+        stuck
+    }
+
+    fn rain_switch_closed(&mut self) -> bool {
+        Input::new(
+            self.rain_gpio.reborrow(),
+            InputConfig::default().with_pull(Pull::Up),
+        )
+        .is_low()
+    }
+
+    /// Current RTC time in seconds
+    pub fn now_s(&self) -> u64 {
+        self.rtc.time_since_boot().as_secs()
+    }
+
     pub fn set_deep_sleep_timer(&mut self, duration: core::time::Duration) {
         self.deep_sleep_timer = TimerWakeupSource::new(duration);
     }
@@ -93,67 +357,92 @@ impl RtcManager {
         self.store_next_full_measurement_s(self.rtc.time_since_boot().as_secs() + duration);
     }
 
-    pub fn sleep(&mut self) {
-        self.rtc
-            .sleep(&self.rtc_cfg, &[&self.ext0, &self.deep_sleep_timer]);
+    /// Program the next full measurement `sleep_s` from now
+    pub fn schedule_sleep(&mut self, sleep_s: u64) {
+        self.set_next_full_measurement_s(sleep_s);
+        self.set_deep_sleep_timer(core::time::Duration::from_secs(sleep_s));
     }
 
-    //direct manipulation of rtc memory
-    pub fn load_rain_tips(&self) -> u32 {
-        let rain_tips = unsafe { RAIN_TIPS };
-        if rain_tips > 100 {
-            0
+    /// Enter deep sleep until the timer, or a tip of the rain gauge unless its switch is stuck
+    pub fn sleep(&mut self) {
+        if rain_sensor_stuck() {
+            self.rtc.sleep(&self.rtc_cfg, &[&self.deep_sleep_timer]);
         } else {
-            rain_tips
-        } //avoid unitialized weird values
+            let ext0 = Ext0WakeupSource::new(self.rain_gpio.reborrow(), WakeupLevel::Low);
+            self.rtc
+                .sleep(&self.rtc_cfg, &[&ext0, &self.deep_sleep_timer]);
+        }
     }
 
-    pub fn store_rain_tips(&self, v: u32) {
-        unsafe {
-            RAIN_TIPS = v;
+    /// Increment rain tips
+    ///
+    /// Count a tip of the bucket unless it is a bounce (see `RtcMemory::inc_rain_tips`), and log
+    /// it for the rain totals
+    pub fn inc_rain_tips(&self, now: u64) {
+        if RtcMemory::inc_rain_tips(self, now, settings().rain_debounce_s) {
+            with_rain_log(|log| log.push(now));
+            info!("Incremented to {}", self.load_rain_tips());
+        } else {
+            info!("Tip ignored, too close to the previous one");
         }
     }
+}
+
+//direct manipulation of rtc memory
+impl RtcMemory for RtcManager {
+    fn load_rain_tips(&self) -> u32 {
+        with_rain_counter(|c| c.tips())
+    }
+
+    fn store_rain_tips(&self, v: u32) {
+        with_rain_counter(|c| c.set_tips(v));
+    }
 
-    pub fn load_next_full_measurement_s(&self) -> u64 {
+    fn load_next_full_measurement_s(&self) -> u64 {
         unsafe { NEXT_FULL_MEASUREMENT_S }
     }
 
-    pub fn store_next_full_measurement_s(&self, v: u64) {
+    fn store_next_full_measurement_s(&self, v: u64) {
         unsafe {
             NEXT_FULL_MEASUREMENT_S = v;
         }
     }
 
-    pub fn load_last_tip(&self) -> u64 {
-        unsafe { LAST_TIP }
+    fn load_last_tip(&self) -> u64 {
+        with_rain_counter(|c| c.last_tip_s())
     }
 
-    pub fn store_last_tip(&self, v: u64) {
+    fn store_last_tip(&self, v: u64) {
+        with_rain_counter(|c| c.set_last_tip_s(v));
+    }
+
+    fn load_boot_count(&self) -> u32 {
+        unsafe { BOOT_COUNT }
+    }
+
+    fn store_boot_count(&self, v: u32) {
         unsafe {
-            LAST_TIP = v;
+            BOOT_COUNT = v;
         }
     }
 
-    /// Increment rain tips
-    ///
-    /// Increment the rain tips and store the time time it was incremented to avoid counting rain
-    /// when the senbsor is stuck
-    pub fn inc_rain_tips(&self, now: u64) {
-        let cur = self.load_rain_tips();
-        let last_tip = self.load_last_tip();
+    fn load_unix_offset(&self) -> u64 {
+        unsafe { UNIX_OFFSET }
+    }
 
-        if cur == 0 {
-            self.store_rain_tips(cur.saturating_add(1));
-            self.store_last_tip(now);
-            return;
+    fn store_unix_offset(&self, v: u64) {
+        unsafe {
+            UNIX_OFFSET = v;
         }
+    }
 
-        if last_tip != 0 && now.saturating_sub(last_tip) > CONFIG.rain_debounce_s {
-            self.store_rain_tips(cur.saturating_add(1));
-            self.store_last_tip(now);
-            info!("Incremented to {}", self.load_rain_tips());
-        } else {
-            info!("Sensor must be stuck");
+    fn load_sleep_interval_s(&self) -> u64 {
+        unsafe { SLEEP_INTERVAL_S }
+    }
+
+    fn store_sleep_interval_s(&self, v: u64) {
+        unsafe {
+            SLEEP_INTERVAL_S = v;
         }
     }
 }
@@ -0,0 +1,165 @@
+//! Runtime settings stored in the `nvs` partition.
+//!
+//! The values compiled in through `CONFIG` are the defaults; settings saved in
+//! flash override them at boot. The partition holds a raw
+//! `weather_core::settings` image, not the ESP-IDF NVS format.
+
+use crate::config::{CONFIG, SETTINGS_SIZE};
+use embassy_sync::once_lock::OnceLock;
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_hal::peripherals::FLASH;
+use esp_storage::FlashStorage;
+use log::{error, info, warn};
+use weather_core::settings::{truncated, Settings, SettingsError};
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum StoreError {
+    Partition(partitions::Error),
+    NoPartition,
+    Settings(SettingsError),
+}
+
+impl From<partitions::Error> for StoreError {
+    fn from(e: partitions::Error) -> Self {
+        StoreError::Partition(e)
+    }
+}
+
+impl From<SettingsError> for StoreError {
+    fn from(e: SettingsError) -> Self {
+        StoreError::Settings(e)
+    }
+}
+
+/// Settings in effect for this boot
+///
+/// # Panics
+/// if called before `load`
+pub fn settings() -> &'static Settings {
+    SETTINGS.try_get().expect("settings are loaded at boot")
+}
+
+/// Read the settings from flash, falling back to the compiled-in defaults
+pub fn load(flash: FLASH<'_>) -> &'static Settings {
+    let mut settings = defaults();
+    let mut image = [0u8; SETTINGS_SIZE];
+
+    match with_partition(flash, |nvs| nvs.read(0, &mut image))
+        .and_then(|()| Ok(settings.load(&image)?))
+    {
+        Ok(()) => info!("Settings loaded from flash"),
+        Err(StoreError::Settings(SettingsError::Empty)) => {
+            info!("No stored settings, using defaults")
+        }
+        Err(e) => warn!("Stored settings ignored, using defaults: {:?}", e),
+    }
+
+    SETTINGS.get_or_init(|| settings)
+}
+
+/// Write `settings` to flash, they take effect on the next boot
+///
+/// Only the values differing from the compiled-in defaults are stored, the others follow the
+/// defaults of the firmware running.
+pub fn save(settings: &Settings) -> Result<(), StoreError> {
+    let mut image = [0xFFu8; SETTINGS_SIZE];
+    let len = settings.encode(&defaults(), &mut image)?;
+
+    // SAFETY: FLASH is handed to the OTA updater at boot. Flash operations are blocking and
+    // run to completion on the core executing the tasks, so the two drivers never interleave.
+    let flash = unsafe { FLASH::steal() };
+    with_partition(flash, |nvs| nvs.write(0, &image[..len])).inspect_err(|e| {
+        error!("Failed to save settings: {:?}", e);
+    })?;
+
+    info!("Settings saved ({} bytes)", len);
+    Ok(())
+}
+
+/// Drop the stored settings, the compiled-in defaults apply from the next boot
+pub fn reset() -> Result<(), StoreError> {
+    save(&defaults())
+}
+
+/// Compiled-in configuration
+pub fn defaults() -> Settings {
+    Settings {
+        ssid: truncated(CONFIG.ssid),
+        wifi_pass: truncated(CONFIG.wifi_pass),
+        broker_url: truncated(CONFIG.broker_url),
+        broker_ip: truncated(CONFIG.broker_ip),
+        broker_port: CONFIG.broker_port,
+        mqtt_tls: CONFIG.mqtt_tls,
+        broker_tls_port: CONFIG.broker_tls_port,
+        tls_server_name: truncated(CONFIG.tls_server_name),
+        mqtt_user: truncated(CONFIG.mqtt_user),
+        mqtt_pass: truncated(CONFIG.mqtt_pass),
+        topic: truncated(CONFIG.topic),
+        deep_sleep_dur_secs: CONFIG.deep_sleep_dur_secs,
+        main_task_dur_secs: CONFIG.main_task_dur_secs,
+        task_dur_secs: CONFIG.task_dur_secs,
+        watchdog_timeout_secs: CONFIG.watchdog_timeout_secs,
+        ota_url: truncated(CONFIG.ota_url),
+        rain_debounce_s: CONFIG.rain_debounce_s,
+        state_json: CONFIG.state_json,
+        ha_discovery: CONFIG.ha_discovery,
+        ha_prefix: truncated(CONFIG.ha_prefix),
+        device_id: truncated(CONFIG.device_id),
+        device_name: truncated(CONFIG.device_name),
+        mqtt_lwt: CONFIG.mqtt_lwt,
+        mqtt_keep_alive_secs: CONFIG.mqtt_keep_alive_secs,
+        ntp_server: truncated(CONFIG.ntp_server),
+        mqtt_commands: CONFIG.mqtt_commands,
+        provision_pass: truncated(CONFIG.provision_pass),
+        ssid_2: truncated(CONFIG.ssid_2),
+        wifi_pass_2: truncated(CONFIG.wifi_pass_2),
+        ssid_3: truncated(CONFIG.ssid_3),
+        wifi_pass_3: truncated(CONFIG.wifi_pass_3),
+        wifi_scan: CONFIG.wifi_scan,
+        static_ip: truncated(CONFIG.static_ip),
+        static_gateway: truncated(CONFIG.static_gateway),
+        static_dns: truncated(CONFIG.static_dns),
+        low_soc_percent: CONFIG.low_soc_percent,
+        low_soc_sleep_secs: CONFIG.low_soc_sleep_secs,
+        survival_mv: CONFIG.survival_mv,
+        survival_sleep_secs: CONFIG.survival_sleep_secs,
+        charging_ma: CONFIG.charging_ma,
+        ina_shunt_mohm: CONFIG.ina_shunt_mohm,
+        ina_max_current_ma: CONFIG.ina_max_current_ma,
+        ina_bus_offset_mv: CONFIG.ina_bus_offset_mv,
+        sleep_current_ua: CONFIG.sleep_current_ua,
+        battery_chemistry: truncated(CONFIG.battery_chemistry),
+        battery_curve: truncated(CONFIG.battery_curve),
+        battery_cells: CONFIG.battery_cells,
+        battery_internal_mohm: CONFIG.battery_internal_mohm,
+        rain_um_per_tip: CONFIG.rain_um_per_tip,
+        anemo_model: truncated(CONFIG.anemo_model),
+        anemo_pulses_per_rev: CONFIG.anemo_pulses_per_rev,
+        anemo_mm_per_rev: CONFIG.anemo_mm_per_rev,
+        anemo_offset_mms: CONFIG.anemo_offset_mms,
+        anemo_table: truncated(CONFIG.anemo_table),
+        anemo_max_hz: CONFIG.anemo_max_hz,
+        vane_speed_weighted: CONFIG.vane_speed_weighted,
+        vane_zero_raw: CONFIG.vane_zero_raw,
+        vane_mirrored: CONFIG.vane_mirrored,
+    }
+}
+
+/// Run `f` on the `nvs` partition
+fn with_partition<F>(flash: FLASH<'_>, f: F) -> Result<(), StoreError>
+where
+    F: FnOnce(&mut partitions::FlashRegion<'_, FlashStorage<'_>>) -> Result<(), partitions::Error>,
+{
+    let mut storage = FlashStorage::new(flash);
+    let mut table = [0u8; PARTITION_TABLE_MAX_LEN];
+    let nvs = partitions::read_partition_table(&mut storage, &mut table)?
+        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))?
+        .ok_or(StoreError::NoPartition)?;
+
+    Ok(f(&mut nvs.as_embedded_storage(&mut storage))?)
+}
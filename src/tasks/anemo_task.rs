@@ -1,55 +1,152 @@
 //! anemo task
 //!
-//! calulate the rotations of the anemo and publish it.
-//!
-//! More details about this module.
+//! Timestamp the pulses of the anemo during the window and publish the mean wind speed with
+//! its gust, lull and standard deviation (see `weather_core::anemometer`), and the 10 minute
+//! average of the last windows.
+use core::sync::atomic::{AtomicU32, Ordering};
+
 use embassy_futures::select::{select, Either};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
 use embassy_time::{Duration, Instant, Ticker};
 use esp_hal::gpio::Input;
-use log::info;
+use heapless::Vec;
+use log::{error, info, warn};
+use weather_core::{
+    anemometer::{AnemometerCalibration, WindStats, AVERAGE_PERIOD_S},
+    reading::{Quality, Quantity, Reading},
+    sensor::{Health, Readings, Sensor, SensorError, SensorId},
+};
 
 use crate::{
-    config::{CHANNEL_SIZE, CONFIG},
-    tasks::mqtt_task::MqttPacket,
+    config::ANEMO_MAX_PULSES,
+    rtc_manager::{timestamp, with_wind_history},
+    settings::settings,
+    tasks::{mqtt_task::ReadingSender, sensor_runner::run_sensor},
 };
 
-const DEBOUNCE: Duration = Duration::from_millis(200);
+// debounced pulses since boot, the wind vane weights its headings with them
+static PULSES: AtomicU32 = AtomicU32::new(0);
 
-#[embassy_executor::task]
-pub async fn anemo_task(
-    mut anemo_pin: Input<'static>,
-    mqtt_sender: Sender<'static, CriticalSectionRawMutex, MqttPacket, CHANNEL_SIZE>,
-) {
-    let mut rotations: u64 = 0;
-    let mut ticker = Ticker::every(Duration::from_secs(CONFIG.task_dur_secs));
-    let mut last = Instant::now() - DEBOUNCE;
-
-    loop {
-        let edge = anemo_pin.wait_for_falling_edge();
-        let tick = ticker.next();
-
-        match select(edge, tick).await {
-            Either::First(()) => {
-                let now = Instant::now();
-                if now.duration_since(last) >= DEBOUNCE {
-                    rotations += 1;
-                    info!("rotated!");
-                    last = now;
+/// Anemometer pulses counted so far, wrapping around
+pub fn pulse_count() -> u32 {
+    PULSES.load(Ordering::Relaxed)
+}
+
+pub struct Anemometer {
+    pin: Input<'static>,
+    // milliseconds since the start of the window
+    pulses: Vec<u32, ANEMO_MAX_PULSES>,
+    calibration: AnemometerCalibration,
+    health: Health,
+}
+
+impl Anemometer {
+    pub fn new(pin: Input<'static>) -> Self {
+        let calibration = settings().anemometer_calibration().unwrap_or_else(|| {
+            error!("Invalid anemometer calibration, using the stock anemometer");
+            AnemometerCalibration::default()
+        });
+
+        Anemometer {
+            pin,
+            pulses: Vec::new(),
+            calibration,
+            health: Health::Unknown,
+        }
+    }
+}
+
+impl Sensor for Anemometer {
+    const ID: SensorId = SensorId::Anemometer;
+    // sampling takes the whole window, there is no time left for a retry
+    const RETRIES: u32 = 0;
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        self.health.track(Ok(()))
+    }
+
+    async fn sample(&mut self, readings: &mut Readings) -> Result<(), SensorError> {
+        let window_secs = settings().task_dur_secs;
+        let mut pulses: u64 = 0;
+        let mut ticker = Ticker::every(Duration::from_secs(window_secs));
+        let debounce = Duration::from_millis(self.calibration.debounce_ms());
+        let start = Instant::now();
+        let mut last = start - debounce;
+        self.pulses.clear();
+
+        loop {
+            let edge = self.pin.wait_for_falling_edge();
+            let tick = ticker.next();
+
+            match select(edge, tick).await {
+                Either::First(()) => {
+                    let now = Instant::now();
+                    if now.duration_since(last) >= debounce {
+                        pulses += 1;
+                        PULSES.fetch_add(1, Ordering::Relaxed);
+                        let _ = self
+                            .pulses
+                            .push(now.duration_since(start).as_millis() as u32);
+                        info!("pulse!");
+                        last = now;
+                    }
+                }
+                Either::Second(()) => {
+                    break;
                 }
             }
-            Either::Second(()) => {
-                break;
-            }
         }
+
+        let mean = self.calibration.average_kmh(pulses, window_secs);
+        // past a full log, the statistics only cover the start of the window
+        let (window_ms, quality) = if self.pulses.is_full() {
+            warn!("Too many anemometer pulses, statistics cover part of the window");
+            (self.pulses.last().map_or(0, |&t| t + 1), Quality::Degraded)
+        } else {
+            (window_secs as u32 * 1000, Quality::Good)
+        };
+        let stats =
+            WindStats::from_pulses(&self.pulses, window_ms, |hz| self.calibration.speed_kmh(hz));
+
+        let now = timestamp();
+        let average = with_wind_history(|history| {
+            history.record(now, window_secs as u32, mean);
+            history.average(now, AVERAGE_PERIOD_S)
+        });
+
+        readings.push(Reading::new(Quantity::WindSpeed, mean)).ok();
+        for (quantity, value) in [
+            (Quantity::WindGust, stats.gust_kmh),
+            (Quantity::WindLull, stats.lull_kmh),
+            (Quantity::WindSpeedStddev, stats.stddev_kmh),
+        ] {
+            readings
+                .push(Reading::new(quantity, value).with_quality(quality))
+                .ok();
+        }
+        if let Some(average) = average {
+            // the station sleeps most of the period, the average is only as good as its coverage
+            let quality = if average.sampled_s as u64 >= AVERAGE_PERIOD_S {
+                Quality::Good
+            } else {
+                Quality::Degraded
+            };
+            readings
+                .push(
+                    Reading::new(Quantity::WindSpeedAverage, average.mean_kmh)
+                        .with_quality(quality),
+                )
+                .ok();
+        }
+
+        self.health.track(Ok(()))
+    }
+
+    fn health(&self) -> Health {
+        self.health
     }
-    publish!(
-        &mqtt_sender,
-        "anemo/wind_speed",
-        caclulate_windspeed(rotations)
-    );
 }
 
-fn caclulate_windspeed(rotations: u64) -> f32 {
-    rotations as f32 * (1.05 / CONFIG.task_dur_secs as f32) * 3.6
+#[embassy_executor::task]
+pub async fn anemo_task(anemo_pin: Input<'static>, mqtt_sender: ReadingSender) {
+    run_sensor(&mut Anemometer::new(anemo_pin), &mqtt_sender).await;
 }
@@ -1,99 +1,179 @@
-use as5600::asynch::As5600;
-use core::str::FromStr;
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+//! wind vane task
+//!
+//! Read the AS5600 every `MEASUREMENT_FREQ` seconds of the window and publish the circular mean
+//! of the headings, their deviation and the state of the magnet. Headings read while the magnet
+//! is missing are dropped, those read with a magnet too weak or too strong are degraded.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use as5600::{asynch::As5600, error::Error, status};
 use embassy_futures::select::{select, Either};
-use embassy_sync::channel::Sender;
 use embassy_time::{Duration, Ticker, Timer};
-use esp_hal::i2c::master::I2c;
-use esp_hal::Async;
 
 use crate::{
-    config::{CHANNEL_SIZE, CONFIG, DEFAULT_STRING_SIZE},
-    tasks::mqtt_task::MqttPacket,
+    settings::settings,
+    tasks::{anemo_task::pulse_count, mqtt_task::ReadingSender, sensor_runner::run_sensor},
+    ShareI2cBus,
 };
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
-use heapless::String;
-use log::error;
+use log::{error, warn};
+use weather_core::{
+    reading::{Quality, Quantity, Reading},
+    sensor::{Health, Readings, Sensor, SensorError, SensorId},
+    wind::{compass_point, DirectionAverage, MagnetStatus, VaneCalibration},
+};
 
 const MEASUREMENT_FREQ: u64 = 2;
-const INVALID_ANGLE: f32 = 361.0;
+const NO_ANGLE: u32 = u32::MAX;
 
-#[embassy_executor::task]
-pub async fn as5600_task(
-    i2c: &'static mut I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>,
-    mqtt_sender: Sender<'static, CriticalSectionRawMutex, MqttPacket, CHANNEL_SIZE>,
-) {
-    let mut encoder = As5600::new(i2c);
-
-    let mut ticker = Ticker::every(Duration::from_secs(CONFIG.task_dur_secs));
-    let mut nb_measurements: f32 = 0.0;
-    let mut sum_angle: f32 = 0.0;
-
-    loop {
-        let tick = ticker.next();
-        match select(Timer::after_secs(MEASUREMENT_FREQ), tick).await {
-            Either::First(()) => {
-                let current_angle = get_wind_direction(&mut encoder).await;
-                if current_angle == INVALID_ANGLE {
-                    continue;
-                }
+// last raw angle read with a magnet, used by the `calibrate_north` command
+static LAST_RAW_ANGLE: AtomicU32 = AtomicU32::new(NO_ANGLE);
+
+/// Last raw angle of the vane read during this window
+pub fn last_raw_angle() -> Option<u16> {
+    match LAST_RAW_ANGLE.load(Ordering::Relaxed) {
+        NO_ANGLE => None,
+        raw => Some(raw as u16),
+    }
+}
+
+pub struct WindVane {
+    encoder: As5600<ShareI2cBus>,
+    calibration: VaneCalibration,
+    health: Health,
+}
 
-                sum_angle += current_angle;
-                nb_measurements += 1.0;
+impl WindVane {
+    pub fn new(i2c: ShareI2cBus) -> Self {
+        let calibration = settings().vane_calibration().unwrap_or_else(|| {
+            error!("Invalid vane calibration, using the stock mounting");
+            VaneCalibration::default()
+        });
+
+        WindVane {
+            encoder: As5600::new(i2c),
+            calibration,
+            health: Health::Unknown,
+        }
+    }
+
+    /// Magnet status and raw angle, the angle is only read with a magnet
+    async fn read(&mut self) -> Option<(MagnetStatus, Option<u16>)> {
+        let magnet = match self.encoder.magnet_status().await {
+            Ok(status) => MagnetStatus::from_register(status.into()),
+            // the driver rejects the patterns without the magnet detected bit
+            Err(Error::Status(status::Error::InvalidBitPattern(bits))) => {
+                MagnetStatus::from_register(bits)
             }
-            Either::Second(()) => {
-                break;
+            Err(_) => {
+                error!("Couldn't read the vane magnet status");
+                return None;
             }
+        };
+        if !magnet.detected() {
+            return Some((magnet, None));
         }
-    }
 
-    publish!(
-        &mqtt_sender,
-        "anemo/wind_direction",
-        match_direction(sum_angle / nb_measurements)
-    );
-    publish!(
-        &mqtt_sender,
-        "anemo/wind_angle",
-        invert_angle(sum_angle / nb_measurements)
-    );
+        match self.encoder.raw_angle().await {
+            Ok(raw) => Some((magnet, Some(raw))),
+            Err(_) => {
+                error!("Couldn't read wind direction");
+                None
+            }
+        }
+    }
 }
 
-async fn get_wind_direction(
-    encoder: &mut As5600<
-        &'static mut I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>,
-    >,
-) -> f32 {
-    let reading = match encoder.angle().await {
-        Ok(value) => value,
-        Err(_) => {
-            error!("Couldn't read wind direction");
-            return INVALID_ANGLE;
+impl Sensor for WindVane {
+    const ID: SensorId = SensorId::WindVane;
+    // sampling takes the whole window, there is no time left for a retry
+    const RETRIES: u32 = 0;
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        self.health.track(Ok(()))
+    }
+
+    async fn sample(&mut self, readings: &mut Readings) -> Result<(), SensorError> {
+        let mut ticker = Ticker::every(Duration::from_secs(settings().task_dur_secs));
+        let weighted = settings().vane_speed_weighted;
+        let mut average = DirectionAverage::new();
+        let mut last_pulses = pulse_count();
+        // the last fault of the window, or ok
+        let mut magnet = None;
+
+        loop {
+            let tick = ticker.next();
+            match select(Timer::after_secs(MEASUREMENT_FREQ), tick).await {
+                Either::First(()) => {
+                    let Some((status, raw)) = self.read().await else {
+                        continue;
+                    };
+                    if magnet.is_none() || status != MagnetStatus::Ok {
+                        magnet = Some(status);
+                    }
+                    let Some(raw) = raw else {
+                        continue;
+                    };
+                    LAST_RAW_ANGLE.store(raw as u32, Ordering::Relaxed);
+
+                    // the anemometer pulses since the previous heading, in step with the speed
+                    let pulses = pulse_count();
+                    let weight = if weighted {
+                        pulses.wrapping_sub(last_pulses) as f32
+                    } else {
+                        1.0
+                    };
+                    last_pulses = pulses;
+                    average.push(self.calibration.heading(raw), weight);
+                }
+                Either::Second(()) => {
+                    break;
+                }
+            }
         }
-    };
 
-    (reading as f32) * (360.0 / 4096.0)
-}
+        let Some(magnet) = magnet else {
+            return self.health.track(Err(SensorError::NoData));
+        };
+        readings
+            .push(Reading::new(Quantity::VaneMagnet, magnet.as_str()))
+            .ok();
+        if let Ok(agc) = self.encoder.automatic_gain_control().await {
+            readings
+                .push(Reading::new(Quantity::VaneAgc, agc as f32))
+                .ok();
+        }
+
+        let Some(direction) = average.finish() else {
+            warn!("No vane magnet, the wind direction is missing");
+            // the runner keeps the magnet status and reports the direction as missing
+            return self.health.track(Err(SensorError::NoData));
+        };
+        let quality = if magnet == MagnetStatus::Ok {
+            Quality::Good
+        } else {
+            Quality::Degraded
+        };
+        let label = compass_point(direction.mean_deg)
+            .map_or(Reading::missing(Quantity::WindDirection), |label| {
+                Reading::new(Quantity::WindDirection, label)
+            });
+        for reading in [
+            label,
+            Reading::new(Quantity::WindAngle, direction.mean_deg),
+            Reading::new(Quantity::WindDirectionStddev, direction.stddev_deg),
+        ] {
+            readings.push(reading.with_quality(quality)).ok();
+        }
 
-fn invert_angle(avg_angle: f32) -> f32 {
-    (360.0 - avg_angle) % 360.0
+        self.health.track(Ok(()))
+    }
+
+    fn health(&self) -> Health {
+        self.health
+    }
 }
 
-fn match_direction(avg_angle: f32) -> String<DEFAULT_STRING_SIZE> {
-    let corrected = invert_angle(avg_angle);
-
-    let direction = match corrected {
-        0.0..45.0 => "N",
-        45.0..90.0 => "NE",
-        90.0..135.0 => "E",
-        135.0..180.0 => "SE",
-        180.0..225.0 => "S",
-        225.0..270.0 => "SW",
-        270.0..315.0 => "W",
-        315.0..360.0 => "NW",
-        _ => "Invalid Angle",
-    };
-
-    String::from_str(direction).unwrap()
+#[embassy_executor::task]
+pub async fn as5600_task(i2c: ShareI2cBus, mqtt_sender: ReadingSender) {
+    run_sensor(&mut WindVane::new(i2c), &mqtt_sender).await;
 }
@@ -1,42 +1,76 @@
-use crate::config::{CHANNEL_SIZE, MAX_RETRY};
-use crate::tasks::mqtt_task::MqttPacket;
+use crate::tasks::{mqtt_task::ReadingSender, sensor_runner::run_sensor};
 use dht_sensor::dht22::r#async as dht22_async;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
 use embassy_time::{Delay, Timer};
 use esp_hal::gpio::{DriveMode, Flex, OutputConfig, Pull};
 use log::error;
+use weather_core::{
+    reading::{Quantity, Reading},
+    sensor::{Health, Readings, Sensor, SensorError, SensorId},
+};
 
-#[embassy_executor::task]
-pub async fn dht_task(
-    mut dht_pin: Flex<'static>,
-    mqtt_sender: Sender<'static, CriticalSectionRawMutex, MqttPacket, CHANNEL_SIZE>,
-) {
-    let mut delay = Delay;
-    // Configure as open-drain with pull-up, then enable output+input
-    dht_pin.apply_output_config(
-        &OutputConfig::default()
-            .with_drive_mode(DriveMode::OpenDrain)
-            .with_pull(Pull::Up),
-    );
-    dht_pin.set_output_enable(true);
-    dht_pin.set_input_enable(true);
-    dht_pin.set_high(); // release the bus (idle high via pull-up)
-    Timer::after_secs(3).await;
-
-    let mut retry = 0;
-
-    while retry < MAX_RETRY {
-        match dht22_async::read(&mut delay, &mut dht_pin).await {
+/// Air temperature of the window, used to compensate the battery voltage
+pub static TEMPERATURE: Watch<CriticalSectionRawMutex, f32, 1> = Watch::new();
+
+pub struct Dht22 {
+    pin: Flex<'static>,
+    health: Health,
+}
+
+impl Dht22 {
+    pub fn new(pin: Flex<'static>) -> Self {
+        Dht22 {
+            pin,
+            health: Health::Unknown,
+        }
+    }
+}
+
+impl Sensor for Dht22 {
+    const ID: SensorId = SensorId::Dht22;
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        // Configure as open-drain with pull-up, then enable output+input
+        self.pin.apply_output_config(
+            &OutputConfig::default()
+                .with_drive_mode(DriveMode::OpenDrain)
+                .with_pull(Pull::Up),
+        );
+        self.pin.set_output_enable(true);
+        self.pin.set_input_enable(true);
+        self.pin.set_high(); // release the bus (idle high via pull-up)
+        Timer::after_secs(3).await;
+
+        self.health.track(Ok(()))
+    }
+
+    async fn sample(&mut self, readings: &mut Readings) -> Result<(), SensorError> {
+        let res = match dht22_async::read(&mut Delay, &mut self.pin).await {
             Ok(reading) => {
-                publish!(&mqtt_sender, "temperature", reading.temperature);
-                publish!(&mqtt_sender, "humidity", reading.relative_humidity);
-                break;
+                TEMPERATURE.sender().send(reading.temperature);
+                readings
+                    .push(Reading::new(Quantity::Temperature, reading.temperature))
+                    .ok();
+                readings
+                    .push(Reading::new(Quantity::Humidity, reading.relative_humidity))
+                    .ok();
+                Ok(())
             }
             Err(e) => {
                 error!("Fail reading DHT {e:?}");
+                Err(SensorError::Read)
             }
-        }
-        retry += 1;
-        Timer::after_secs(1).await;
+        };
+
+        self.health.track(res)
     }
+
+    fn health(&self) -> Health {
+        self.health
+    }
+}
+
+#[embassy_executor::task]
+pub async fn dht_task(dht_pin: Flex<'static>, mqtt_sender: ReadingSender) {
+    run_sensor(&mut Dht22::new(dht_pin), &mqtt_sender).await;
 }
@@ -1,83 +1,179 @@
-use crate::config::{CHANNEL_SIZE, MAX_RETRY};
-use crate::tasks::mqtt_task::MqttPacket;
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::Sender;
-use embassy_time::Timer;
-use esp_hal::i2c::master::I2c;
-use esp_hal::Async;
+use crate::rtc_manager::{timestamp, unix_offset, with_charge_counter};
+use crate::settings::settings;
+use crate::tasks::{dht_task::TEMPERATURE, mqtt_task::ReadingSender, sensor_runner::run_sensor};
+use crate::ShareI2cBus;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer, WithTimeout};
 use ina219::address::Address;
-use ina219::calibration::{IntCalibration, MicroAmpere};
+use ina219::calibration::{Calibration, IntCalibration, MicroAmpere};
 use ina219::AsyncIna219;
-use log::error;
+use log::{error, warn};
+use weather_core::{
+    battery::{BatteryModel, ShuntCalibration},
+    power::BatteryState,
+    reading::{Quantity, Reading},
+    sensor::{Health, Readings, Sensor, SensorError, SensorId},
+};
 
-#[embassy_executor::task]
-pub async fn ina210_task(
-    i2c: &'static mut I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>,
-    mqtt_sender: Sender<'static, CriticalSectionRawMutex, MqttPacket, CHANNEL_SIZE>,
-) {
-    let current_lsb = MicroAmpere(15); // max current (0.5A) / 32767 (size of reg)
-    let r_shunt_uohm = 100_000;
-
-    let calib = IntCalibration::new(current_lsb, r_shunt_uohm).unwrap();
-    Timer::after_secs(1).await;
-    let mut ina = match AsyncIna219::new_calibrated(i2c, Address::default(), calib).await {
-        Ok(ina) => ina,
-        Err(e) => {
-            error!("Error initiating the ina219: {e:?}");
-            return;
-        }
-    };
-
-    let mut retry = 0;
-    while retry < MAX_RETRY {
-        match ina.bus_voltage().await {
-            Ok(voltage) => {
-                let voltage = (voltage.voltage_mv() + 160) as f32;
-
-                publish!(&mqtt_sender, "battery/voltage", voltage);
-                publish!(
-                    &mqtt_sender,
-                    "battery/percentage",
-                    voltage_to_soc(voltage / 1000.0)
-                );
-                break;
-            }
-            Err(e) => error!("Fail reading ina219: {e:?}"),
+const DEFAULT_CURRENT_LSB_UA: i64 = 16; // 0.5 A over the 15 bits of the current register
+const DEFAULT_SHUNT_UOHM: u32 = 100_000;
+const SECONDS_PER_DAY: u64 = 86_400;
+const TEMPERATURE_WAIT_SECS: u64 = 5;
+
+/// Last battery measurement of the window, read by the power policy
+pub static BATTERY: Signal<CriticalSectionRawMutex, BatteryState> = Signal::new();
+
+pub struct Ina219 {
+    i2c: Option<ShareI2cBus>,
+    ina: Option<AsyncIna219<ShareI2cBus, IntCalibration>>,
+    calib: IntCalibration,
+    calibration: ShuntCalibration,
+    battery: BatteryModel,
+    health: Health,
+}
+
+impl Ina219 {
+    pub fn new(i2c: ShareI2cBus) -> Self {
+        let calibration = settings().shunt_calibration();
+        let calib = IntCalibration::new(
+            MicroAmpere(calibration.current_lsb_ua()),
+            calibration.shunt_uohm(),
+        )
+        .unwrap_or_else(|| {
+            error!("Invalid ina219 calibration {calibration:?}, using the default one");
+            IntCalibration::new(MicroAmpere(DEFAULT_CURRENT_LSB_UA), DEFAULT_SHUNT_UOHM).unwrap()
+        });
+
+        let battery = settings().battery_model().unwrap_or_else(|| {
+            error!("Invalid battery description, assuming a Li-ion cell");
+            BatteryModel::default()
+        });
+
+        Ina219 {
+            i2c: Some(i2c),
+            ina: None,
+            calib,
+            calibration,
+            battery,
+            health: Health::Unknown,
         }
-        retry += 1;
     }
 }
 
-const SOC_TABLE: &[(f32, f32)] = &[
-    (4.20, 100.0),
-    (4.10, 90.0),
-    (4.00, 80.0),
-    (3.90, 70.0),
-    (3.80, 55.0),
-    (3.70, 35.0),
-    (3.60, 20.0),
-    (3.50, 8.0),
-    (3.40, 0.0),
-];
-
-fn voltage_to_soc(v: f32) -> f32 {
-    if v >= SOC_TABLE[0].0 {
-        return 100.0;
-    }
-    if v <= SOC_TABLE[SOC_TABLE.len() - 1].0 {
-        return 0.0;
+impl Sensor for Ina219 {
+    const ID: SensorId = SensorId::Ina219;
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        let Some(i2c) = self.i2c.take() else {
+            return self.health.track(Ok(()));
+        };
+
+        Timer::after_secs(1).await;
+        let res = match AsyncIna219::new_calibrated(i2c, Address::default(), self.calib).await {
+            Ok(ina) => {
+                self.ina = Some(ina);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error initiating the ina219: {e:?}");
+                self.i2c = Some(e.device);
+                Err(SensorError::Init)
+            }
+        };
+
+        self.health.track(res)
     }
 
-    // Find interval and linearly interpolate
-    for win in SOC_TABLE.windows(2) {
-        let (v_hi, soc_hi) = win[0];
-        let (v_lo, soc_lo) = win[1];
-        if v <= v_hi && v >= v_lo {
-            let t = (v - v_lo) / (v_hi - v_lo);
-            return soc_lo + t * (soc_hi - soc_lo);
+    async fn sample(&mut self, readings: &mut Readings) -> Result<(), SensorError> {
+        let Some(ina) = self.ina.as_mut() else {
+            return self.health.track(Err(SensorError::Init));
+        };
+
+        let voltage = match ina.bus_voltage().await {
+            Ok(bus) => self.calibration.battery_mv(bus.voltage_mv()),
+            Err(e) => {
+                error!("Fail reading ina219: {e:?}");
+                return self.health.track(Err(SensorError::Read));
+            }
+        };
+        // the shunt is wired so that a charging battery reads positive
+        let current_ma = match ina.current_raw().await {
+            Ok(reg) => Some(self.calib.current_from_register(reg).0 as f32 / 1000.0),
+            Err(e) => {
+                warn!("Fail reading ina219 current: {e:?}");
+                None
+            }
+        };
+        let soc = self
+            .battery
+            .soc(voltage, current_ma, air_temperature().await);
+        readings
+            .push(Reading::new(Quantity::BatteryVoltage, voltage))
+            .ok();
+        readings
+            .push(Reading::new(Quantity::BatteryPercentage, soc))
+            .ok();
+        match ina.power_raw().await {
+            Ok(reg) => {
+                let power_mw = self.calib.power_from_register(reg).0 as f32 / 1000.0;
+                readings
+                    .push(Reading::new(Quantity::BatteryPower, power_mw))
+                    .ok();
+            }
+            Err(e) => warn!("Fail reading ina219 power: {e:?}"),
+        }
+        match ina.shunt_voltage().await {
+            Ok(shunt) => {
+                let shunt_mv = shunt.shunt_voltage_uv() as f32 / 1000.0;
+                readings
+                    .push(Reading::new(Quantity::ShuntVoltage, shunt_mv))
+                    .ok();
+            }
+            Err(e) => warn!("Fail reading ina219 shunt voltage: {e:?}"),
+        }
+
+        if let Some(current_ma) = current_ma {
+            let now = timestamp();
+            let day = (unix_offset().unwrap_or(0) + now) / SECONDS_PER_DAY;
+            let cycle = settings().duty_cycle();
+            let charge = with_charge_counter(|c| c.record(now, day as u32, current_ma, cycle));
+            readings
+                .push(Reading::new(Quantity::BatteryCurrent, current_ma))
+                .ok();
+            readings
+                .push(Reading::new(Quantity::ChargeIn, charge.in_mah))
+                .ok();
+            readings
+                .push(Reading::new(Quantity::ChargeOut, charge.out_mah))
+                .ok();
         }
+
+        BATTERY.signal(BatteryState {
+            voltage_mv: voltage,
+            soc,
+            current_ma,
+        });
+        self.health.track(Ok(()))
     }
 
-    0.0 // fallback
+    fn health(&self) -> Health {
+        self.health
+    }
+}
+
+/// Air temperature measured by the DHT22 during this window, if it comes in time
+async fn air_temperature() -> Option<f32> {
+    match TEMPERATURE.receiver() {
+        Some(mut receiver) => receiver
+            .get()
+            .with_timeout(Duration::from_secs(TEMPERATURE_WAIT_SECS))
+            .await
+            .ok(),
+        None => TEMPERATURE.try_get(),
+    }
+}
+
+#[embassy_executor::task]
+pub async fn ina210_task(i2c: ShareI2cBus, mqtt_sender: ReadingSender) {
+    run_sensor(&mut Ina219::new(i2c), &mqtt_sender).await;
 }
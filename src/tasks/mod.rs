@@ -4,4 +4,5 @@ pub mod dht_task;
 pub mod ina219_task;
 pub mod mqtt_task;
 pub mod ota_task;
+pub mod sensor_runner;
 pub mod wifi_task;
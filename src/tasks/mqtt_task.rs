@@ -1,96 +1,641 @@
+use core::fmt::Write as _;
 use core::str::FromStr;
 use embassy_net::IpAddress;
 use rust_mqtt::packet::v5::publish_packet::QualityOfService;
 
-use crate::config::BUFFER_SIZE;
-use crate::config::{CHANNEL_SIZE, CONFIG, PAYLOAD_SIZE, SOCKET_TIMEOUT, TOPIC_SIZE};
-use embassy_net::{tcp::TcpSocket, Stack};
+use crate::commands;
+use crate::config::{ACK_SIZE, COMMAND_POLL_SECS, COMMAND_SIZE, COMMAND_WAIT_MS};
+use crate::config::{BACKLOG_PAYLOAD_SIZE, BUFFER_SIZE, DISCOVERY_PAYLOAD_SIZE};
+use crate::config::{CHANNEL_SIZE, PAYLOAD_SIZE, SOCKET_TIMEOUT, TOPIC_SIZE};
+use crate::config::{
+    DIAGNOSTICS_PAYLOAD_SIZE, POWER_PAYLOAD_SIZE, STATE_PAYLOAD_SIZE, STATUS_SIZE,
+};
+use crate::config::{MQTT_BACKOFF_INITIAL_MS, MQTT_BACKOFF_MAX_MS};
+use crate::config::{MQTT_CONNECT_TIMEOUT_SECS, MQTT_MAX_RECONNECTS, NETWORK_BACKOFF_MAX_SECS};
+use crate::deadline::{DeadlineTransport, ReadDeadline};
+use crate::network::resolve;
+use crate::rtc_manager::{store_mqtt_failure, take_mqtt_failure, unix_offset, with_backlog};
+use crate::settings::settings;
+use crate::tls::{self, TLS_RECORD_SIZE};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::{
+    tcp::{ConnectError, TcpSocket},
+    Stack,
+};
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
-    channel::{Channel, Receiver},
+    channel::{Channel, Receiver, Sender},
+    signal::Signal,
 };
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, TimeoutError, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use embedded_tls::TlsError;
 use esp_hal::rng::Rng;
 use heapless::String;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rust_mqtt::client::{client::MqttClient, client_config::ClientConfig};
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use weather_core::{
+    backlog,
+    backoff::Backoff,
+    command::{self, Command, SLEEP_RANGE_S},
+    discovery::{self, Device},
+    reading::{Quality, Reading},
+    state::{Snapshot, StateContext},
+    status::{OFFLINE, ONLINE},
+};
+
+pub type ReadingSender = Sender<'static, CriticalSectionRawMutex, Reading, CHANNEL_SIZE>;
+pub type ReadingReceiver = Receiver<'static, CriticalSectionRawMutex, Reading, CHANNEL_SIZE>;
+
+const MAX_PROPERTIES: usize = 16;
+
+type Client<'a, T> = MqttClient<'a, T, MAX_PROPERTIES, Rng>;
+
+pub static MQTT_CHANNEL: Channel<CriticalSectionRawMutex, Reading, CHANNEL_SIZE> = Channel::new();
+
+/// What the MQTT task publishes before closing the session
+pub struct WindowEnd {
+    /// Context of the state document, `None` when it is disabled
+    pub state: Option<StateContext>,
+    /// Power policy document, published on `<topic>/power`
+    pub power: String<POWER_PAYLOAD_SIZE>,
+    /// Sensor error statistics, published on `<topic>/diagnostics`
+    pub diagnostics: String<DIAGNOSTICS_PAYLOAD_SIZE>,
+    /// Availability published in place of `online`
+    pub status: String<STATUS_SIZE>,
+}
 
-pub static MQTT_CHANNEL: Channel<CriticalSectionRawMutex, MqttPacket, CHANNEL_SIZE> =
-    Channel::new();
+/// Raised by the main task when the active window is over
+pub static END_OF_WINDOW: Signal<CriticalSectionRawMutex, WindowEnd> = Signal::new();
+/// Raised by the MQTT task once it disconnected from the broker
+pub static WINDOW_CLOSED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
+/// Why an MQTT session could not be established or was lost
 #[derive(Debug)]
-pub struct MqttPacket {
-    topic: String<TOPIC_SIZE>,
-    payload: String<PAYLOAD_SIZE>,
+pub enum MqttError {
+    /// No `broker_url` and `broker_ip` is not a valid address
+    InvalidAddress,
+    /// `broker_url` could not be resolved and there is no valid `broker_ip` to fall back to
+    Dns,
+    /// The TCP connection to the broker failed
+    Tcp(ConnectError),
+    /// The broker did not answer in time
+    Timeout,
+    /// The TLS handshake failed or TLS is not configured properly
+    Tls(TlsError),
+    /// The broker refused the session or a packet could not be exchanged
+    Mqtt(ReasonCode),
 }
 
-impl MqttPacket {
-    pub fn new(topic: String<TOPIC_SIZE>, payload: String<PAYLOAD_SIZE>) -> Self {
-        MqttPacket { topic, payload }
+/// Failure reasons reported on `<topic>/last_error`, indexed by `MqttError::code() - 1`
+const FAILURE_REASONS: [&str; 7] = [
+    "invalid_address",
+    "tcp_connect",
+    "timeout",
+    "not_authorized",
+    "broker_error",
+    "dns",
+    "tls",
+];
+
+impl MqttError {
+    /// Non zero code persisted in RTC memory when a window is given up
+    ///
+    /// Codes survive a firmware update in RTC memory, new errors take the next free code.
+    fn code(&self) -> u8 {
+        match self {
+            MqttError::InvalidAddress => 1,
+            MqttError::Tcp(_) => 2,
+            MqttError::Timeout => 3,
+            MqttError::Mqtt(ReasonCode::NotAuthorized | ReasonCode::BadUserNameOrPassword) => 4,
+            MqttError::Mqtt(_) => 5,
+            MqttError::Dns => 6,
+            MqttError::Tls(_) => 7,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        FAILURE_REASONS[self.code() as usize - 1]
+    }
+
+    /// Retrying cannot fix a wrong configuration
+    fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            MqttError::InvalidAddress | MqttError::Tls(TlsError::InvalidCertificate)
+        )
     }
 }
 
+impl From<ConnectError> for MqttError {
+    fn from(e: ConnectError) -> Self {
+        MqttError::Tcp(e)
+    }
+}
+
+impl From<TlsError> for MqttError {
+    fn from(e: TlsError) -> Self {
+        MqttError::Tls(e)
+    }
+}
+
+impl From<ReasonCode> for MqttError {
+    fn from(e: ReasonCode) -> Self {
+        MqttError::Mqtt(e)
+    }
+}
+
+impl From<TimeoutError> for MqttError {
+    fn from(_: TimeoutError) -> Self {
+        MqttError::Timeout
+    }
+}
+
+/// Socket, TLS and client buffers, reused by every session of the window
+struct Buffers {
+    tcp_rx: [u8; BUFFER_SIZE],
+    tcp_tx: [u8; BUFFER_SIZE],
+    tls_rx: [u8; TLS_RECORD_SIZE],
+    tls_tx: [u8; TLS_RECORD_SIZE],
+    mqtt_rx: [u8; BUFFER_SIZE],
+    mqtt_tx: [u8; BUFFER_SIZE],
+}
+
+/// State carried over from one session to the next within a window
+struct Window {
+    receiver: ReadingReceiver,
+    /// Discovery configs still have to be published
+    announce: bool,
+    snapshot: Snapshot,
+    backoff: Backoff,
+}
+
+/// Connect to the broker and publish every reading received on `mqtt_receiver`.
+///
+/// When `announce` is set, the Home Assistant discovery configs are (re)published first.
+/// A failed or lost session is re-established with exponential backoff, at most
+/// `MQTT_MAX_RECONNECTS` times in a row. Readings that cannot be delivered are kept in the RTC
+/// backlog and replayed on `<topic>/backlog` once the broker is reachable again.
 #[embassy_executor::task]
-pub async fn mqtt_task(
+pub async fn mqtt_task(stack: Stack<'static>, mqtt_receiver: ReadingReceiver, announce: bool) {
+    let mut buffers = Buffers {
+        tcp_rx: [0; BUFFER_SIZE],
+        tcp_tx: [0; BUFFER_SIZE],
+        tls_rx: [0; TLS_RECORD_SIZE],
+        tls_tx: [0; TLS_RECORD_SIZE],
+        mqtt_rx: [0; BUFFER_SIZE],
+        mqtt_tx: [0; BUFFER_SIZE],
+    };
+    let mut window = Window {
+        receiver: mqtt_receiver,
+        announce,
+        snapshot: Snapshot::new(),
+        backoff: Backoff::new(
+            MQTT_BACKOFF_INITIAL_MS,
+            MQTT_BACKOFF_MAX_MS,
+            MQTT_MAX_RECONNECTS,
+        ),
+    };
+
+    loop {
+        let e = match run_session(stack, &mut buffers, &mut window).await {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+        error!("MQTT session failed: {:?}", e);
+
+        let delay = if e.is_retryable() {
+            window.backoff.next_delay_ms()
+        } else {
+            None
+        };
+        let Some(delay) = delay else {
+            error!("Giving up on the broker for this window: {}", e.reason());
+            store_mqtt_failure(e.code());
+            buffer_until(&window.receiver, Some(&mut window.snapshot), Instant::MAX).await;
+            break;
+        };
+
+        info!("Reconnecting to the broker in {} ms", delay);
+        let deadline = Instant::now() + Duration::from_millis(delay);
+        if !buffer_until(&window.receiver, Some(&mut window.snapshot), deadline).await {
+            break;
+        }
+    }
+    WINDOW_CLOSED.signal(());
+}
+
+/// Keep every reading of the window in the backlog, used in place of `mqtt_task` when the
+/// network did not come up.
+#[embassy_executor::task]
+pub async fn buffer_task(mqtt_receiver: ReadingReceiver) {
+    buffer_until(&mqtt_receiver, None, Instant::MAX).await;
+    WINDOW_CLOSED.signal(());
+}
+
+/// Run one MQTT session until the end of the window
+///
+/// Returns `Ok` once the session was closed cleanly at the end of the window.
+async fn run_session(
     stack: Stack<'static>,
-    mqtt_receiver: Receiver<'static, CriticalSectionRawMutex, MqttPacket, CHANNEL_SIZE>,
-) {
-    let broker = (
-        IpAddress::from_str(CONFIG.broker_ip).unwrap(),
-        CONFIG.broker_port,
-    );
+    buffers: &mut Buffers,
+    window: &mut Window,
+) -> Result<(), MqttError> {
+    let port = if settings().mqtt_tls {
+        settings().broker_tls_port
+    } else {
+        settings().broker_port
+    };
+    let broker = (resolve_broker(stack).await?, port);
     debug!("Broker address: {broker:?}");
+    let connect_timeout = Duration::from_secs(MQTT_CONNECT_TIMEOUT_SECS);
 
     // Create a TCP socket
-    let mut tcp_rx = [0; BUFFER_SIZE];
-    let mut tcp_tx = [0; BUFFER_SIZE];
-    let mut socket = TcpSocket::new(stack, &mut tcp_rx, &mut tcp_tx);
+    let mut socket = TcpSocket::new(stack, &mut buffers.tcp_rx, &mut buffers.tcp_tx);
     socket.set_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT)));
-    socket.connect(broker).await.unwrap();
+    socket
+        .connect(broker)
+        .with_timeout(connect_timeout)
+        .await??;
+
+    let (mqtt_tx, mqtt_rx) = (&mut buffers.mqtt_tx, &mut buffers.mqtt_rx);
+    if !settings().mqtt_tls {
+        return run_client(socket, mqtt_tx, mqtt_rx, window).await;
+    }
+
+    let tls = tls::open(socket, &mut buffers.tls_rx, &mut buffers.tls_tx)
+        .with_timeout(connect_timeout)
+        .await??;
+    run_client(tls, mqtt_tx, mqtt_rx, window).await
+}
 
+/// Open the MQTT session over `transport` and publish readings until the end of the window
+async fn run_client<T: Read + Write>(
+    transport: T,
+    mqtt_tx: &mut [u8; BUFFER_SIZE],
+    mqtt_rx: &mut [u8; BUFFER_SIZE],
+    window: &mut Window,
+) -> Result<(), MqttError> {
     // Create mqtt client
+    let mut status_topic: String<TOPIC_SIZE> = String::new();
+    let _ = discovery::write_availability_topic(&mut status_topic, &ha_device());
+
     let rng = Rng::new();
-    let mut config: ClientConfig<'static, 16, Rng> =
+    let mut config: ClientConfig<'_, MAX_PROPERTIES, Rng> =
         rust_mqtt::client::client_config::ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
             rng,
         );
     config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
     config.add_client_id("esp_client");
-    config.add_username(CONFIG.mqtt_user);
-    config.add_password(CONFIG.mqtt_pass);
-
-    let mut mqtt_rx = [0; BUFFER_SIZE];
-    let mut mqtt_tx = [0; BUFFER_SIZE];
+    config.add_username(&settings().mqtt_user);
+    config.add_password(&settings().mqtt_pass);
+    config.keep_alive = settings().mqtt_keep_alive_secs;
+    if settings().mqtt_lwt {
+        config.add_will(&status_topic, OFFLINE.as_bytes(), true);
+    }
 
+    let deadline = ReadDeadline::default();
     let mut client = MqttClient::new(
-        socket,
-        &mut mqtt_tx,
+        DeadlineTransport::new(transport, &deadline),
+        mqtt_tx,
         BUFFER_SIZE,
-        &mut mqtt_rx,
+        mqtt_rx,
         BUFFER_SIZE,
         config,
     );
 
     client
         .connect_to_broker()
-        .await
-        .expect("Couldnt connect to broker!");
+        .with_timeout(Duration::from_secs(MQTT_CONNECT_TIMEOUT_SECS))
+        .await??;
+    window.backoff.reset();
 
+    if core::mem::take(&mut window.announce) {
+        publish_discovery(&mut client).await;
+    }
+    publish_retained(&mut client, &status_topic, ONLINE.as_bytes()).await;
+    report_failure(&mut client).await;
+    if settings().mqtt_commands {
+        check_commands(&mut client, &deadline).await?;
+    }
+    replay_backlog(&mut client).await?;
+
+    let mut next_poll = Instant::now() + Duration::from_secs(COMMAND_POLL_SECS);
     loop {
-        let received = mqtt_receiver.receive().await;
-        info!("topic: {}, payload: {}", received.topic, received.payload);
+        // while staying awake, commands are picked up periodically; rebuilt on every pass so a
+        // command ending the stay takes effect at once
+        let command_poll = async {
+            match commands::stay_awake_until() {
+                Some(until) if settings().mqtt_commands && next_poll < until => {
+                    Timer::at(next_poll).await
+                }
+                _ => core::future::pending().await,
+            }
+        };
 
-        client
+        let received = match select3(
+            window.receiver.receive(),
+            END_OF_WINDOW.wait(),
+            command_poll,
+        )
+        .await
+        {
+            Either3::First(reading) => reading,
+            Either3::Third(()) => {
+                check_commands(&mut client, &deadline).await?;
+                next_poll = Instant::now() + Duration::from_secs(COMMAND_POLL_SECS);
+                continue;
+            }
+            Either3::Second(end) => {
+                if let Some(ctx) = end.state {
+                    publish_state(&mut client, &window.snapshot, &ctx).await;
+                }
+                let mut power_topic: String<TOPIC_SIZE> = String::new();
+                let _ = write!(power_topic, "{}/power", settings().topic);
+                publish_retained(&mut client, &power_topic, end.power.as_bytes()).await;
+                let mut diagnostics_topic: String<TOPIC_SIZE> = String::new();
+                let _ = write!(diagnostics_topic, "{}/diagnostics", settings().topic);
+                publish_retained(&mut client, &diagnostics_topic, end.diagnostics.as_bytes()).await;
+                publish_retained(&mut client, &status_topic, end.status.as_bytes()).await;
+                client
+                    .disconnect()
+                    .await
+                    .map_err(|e| error!("Error disconnecting from broker: {:?}", e))
+                    .ok();
+                return Ok(());
+            }
+        };
+        record_state(&mut window.snapshot, received);
+        // missing data only shows in the state document, topics keep their last value
+        if received.quality == Quality::Invalid {
+            continue;
+        }
+
+        let (topic, payload) = format_reading(&received);
+        info!("topic: {}, payload: {}", topic, payload);
+
+        if let Err(e) = client
             .send_message(
-                received.topic.as_str(),
-                received.payload.as_bytes(),
+                topic.as_str(),
+                payload.as_bytes(),
                 QualityOfService::QoS1,
                 true,
             )
             .await
-            .map_err(|e| error!("Error sending mqtt packet: {:?}", e))
-            .ok();
+        {
+            store(&received);
+            return Err(e.into());
+        }
         Timer::after_millis(500).await;
     }
 }
+
+/// Address of the broker
+///
+/// `broker_url` is resolved through DNS when set, `broker_ip` is the fallback.
+async fn resolve_broker(stack: Stack<'static>) -> Result<IpAddress, MqttError> {
+    if !settings().broker_url.is_empty() {
+        if let Some(addr) = resolve(stack, &settings().broker_url).await {
+            return Ok(addr);
+        }
+    }
+
+    IpAddress::from_str(&settings().broker_ip).map_err(|_| {
+        if settings().broker_url.is_empty() {
+            MqttError::InvalidAddress
+        } else {
+            MqttError::Dns
+        }
+    })
+}
+
+/// Keep incoming readings in the backlog until `deadline`, and in `snapshot` when given
+///
+/// Returns `false` when the window ended first.
+async fn buffer_until(
+    mqtt_receiver: &ReadingReceiver,
+    mut snapshot: Option<&mut Snapshot>,
+    deadline: Instant,
+) -> bool {
+    loop {
+        match select3(
+            mqtt_receiver.receive(),
+            END_OF_WINDOW.wait(),
+            Timer::at(deadline),
+        )
+        .await
+        {
+            Either3::First(reading) => {
+                // a later session of the window still publishes them in the state document
+                if let Some(snapshot) = snapshot.as_deref_mut() {
+                    record_state(snapshot, reading);
+                }
+                store(&reading);
+            }
+            Either3::Second(_) => return false,
+            Either3::Third(()) => return true,
+        }
+    }
+}
+
+/// Add `reading` to the state document of the window, when it is enabled
+fn record_state(snapshot: &mut Snapshot, reading: Reading) {
+    if settings().state_json {
+        snapshot.push(reading);
+    }
+}
+
+/// Handle the commands retained on `<topic>/cmd`
+///
+/// Commands are published retained so they reach the station at its next wakeup. The station
+/// subscribes only long enough to receive the retained message, acknowledges each command on
+/// `<topic>/cmd/ack` and then clears the retained message so it runs once.
+async fn check_commands<T: Read + Write>(
+    client: &mut Client<'_, DeadlineTransport<'_, T>>,
+    deadline: &ReadDeadline,
+) -> Result<(), ReasonCode> {
+    let mut topic: String<TOPIC_SIZE> = String::new();
+    let _ = write!(topic, "{}/cmd", settings().topic);
+
+    client.subscribe_to_topic(&topic).await?;
+    // The broker sends the retained message right after acknowledging the subscription. Only the
+    // wait for its first byte is bounded, a message that started is read to the end.
+    deadline.arm(Instant::now() + Duration::from_millis(COMMAND_WAIT_MS));
+    let mut payload: String<COMMAND_SIZE> = String::new();
+    match client.receive_message().await {
+        Ok((_, bytes)) => match core::str::from_utf8(bytes).map(|s| payload.push_str(s)) {
+            Ok(Ok(())) => {}
+            _ => warn!("Ignoring malformed command of {} bytes", bytes.len()),
+        },
+        Err(_) if deadline.expired() => {}
+        Err(e) => return Err(e),
+    }
+    client.unsubscribe_from_topic(&topic).await?;
+
+    if payload.is_empty() {
+        return Ok(());
+    }
+
+    let mut ack_topic: String<TOPIC_SIZE> = String::new();
+    let _ = write!(ack_topic, "{}/ack", topic);
+    for text in command::split(&payload) {
+        let result = Command::parse(text);
+        match result {
+            Ok(cmd) => commands::schedule(cmd),
+            Err(e) => warn!("Rejected command {}: {}", text, e.as_str()),
+        }
+
+        let mut ack: String<ACK_SIZE> = String::new();
+        let _ = command::write_ack(&mut ack, text, result);
+        client
+            .send_message(&ack_topic, ack.as_bytes(), QualityOfService::QoS1, false)
+            .await?;
+    }
+
+    client
+        .send_message(&topic, &[], QualityOfService::QoS1, true)
+        .await
+}
+
+/// Publish why the broker was given up during a previous window on `<topic>/last_error`
+async fn report_failure<T: Read + Write>(client: &mut Client<'_, T>) {
+    let code = take_mqtt_failure();
+    let Some(reason) = (code as usize)
+        .checked_sub(1)
+        .and_then(|i| FAILURE_REASONS.get(i))
+    else {
+        return;
+    };
+
+    let mut topic: String<TOPIC_SIZE> = String::new();
+    let _ = write!(topic, "{}/last_error", settings().topic);
+    client
+        .send_message(&topic, reason.as_bytes(), QualityOfService::QoS1, false)
+        .await
+        .map_err(|e| error!("Error publishing to {}: {:?}", topic, e))
+        .ok();
+}
+
+fn store(reading: &Reading) {
+    if with_backlog(|b| b.push(reading)) {
+        warn!("Backlog full, dropped the oldest reading");
+    }
+}
+
+/// Publish the backlog oldest first on `<topic>/backlog`
+///
+/// Replayed readings carry their original timestamp and are not retained, so they never
+/// override the current value of a topic. A reading leaves the backlog only once the broker
+/// acknowledged it.
+async fn replay_backlog<T: Read + Write>(client: &mut Client<'_, T>) -> Result<(), ReasonCode> {
+    let mut topic: String<TOPIC_SIZE> = String::new();
+    let _ = write!(topic, "{}/backlog", settings().topic);
+    let offset = unix_offset();
+    let mut replayed = 0;
+
+    while let Some(reading) = with_backlog(|b| b.front()) {
+        let mut payload: String<BACKLOG_PAYLOAD_SIZE> = String::new();
+        if backlog::write_json(&mut payload, &reading, offset).is_ok() {
+            client
+                .send_message(&topic, payload.as_bytes(), QualityOfService::QoS1, false)
+                .await?;
+        }
+        with_backlog(|b| b.pop_front());
+        replayed += 1;
+    }
+
+    if replayed > 0 {
+        info!("Replayed {} buffered readings", replayed);
+    }
+    Ok(())
+}
+
+/// Build the per-quantity topic of a reading and its plain text payload
+fn format_reading(reading: &Reading) -> (String<TOPIC_SIZE>, String<PAYLOAD_SIZE>) {
+    let mut topic = String::new();
+    let mut payload = String::new();
+
+    let _ = write!(topic, "{}/{}", settings().topic, reading.quantity.topic());
+    let _ = write!(payload, "{}", reading.value);
+
+    (topic, payload)
+}
+
+/// Publish every reading of the window as one JSON document on `<topic>/state`
+async fn publish_state<T: Read + Write>(
+    client: &mut Client<'_, T>,
+    snapshot: &Snapshot,
+    ctx: &StateContext,
+) {
+    let mut topic: String<TOPIC_SIZE> = String::new();
+    let mut payload: String<STATE_PAYLOAD_SIZE> = String::new();
+    let _ = write!(topic, "{}/state", settings().topic);
+
+    if snapshot.write_json(ctx, &mut payload).is_err() {
+        error!("State document does not fit in {STATE_PAYLOAD_SIZE} bytes");
+        return;
+    }
+    publish_retained(client, &topic, payload.as_bytes()).await;
+}
+
+fn ha_device() -> Device<'static> {
+    Device {
+        prefix: &settings().ha_prefix,
+        id: &settings().device_id,
+        name: &settings().device_name,
+        base_topic: &settings().topic,
+        firmware_version: env!("CARGO_PKG_VERSION"),
+        // values survive one missed wake cycle, however long the station sleeps
+        expire_after_s: 2 * longest_sleep_s() + settings().main_task_dur_secs,
+    }
+}
+
+/// Longest deep sleep the station can pick, in any power mode, after a `sleep` command or while
+/// backing off without network
+fn longest_sleep_s() -> u64 {
+    let s = settings();
+    [
+        s.deep_sleep_dur_secs,
+        s.low_soc_sleep_secs,
+        s.survival_sleep_secs,
+        *SLEEP_RANGE_S.end(),
+        NETWORK_BACKOFF_MAX_SECS,
+    ]
+    .into_iter()
+    .max()
+    .unwrap_or(s.deep_sleep_dur_secs)
+}
+
+/// Publish the retained Home Assistant discovery config of every quantity
+async fn publish_discovery<T: Read + Write>(client: &mut Client<'_, T>) {
+    let device = ha_device();
+
+    for &quantity in discovery::QUANTITIES {
+        let mut topic: String<TOPIC_SIZE> = String::new();
+        let mut payload: String<DISCOVERY_PAYLOAD_SIZE> = String::new();
+
+        if discovery::write_topic(&mut topic, &device, quantity).is_err()
+            || discovery::write_payload(&mut payload, &device, quantity).is_err()
+        {
+            error!("Discovery config of {:?} does not fit", quantity);
+            continue;
+        }
+        publish_retained(client, &topic, payload.as_bytes()).await;
+    }
+}
+
+async fn publish_retained<T: Read + Write>(
+    client: &mut Client<'_, T>,
+    topic: &str,
+    payload: &[u8],
+) {
+    info!("topic: {}, payload: {} bytes", topic, payload.len());
+
+    client
+        .send_message(topic, payload, QualityOfService::QoS1, true)
+        .await
+        .map_err(|e| error!("Error publishing to {}: {:?}", topic, e))
+        .ok();
+}
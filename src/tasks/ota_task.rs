@@ -1,4 +1,4 @@
-use crate::config::CONFIG;
+use crate::settings::settings;
 use embassy_net::{
     dns::DnsSocket,
     tcp::client::{TcpClient, TcpClientState},
@@ -17,6 +17,7 @@ use reqwless::{
     response::{HeaderIterator, Response},
 };
 use static_cell::StaticCell;
+use weather_core::ota;
 
 const NB_CON: usize = 1;
 const RX_SIZE: usize = 4096;
@@ -45,7 +46,7 @@ pub async fn check_for_ota(
 
     if let Ok(res) = with_timeout(
         Duration::from_secs(TIMEOUT_SECS),
-        client.request(Method::GET, CONFIG.ota_url),
+        client.request(Method::GET, &settings().ota_url),
     )
     .await
     {
@@ -138,14 +139,7 @@ pub async fn do_update<'resp, 'buf, C>(
 }
 
 pub fn get_crc(headers: HeaderIterator) -> u32 {
-    for (name, value) in headers {
-        if name.eq_ignore_ascii_case("target_crc") {
-            info!("got crc: {value:?}");
-            let s = core::str::from_utf8(value).unwrap_or("0");
-            if let Ok(crc) = s.trim().parse::<u32>() {
-                return crc;
-            }
-        }
-    }
-    0
+    let crc = ota::get_crc(headers);
+    info!("got crc: {crc}");
+    crc
 }
@@ -0,0 +1,54 @@
+//! Shared driver of the sensor tasks.
+//!
+//! Every sensor task builds its `Sensor` and hands it to `run_sensor`, which
+//! initialises it, samples it with the retry policy of `weather-core` and
+//! forwards the timestamped readings to the MQTT task. A sensor that produced
+//! no data reports its quantities as missing, its status readings such as the
+//! vane magnet aside, and the outcome of every window is counted in the sensor
+//! diagnostics. A window whose readings are all invalid, status readings
+//! aside, counts as no data.
+
+use embassy_time::Timer;
+use log::error;
+use weather_core::sensor::{init_with_retry, sample_with_retry, Readings, Sensor, SensorError};
+
+use crate::{
+    rtc_manager::{timestamp, with_sensor_diagnostics},
+    tasks::mqtt_task::ReadingSender,
+};
+
+const RETRY_DELAY_SECS: u64 = 1;
+
+pub async fn run_sensor<S: Sensor>(sensor: &mut S, mqtt_sender: &ReadingSender) {
+    let mut readings = Readings::new();
+    let outcome = match init_with_retry(sensor, || Timer::after_secs(RETRY_DELAY_SECS)).await {
+        Ok(()) => sample_with_retry(sensor, &mut readings, || {
+            Timer::after_secs(RETRY_DELAY_SECS)
+        })
+        .await
+        .inspect_err(|e| error!("{}: sampling failed: {e:?}", S::NAME)),
+        Err(e) => {
+            error!("{}: initialisation failed: {e:?}", S::NAME);
+            Err(e)
+        }
+    };
+
+    let outcome = match outcome {
+        Ok(()) if !S::ID.has_data(&readings) => {
+            error!("{}: no valid reading", S::NAME);
+            Err(SensorError::NoData)
+        }
+        Err(e) => {
+            // only the status readings are left, they tell why
+            S::ID.fill_missing(&mut readings);
+            Err(e)
+        }
+        ok => ok,
+    };
+    with_sensor_diagnostics(|d| d.record(S::ID, outcome));
+
+    let now = timestamp();
+    for reading in readings {
+        mqtt_sender.send(reading.with_timestamp(now)).await;
+    }
+}
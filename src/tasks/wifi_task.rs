@@ -1,38 +1,199 @@
-use crate::config::CONFIG;
+use crate::rtc_manager::{last_network, store_last_network, with_connection_cache};
+use crate::settings::settings;
+use core::cell::Cell;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicI32, Ordering};
+use critical_section::Mutex;
+use embassy_futures::select::select;
 use embassy_net::Runner;
-use esp_radio::wifi::{ClientConfig, ModeConfig, WifiController, WifiDevice, WifiEvent};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use esp_hal::efuse::Efuse;
+use esp_radio::wifi::event::{EventExt, StaConnected};
+use esp_radio::wifi::{
+    AccessPointConfig, AuthMethod, ClientConfig, ModeConfig, ScanConfig, WifiController,
+    WifiDevice, WifiEvent,
+};
+use heapless::{String, Vec};
+use weather_core::{
+    provision::portal_password,
+    wifi::{connection_order, Association, MAX_NETWORKS},
+};
+
+// RSSI of the access point sampled right after connecting, 0 when unknown
+static LAST_RSSI: AtomicI32 = AtomicI32::new(0);
+// BSSID and channel reported by the last StaConnected event
+static CONNECTED_AP: Mutex<Cell<Option<([u8; 6], u8)>>> = Mutex::new(Cell::new(None));
+
+/// Index of the network joined, signalled once the connection cache is up to date
+pub static JOINED: Signal<CriticalSectionRawMutex, usize> = Signal::new();
+/// Raised when the network is given up for this wake, turns the radio off
+pub static WIFI_STOP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn last_rssi() -> Option<i32> {
+    match LAST_RSSI.load(Ordering::Relaxed) {
+        0 => None,
+        rssi => Some(rssi),
+    }
+}
 
 #[embassy_executor::task]
 pub async fn runner_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await;
 }
 
+/// Join one of the configured networks and stay connected, until `WIFI_STOP` is raised
 #[embassy_executor::task]
 pub async fn wifi_task(mut controller: WifiController<'static>) {
-    // STA-only
-    let client_cfg = ModeConfig::Client(
-        ClientConfig::default()
-            .with_ssid(CONFIG.ssid.into())
-            .with_password(CONFIG.wifi_pass.into()),
-    );
+    StaConnected::update_handler(|event| {
+        let mut bssid = [0u8; 6];
+        bssid.copy_from_slice(event.bssid());
+        critical_section::with(|cs| CONNECTED_AP.borrow(cs).set(Some((bssid, event.channel()))));
+    });
+    controller
+        .set_config(&ModeConfig::Client(ClientConfig::default()))
+        .unwrap();
+    controller.start_async().await.unwrap();
 
-    controller.set_config(&client_cfg).unwrap();
+    select(stay_connected(&mut controller), WIFI_STOP.wait()).await;
+    if let Err(e) = controller.stop_async().await {
+        log::warn!("Couldn't stop Wi-Fi: {e:?}");
+    }
+    log::info!("Wi-Fi stopped");
+}
 
-    log::info!("Connecting to {} {}", CONFIG.ssid, CONFIG.wifi_pass);
+/// Keep trying the configured networks
+///
+/// The network joined on the previous wake is tried first, directly on the access point and
+/// channel it was joined through. With `wifi_scan`, the others are ranked by signal strength once
+/// it fails.
+async fn stay_connected(controller: &mut WifiController<'static>) {
+    let ssids = settings().networks().map(|(ssid, _)| ssid);
+    let mut last_good = last_network();
+    let mut cached = with_connection_cache(|cache| cache.association());
+    loop {
+        let seen = match last_good {
+            None if settings().wifi_scan => scan(controller, &ssids).await,
+            _ => Vec::new(),
+        };
 
-    controller.start_async().await.unwrap();
+        for index in connection_order(&ssids, last_good, &seen) {
+            let hint = cached.take().filter(|a| a.network as usize == index);
+            let joined = match hint {
+                Some(hint) => {
+                    join(controller, index, Some(hint)).await || {
+                        log::info!("Cached access point unavailable, scanning");
+                        with_connection_cache(|cache| cache.clear());
+                        join(controller, index, None).await
+                    }
+                }
+                None => join(controller, index, None).await,
+            };
 
-    loop {
-        match controller.connect_async().await {
-            Ok(()) => {
-                log::info!("STA connected");
+            if joined {
+                last_good = Some(index);
                 controller.wait_for_event(WifiEvent::StaDisconnected).await;
                 log::info!("STA disconnected, retrying");
             }
-            Err(e) => {
-                log::info!("connect_async() failed: {e:?}. Retrying");
-            }
+        }
+
+        if settings().wifi_scan {
+            last_good = None;
         }
         embassy_time::Timer::after_secs(1).await;
     }
 }
+
+/// Connect to network `index`, through the access point of `hint` when given
+async fn join(
+    controller: &mut WifiController<'static>,
+    index: usize,
+    hint: Option<Association>,
+) -> bool {
+    let (ssid, password) = settings().networks()[index];
+    let mut client_cfg = ClientConfig::default()
+        .with_ssid(ssid.into())
+        .with_password(password.into());
+    if let Some(hint) = hint {
+        client_cfg = client_cfg.with_bssid(hint.bssid).with_channel(hint.channel);
+    }
+    if let Err(e) = controller.set_config(&ModeConfig::Client(client_cfg)) {
+        log::error!("Invalid configuration for {ssid}: {e:?}");
+        return false;
+    }
+
+    log::info!("Connecting to {ssid}");
+    if let Err(e) = controller.connect_async().await {
+        log::info!("connect_async() failed: {e:?}. Retrying");
+        return false;
+    }
+
+    log::info!("STA connected to {ssid}");
+    store_last_network(index);
+    if let Ok(rssi) = controller.rssi() {
+        LAST_RSSI.store(rssi, Ordering::Relaxed);
+    }
+    if let Some((bssid, channel)) = critical_section::with(|cs| CONNECTED_AP.borrow(cs).take()) {
+        with_connection_cache(|cache| {
+            cache.set_association(Association {
+                network: index as u8,
+                bssid,
+                channel,
+            })
+        });
+    }
+    JOINED.signal(index);
+    true
+}
+
+/// Best RSSI of the configured networks in range
+async fn scan(
+    controller: &mut WifiController<'static>,
+    ssids: &[&'static str],
+) -> Vec<(&'static str, i8), MAX_NETWORKS> {
+    let mut seen = Vec::new();
+    match controller
+        .scan_with_config_async(ScanConfig::default())
+        .await
+    {
+        Ok(found) => {
+            for &ssid in ssids.iter().filter(|ssid| !ssid.is_empty()) {
+                let best = found
+                    .iter()
+                    .filter(|ap| ap.ssid == ssid)
+                    .map(|ap| ap.signal_strength)
+                    .max();
+                if let Some(rssi) = best {
+                    let _ = seen.push((ssid, rssi));
+                }
+            }
+        }
+        Err(e) => log::warn!("Wi-Fi scan failed: {e:?}"),
+    }
+    seen
+}
+
+/// Run the access point used for provisioning, named after `device_id`
+#[embassy_executor::task]
+pub async fn ap_task(mut controller: WifiController<'static>) {
+    let mut ssid = String::<32>::new();
+    let _ = write!(ssid, "{}-setup", settings().device_id);
+
+    let pass = portal_password(&settings().provision_pass, Efuse::mac_address());
+    if pass != settings().provision_pass {
+        // printed on the serial console of a station that was never configured
+        log::info!("Access point password: {}", pass);
+    }
+    let ap_cfg = AccessPointConfig::default()
+        .with_ssid(ssid.as_str().into())
+        .with_auth_method(AuthMethod::Wpa2Personal)
+        .with_password(pass.as_str().into());
+
+    controller
+        .set_config(&ModeConfig::AccessPoint(ap_cfg))
+        .unwrap();
+    controller.start_async().await.unwrap();
+    log::info!("Access point {} started", ssid);
+
+    // the access point stops when the controller is dropped
+    core::future::pending::<()>().await;
+}
@@ -0,0 +1,73 @@
+//! TLS transport for the MQTT session.
+//!
+//! The broker certificate is verified against a CA compiled into the firmware from
+//! `certs/ca.der` (see `build.rs`). Its validity period is checked against the wall clock kept in
+//! RTC memory, so the clock must have been synchronised at least once.
+
+use crate::rtc_manager::{timestamp, unix_offset};
+use crate::settings::settings;
+use embassy_net::tcp::TcpSocket;
+use embedded_tls::{
+    webpki::CertVerifier, Aes128GcmSha256, Certificate, TlsClock, TlsConfig, TlsConnection,
+    TlsContext, TlsError,
+};
+use esp_hal::rng::Trng;
+use log::{error, warn};
+
+/// Largest TLS record plus encryption overhead
+pub const TLS_RECORD_SIZE: usize = 16_640;
+/// Room for the server certificate chain kept during the handshake
+const CERT_SIZE: usize = 4096;
+
+/// DER encoded CA certificate, empty when none was provided at build time
+static CA_CERT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ca.der"));
+
+pub type TlsSocket<'a> = TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>;
+
+/// Wall clock used for the certificate validity checks
+struct RtcClock;
+
+impl TlsClock for RtcClock {
+    fn now() -> Option<u64> {
+        unix_offset().map(|offset| offset + timestamp())
+    }
+}
+
+/// Perform the TLS handshake over an established TCP connection
+///
+/// The certificate must be issued by the compiled-in CA and, when `tls_server_name` (or else
+/// `broker_url`) is set, be valid for that name.
+pub async fn open<'a>(
+    socket: TcpSocket<'a>,
+    rx: &'a mut [u8],
+    tx: &'a mut [u8],
+) -> Result<TlsSocket<'a>, TlsError> {
+    if CA_CERT.is_empty() {
+        error!("mqtt_tls is set but no CA certificate was compiled in");
+        return Err(TlsError::InvalidCertificate);
+    }
+    if RtcClock::now().is_none() {
+        warn!("Clock not synchronised, the broker certificate will be rejected");
+    }
+
+    let server_name = if settings().tls_server_name.is_empty() {
+        settings().broker_url.as_str()
+    } else {
+        settings().tls_server_name.as_str()
+    };
+    let mut config = TlsConfig::new().with_ca(Certificate::X509(CA_CERT));
+    if !server_name.is_empty() {
+        config = config.with_server_name(server_name);
+    }
+
+    // the radio is up during the window, which makes the hardware RNG a true one
+    let mut rng = Trng::try_new().map_err(|_| TlsError::UnableToInitializeCryptoEngine)?;
+
+    let mut tls = TlsConnection::new(socket, rx, tx);
+    tls.open::<_, CertVerifier<'_, Aes128GcmSha256, RtcClock, CERT_SIZE>>(TlsContext::new(
+        &config, &mut rng,
+    ))
+    .await?;
+
+    Ok(tls)
+}
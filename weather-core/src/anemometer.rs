@@ -0,0 +1,510 @@
+//! Wind speed statistics from anemometer pulses.
+//!
+//! The anemometer task timestamps every pulse of the sampling window. Gust
+//! and lull are the highest and lowest 3 second running means of the speed,
+//! sampled every 250 ms as recommended by the WMO, and the standard deviation
+//! is taken over the 1 second means.
+//!
+//! The window only lasts a few seconds, so the 10 minute average is built
+//! from the windows of the last 10 minutes, kept in a `WindHistory` that the
+//! firmware places in RTC memory.
+//!
+//! Pulse frequencies are turned into speeds by an `AnemometerCalibration`,
+//! preset for the supported anemometer models or given in the configuration.
+
+use crate::crc::Crc32;
+use crate::sealed::Seal;
+use crate::wind::METERS_PER_ROTATION;
+use heapless::Vec;
+
+/// Length of the running mean gusts and lulls are taken from.
+pub const GUST_WINDOW_MS: u32 = 3000;
+
+/// Step between two running means.
+pub const GUST_STEP_MS: u32 = 250;
+
+/// Length of the means the standard deviation is computed over.
+pub const STDDEV_BIN_MS: u32 = 1000;
+
+/// Period of the long term average.
+pub const AVERAGE_PERIOD_S: u64 = 600;
+
+/// Windows remembered by `WindHistory`.
+pub const HISTORY_LEN: usize = 16;
+
+/// Most points a speed table can have.
+pub const MAX_TABLE_POINTS: usize = 12;
+
+/// `(revolutions per second, km/h)` pairs, by increasing frequency.
+pub type SpeedTable = Vec<(f32, f32), MAX_TABLE_POINTS>;
+
+/// Anemometer model, selects the calibration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnemometerModel {
+    /// The cups shipped with the station, one pulse per revolution.
+    Stock,
+    /// Davis 6410, 2.25 mph per Hz.
+    Davis,
+    /// Misol / Fine Offset WH-SP-WS01, 2.4 km/h per Hz.
+    Misol,
+    /// Calibration given in the configuration.
+    Custom,
+}
+
+impl AnemometerModel {
+    /// Configuration name: `stock`, `davis`, `misol` or `custom`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "stock" => Some(AnemometerModel::Stock),
+            "davis" => Some(AnemometerModel::Davis),
+            "misol" => Some(AnemometerModel::Misol),
+            "custom" => Some(AnemometerModel::Custom),
+            _ => None,
+        }
+    }
+}
+
+/// Relation between the rotation of the cups and the wind speed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transfer {
+    /// `offset + meters_per_rev * revolutions per second`, in m/s, once the
+    /// cups turn.
+    Linear { meters_per_rev: f32, offset_ms: f32 },
+    /// Piecewise linear table, extended by its first and last segments.
+    Table(SpeedTable),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnemometerCalibration {
+    pulses_per_rev: u8,
+    transfer: Transfer,
+    max_hz: f32,
+}
+
+impl Default for AnemometerCalibration {
+    fn default() -> Self {
+        Self::model(AnemometerModel::Stock).unwrap()
+    }
+}
+
+impl AnemometerCalibration {
+    /// Calibration of an anemometer sending `pulses_per_rev` pulses per
+    /// revolution, at most `max_hz` of them per second; faster pulses are
+    /// bounces of the switch. `None` when a value is out of range.
+    pub fn new(pulses_per_rev: u8, transfer: Transfer, max_hz: f32) -> Option<Self> {
+        let transfer_valid = match &transfer {
+            Transfer::Linear {
+                meters_per_rev,
+                offset_ms,
+            } => *meters_per_rev > 0.0 && *offset_ms >= 0.0,
+            Transfer::Table(table) => table.len() >= 2,
+        };
+        (pulses_per_rev != 0 && max_hz > 0.0 && max_hz.is_finite() && transfer_valid).then_some(
+            AnemometerCalibration {
+                pulses_per_rev,
+                transfer,
+                max_hz,
+            },
+        )
+    }
+
+    /// Preset calibration of `model`, `None` for `Custom`.
+    pub fn model(model: AnemometerModel) -> Option<Self> {
+        let (pulses_per_rev, meters_per_rev, max_hz) = match model {
+            // the 200 ms debounce the station always used
+            AnemometerModel::Stock => (1, METERS_PER_ROTATION, 5.0),
+            AnemometerModel::Davis => (1, 2.25 * 0.447_04, 100.0),
+            AnemometerModel::Misol => (1, 2.4 / 3.6, 50.0),
+            AnemometerModel::Custom => return None,
+        };
+        let transfer = Transfer::Linear {
+            meters_per_rev,
+            offset_ms: 0.0,
+        };
+        Self::new(pulses_per_rev, transfer, max_hz)
+    }
+
+    /// Shortest interval between two pulses, anything closer is a bounce.
+    pub fn debounce_ms(&self) -> u64 {
+        (1000.0 / self.max_hz) as u64
+    }
+
+    /// Wind speed in km/h for pulses coming at `pulse_hz`.
+    pub fn speed_kmh(&self, pulse_hz: f32) -> f32 {
+        let rps = pulse_hz / self.pulses_per_rev as f32;
+        if rps <= 0.0 || !rps.is_finite() {
+            return 0.0;
+        }
+
+        match &self.transfer {
+            Transfer::Linear {
+                meters_per_rev,
+                offset_ms,
+            } => (offset_ms + meters_per_rev * rps) * 3.6,
+            Transfer::Table(table) => interpolate(table, rps).max(0.0),
+        }
+    }
+
+    /// Average wind speed in km/h for `pulses` counted over `window_secs`.
+    pub fn average_kmh(&self, pulses: u64, window_secs: u64) -> f32 {
+        if window_secs == 0 {
+            return 0.0;
+        }
+        self.speed_kmh(pulses as f32 / window_secs as f32)
+    }
+}
+
+/// Value of the piecewise linear `table` at `x`, extending its end segments.
+fn interpolate(table: &[(f32, f32)], x: f32) -> f32 {
+    let i = table
+        .windows(2)
+        .position(|w| x < w[1].0)
+        .unwrap_or(table.len() - 2);
+    let ((x0, y0), (x1, y1)) = (table[i], table[i + 1]);
+    y0 + (x - x0) * (y1 - y0) / (x1 - x0)
+}
+
+/// Parse a speed table, `rev_per_s:kmh` pairs separated by commas, e.g.
+/// `0.5:2.8,5:19,20:75`.
+///
+/// Frequencies must strictly increase and speeds must not decrease.
+pub fn parse_table(s: &str) -> Option<SpeedTable> {
+    let mut table = SpeedTable::new();
+    for point in s.split(',') {
+        let (hz, kmh) = point.trim().split_once(':')?;
+        let point: (f32, f32) = (hz.trim().parse().ok()?, kmh.trim().parse().ok()?);
+        if !(point.0 >= 0.0 && point.0.is_finite() && point.1 >= 0.0 && point.1.is_finite()) {
+            return None;
+        }
+        let out_of_order = table
+            .last()
+            .is_some_and(|&(hz_prev, kmh_prev)| point.0 <= hz_prev || point.1 < kmh_prev);
+        if out_of_order {
+            return None;
+        }
+        table.push(point).ok()?;
+    }
+
+    (table.len() >= 2).then_some(table)
+}
+
+/// Speed statistics of one sampling window, in km/h.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindStats {
+    pub mean_kmh: f32,
+    pub gust_kmh: f32,
+    pub lull_kmh: f32,
+    pub stddev_kmh: f32,
+}
+
+impl WindStats {
+    /// Statistics of a window of `window_ms` with pulses at `pulses_ms`,
+    /// milliseconds since its start in increasing order.
+    ///
+    /// `speed` converts a pulse frequency in Hz to km/h. Windows shorter than
+    /// `GUST_WINDOW_MS` report their mean as gust and lull.
+    pub fn from_pulses(pulses_ms: &[u32], window_ms: u32, speed: impl Fn(f32) -> f32) -> Self {
+        let rate = |count: usize, ms: u32| speed(count as f32 * 1000.0 / ms as f32);
+        let count_in = |from: u32, to: u32| {
+            pulses_ms.partition_point(|&t| t < to) - pulses_ms.partition_point(|&t| t < from)
+        };
+
+        if window_ms == 0 {
+            return WindStats {
+                mean_kmh: 0.0,
+                gust_kmh: 0.0,
+                lull_kmh: 0.0,
+                stddev_kmh: 0.0,
+            };
+        }
+        let mean_kmh = rate(count_in(0, window_ms), window_ms);
+
+        let (mut gust_kmh, mut lull_kmh) = (mean_kmh, mean_kmh);
+        if window_ms >= GUST_WINDOW_MS {
+            let mut running = (0..=(window_ms - GUST_WINDOW_MS) / GUST_STEP_MS).map(|i| {
+                let from = i * GUST_STEP_MS;
+                rate(count_in(from, from + GUST_WINDOW_MS), GUST_WINDOW_MS)
+            });
+            let first = running.next().unwrap_or(mean_kmh);
+            (gust_kmh, lull_kmh) =
+                running.fold((first, first), |(max, min), v| (max.max(v), min.min(v)));
+        }
+
+        WindStats {
+            mean_kmh,
+            gust_kmh,
+            lull_kmh,
+            stddev_kmh: stddev((0..window_ms / STDDEV_BIN_MS).map(|i| {
+                let from = i * STDDEV_BIN_MS;
+                rate(count_in(from, from + STDDEV_BIN_MS), STDDEV_BIN_MS)
+            })),
+        }
+    }
+}
+
+/// Population standard deviation, 0 for less than two values.
+fn stddev(values: impl Iterator<Item = f32> + Clone) -> f32 {
+    let (n, sum) = values
+        .clone()
+        .fold((0u32, 0.0), |(n, sum), v| (n + 1, sum + v));
+    if n < 2 {
+        return 0.0;
+    }
+    let mean = sum / n as f32;
+    let variance = values.map(|v| (v - mean) * (v - mean)).sum::<f32>() / n as f32;
+    libm::sqrtf(variance)
+}
+
+/// Mean speed over a period, from the windows that fell into it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeriodAverage {
+    pub mean_kmh: f32,
+    /// Seconds actually sampled during the period.
+    pub sampled_s: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Window {
+    /// RTC time the window ended at, 0 for an empty slot.
+    end_s: u32,
+    duration_s: u32,
+    mean_kmh: f32,
+}
+
+/// Mean speed of the last `HISTORY_LEN` sampling windows.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct WindHistory {
+    windows: [Window; HISTORY_LEN],
+    next: u32,
+}
+
+impl Default for WindHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WindHistory {
+    pub const fn new() -> Self {
+        WindHistory {
+            windows: [Window {
+                end_s: 0,
+                duration_s: 0,
+                mean_kmh: 0.0,
+            }; HISTORY_LEN],
+            next: 0,
+        }
+    }
+
+    /// Remember a window of `duration_s` ending at `end_s` (RTC seconds).
+    pub fn record(&mut self, end_s: u64, duration_s: u32, mean_kmh: f32) {
+        if !mean_kmh.is_finite() || duration_s == 0 {
+            return;
+        }
+
+        self.windows[self.next as usize] = Window {
+            end_s: end_s.min(u32::MAX as u64) as u32,
+            duration_s,
+            mean_kmh,
+        };
+        self.next = (self.next + 1) % HISTORY_LEN as u32;
+    }
+
+    /// Time weighted mean of the windows ended during the `period_s` before
+    /// `now_s`, `None` when there is none.
+    pub fn average(&self, now_s: u64, period_s: u64) -> Option<PeriodAverage> {
+        let since = now_s.saturating_sub(period_s);
+        let (sampled_s, sum) = self
+            .windows
+            .iter()
+            .filter(|w| w.duration_s != 0 && (since..=now_s).contains(&(w.end_s as u64)))
+            .fold((0u32, 0.0f32), |(secs, sum), w| {
+                (secs + w.duration_s, sum + w.mean_kmh * w.duration_s as f32)
+            });
+
+        (sampled_s != 0).then(|| PeriodAverage {
+            mean_kmh: sum / sampled_s as f32,
+            sampled_s,
+        })
+    }
+}
+
+impl Seal for WindHistory {
+    const TAG: u8 = 0xA5;
+    const EMPTY: Self = Self::new();
+
+    fn checksum(&self, crc: &mut Crc32) {
+        for w in &self.windows {
+            crc.update(&w.end_s.to_le_bytes());
+            crc.update(&w.duration_s.to_le_bytes());
+            crc.update(&w.mean_kmh.to_le_bytes());
+        }
+        crc.update(&self.next.to_le_bytes());
+    }
+
+    fn is_consistent(&self) -> bool {
+        (self.next as usize) < HISTORY_LEN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sealed::Sealed;
+
+    // one pulse per second is 1 km/h
+    fn speed(hz: f32) -> f32 {
+        hz
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn steady_wind() {
+        let pulses: std::vec::Vec<u32> = (0..30).map(|i| i * 1000 + 500).collect();
+        let stats = WindStats::from_pulses(&pulses, 30_000, speed);
+        assert!(close(stats.mean_kmh, 1.0));
+        assert!(close(stats.gust_kmh, 1.0));
+        assert!(close(stats.lull_kmh, 1.0));
+        assert!(close(stats.stddev_kmh, 0.0));
+    }
+
+    #[test]
+    fn gust_and_lull() {
+        // calm for 10 s, 3 s at 10 Hz, then 1 Hz
+        let mut pulses: std::vec::Vec<u32> = (0..30).map(|i| 10_000 + i * 100).collect();
+        pulses.extend((13..20).map(|s| s * 1000 + 500));
+        let stats = WindStats::from_pulses(&pulses, 20_000, speed);
+
+        assert!(close(stats.mean_kmh, 37.0 / 20.0));
+        assert!(close(stats.gust_kmh, 10.0));
+        assert!(close(stats.lull_kmh, 0.0));
+        assert!(stats.stddev_kmh > 3.0);
+    }
+
+    #[test]
+    fn short_window_reports_its_mean() {
+        let stats = WindStats::from_pulses(&[100, 600, 1100, 1600], 2000, speed);
+        assert!(close(stats.gust_kmh, 2.0));
+        assert!(close(stats.lull_kmh, 2.0));
+
+        let stats = WindStats::from_pulses(&[], 0, speed);
+        assert_eq!(stats.mean_kmh, 0.0);
+    }
+
+    #[test]
+    fn presets() {
+        let stock = AnemometerCalibration::default();
+        // 30 rotations in 30 s is 1.05 m/s
+        assert!(close(stock.average_kmh(30, 30), 3.78));
+        assert_eq!(stock.average_kmh(0, 30), 0.0);
+        assert_eq!(stock.debounce_ms(), 200);
+
+        let davis = AnemometerCalibration::model(AnemometerModel::Davis).unwrap();
+        assert!(close(davis.speed_kmh(1.0), 3.621));
+        let misol = AnemometerCalibration::model(AnemometerModel::Misol).unwrap();
+        assert!(close(misol.speed_kmh(1.0), 2.4));
+        assert_eq!(misol.debounce_ms(), 20);
+
+        assert_eq!(AnemometerCalibration::model(AnemometerModel::Custom), None);
+        assert_eq!(
+            AnemometerModel::parse("davis"),
+            Some(AnemometerModel::Davis)
+        );
+        assert_eq!(AnemometerModel::parse("kestrel"), None);
+    }
+
+    #[test]
+    fn linear_calibration_with_offset() {
+        let transfer = Transfer::Linear {
+            meters_per_rev: 1.0,
+            offset_ms: 0.5,
+        };
+        let cal = AnemometerCalibration::new(2, transfer, 40.0).unwrap();
+        // 4 pulses per second are 2 revolutions
+        assert!(close(cal.speed_kmh(4.0), 9.0));
+        // the offset only applies once the cups turn
+        assert_eq!(cal.speed_kmh(0.0), 0.0);
+        assert_eq!(cal.debounce_ms(), 25);
+    }
+
+    #[test]
+    fn table_calibration() {
+        let table = parse_table("0.5:2.8, 5:19,20:75").unwrap();
+        let cal = AnemometerCalibration::new(1, Transfer::Table(table), 50.0).unwrap();
+        assert!(close(cal.speed_kmh(5.0), 19.0));
+        assert!(close(cal.speed_kmh(12.5), 47.0));
+        // extended past the last point
+        assert!(close(cal.speed_kmh(25.0), 93.667));
+        // and below the first one, never negative
+        assert!(cal.speed_kmh(0.05) >= 0.0);
+
+        for invalid in ["", "1:5", "2:5,1:10", "1:10,2:5", "1:x,2:5", "-1:0,2:5"] {
+            assert_eq!(parse_table(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn invalid_calibrations() {
+        let linear = |meters_per_rev| Transfer::Linear {
+            meters_per_rev,
+            offset_ms: 0.0,
+        };
+        assert_eq!(AnemometerCalibration::new(0, linear(1.0), 5.0), None);
+        assert_eq!(AnemometerCalibration::new(1, linear(0.0), 5.0), None);
+        assert_eq!(AnemometerCalibration::new(1, linear(1.0), 0.0), None);
+    }
+
+    #[test]
+    fn standard_deviation() {
+        assert_eq!(stddev([4.0].into_iter()), 0.0);
+        assert!(close(
+            stddev([2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].into_iter()),
+            2.0
+        ));
+    }
+
+    #[test]
+    fn average_over_the_period() {
+        let mut history = WindHistory::new();
+        assert_eq!(history.average(1000, AVERAGE_PERIOD_S), None);
+
+        history.record(1000, 30, 10.0);
+        history.record(1300, 30, 20.0);
+        history.record(1600, 60, 40.0);
+        let avg = history.average(1600, AVERAGE_PERIOD_S).unwrap();
+        assert_eq!(avg.sampled_s, 120);
+        assert!(close(avg.mean_kmh, (300.0 + 600.0 + 2400.0) / 120.0));
+
+        // the first window is now older than 10 minutes
+        let avg = history.average(1700, AVERAGE_PERIOD_S).unwrap();
+        assert_eq!(avg.sampled_s, 90);
+    }
+
+    #[test]
+    fn history_wraps_and_skips_invalid_windows() {
+        let mut history = WindHistory::new();
+        for i in 0..HISTORY_LEN as u64 + 4 {
+            history.record(100 + i, 1, i as f32);
+        }
+        history.record(200, 30, f32::NAN);
+        let avg = history.average(200, AVERAGE_PERIOD_S).unwrap();
+        assert_eq!(avg.sampled_s, HISTORY_LEN as u32);
+    }
+
+    #[test]
+    fn corrupted_history_starts_over() {
+        let mut history = Sealed::<WindHistory>::new();
+        history.update(|h| h.record(1000, 30, 10.0));
+        history.corrupt(|h| h.windows[0].mean_kmh = 1e9);
+        assert_eq!(history.update(|h| h.average(1000, AVERAGE_PERIOD_S)), None);
+
+        history.update(|h| h.record(1010, 30, 12.0));
+        let avg = history.get().unwrap().average(1010, AVERAGE_PERIOD_S);
+        assert_eq!(avg.unwrap().sampled_s, 30);
+    }
+}
@@ -0,0 +1,291 @@
+//! Store-and-forward buffer for undelivered readings.
+//!
+//! Readings that could not be published are kept in a fixed size ring buffer
+//! which the firmware places in RTC slow memory, so it survives deep sleep.
+//! They are replayed, oldest first, with their original timestamps once the
+//! broker is reachable again. When the buffer is full the oldest reading is
+//! evicted.
+//!
+//! The buffer is plain old data: any bit pattern left in memory after a
+//! power loss is either detected by `is_valid` or decoded as an invalid
+//! record and skipped.
+
+use crate::json::{write_str, write_value};
+use crate::reading::{Quality, Quantity, Reading, Value};
+use core::fmt::{self, Write};
+
+/// Compact form of a reading.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Record {
+    pub timestamp: u64,
+    pub value: f32,
+    pub quantity: u8,
+    pub quality: u8,
+}
+
+impl Record {
+    /// Encode a reading, a label as its index in `Quantity::labels`.
+    ///
+    /// Missing readings are not stored, nor are `Value::Timestamp` readings,
+    /// which an `f32` cannot hold and the next window publishes again.
+    pub fn encode(reading: &Reading) -> Option<Self> {
+        let value = match reading.value {
+            Value::Number(value) => value,
+            Value::Label(label) => {
+                let labels = reading.quantity.labels();
+                labels.iter().position(|&l| l == label)? as f32
+            }
+            Value::Timestamp(_) | Value::Missing => return None,
+        };
+
+        Some(Record {
+            timestamp: reading.timestamp,
+            value,
+            quantity: reading.quantity as u8,
+            quality: reading.quality as u8,
+        })
+    }
+
+    pub fn decode(&self) -> Option<Reading> {
+        let quantity = Quantity::from_u8(self.quantity)?;
+        let quality = Quality::from_u8(self.quality)?;
+        let value = match quantity.labels() {
+            [] => Value::Number(self.value),
+            labels => Value::Label(labels.get(self.value as usize)?),
+        };
+
+        Some(
+            Reading::new(quantity, value)
+                .with_timestamp(self.timestamp)
+                .with_quality(quality),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct Backlog<const N: usize> {
+    head: u32,
+    len: u32,
+    records: [Record; N],
+}
+
+impl<const N: usize> Default for Backlog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Backlog<N> {
+    pub const fn new() -> Self {
+        Backlog {
+            head: 0,
+            len: 0,
+            records: [Record {
+                timestamp: 0,
+                value: 0.0,
+                quantity: 0,
+                quality: 0,
+            }; N],
+        }
+    }
+
+    /// Whether the indices are consistent, `false` for uninitialised memory.
+    pub fn is_valid(&self) -> bool {
+        (self.head as usize) < N && (self.len as usize) <= N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Append a reading, evicting the oldest one when full.
+    ///
+    /// Returns `true` when a reading was evicted to make room.
+    pub fn push(&mut self, reading: &Reading) -> bool {
+        let Some(record) = Record::encode(reading) else {
+            return false;
+        };
+        if N == 0 {
+            return true;
+        }
+
+        let tail = (self.head as usize + self.len as usize) % N;
+        self.records[tail] = record;
+
+        if self.len() == N {
+            self.head = ((self.head as usize + 1) % N) as u32;
+            true
+        } else {
+            self.len += 1;
+            false
+        }
+    }
+
+    /// Oldest valid reading, skipping records that cannot be decoded.
+    pub fn front(&mut self) -> Option<Reading> {
+        while !self.is_empty() {
+            if let Some(reading) = self.records[self.head as usize].decode() {
+                return Some(reading);
+            }
+            self.pop_front();
+        }
+        None
+    }
+
+    /// Drop the oldest record.
+    pub fn pop_front(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        self.head = ((self.head as usize + 1) % N) as u32;
+        self.len -= 1;
+    }
+}
+
+/// Serialise a replayed reading as a JSON object.
+///
+/// The timestamp is converted to Unix time when `unix_offset` is known,
+/// otherwise the raw RTC time is reported as `rtc_timestamp`.
+pub fn write_json<W: Write>(w: &mut W, reading: &Reading, unix_offset: Option<u64>) -> fmt::Result {
+    w.write_str("{\"quantity\":")?;
+    write_str(w, reading.quantity.name())?;
+    w.write_str(",\"value\":")?;
+    write_value(w, &reading.value)?;
+    w.write_str(",\"unit\":")?;
+    write_str(w, reading.unit.symbol())?;
+    w.write_str(",\"quality\":")?;
+    write_str(w, reading.quality.as_str())?;
+    match unix_offset {
+        Some(offset) => write!(w, ",\"timestamp\":{}}}", offset + reading.timestamp),
+        None => write!(w, ",\"rtc_timestamp\":{}}}", reading.timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(ts: u64) -> Reading {
+        Reading::new(Quantity::Temperature, ts as f32).with_timestamp(ts)
+    }
+
+    fn drain<const N: usize>(backlog: &mut Backlog<N>) -> Vec<u64> {
+        let mut out = Vec::new();
+        while let Some(r) = backlog.front() {
+            out.push(r.timestamp);
+            backlog.pop_front();
+        }
+        out
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let mut backlog = Backlog::<4>::new();
+        for ts in 1..=3 {
+            assert!(!backlog.push(&reading(ts)));
+        }
+        assert_eq!(backlog.len(), 3);
+        assert_eq!(drain(&mut backlog), [1, 2, 3]);
+        assert!(backlog.is_empty());
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let mut backlog = Backlog::<3>::new();
+        for ts in 1..=3 {
+            backlog.push(&reading(ts));
+        }
+        assert!(backlog.push(&reading(4)));
+        assert!(backlog.push(&reading(5)));
+        assert_eq!(backlog.len(), 3);
+        assert_eq!(drain(&mut backlog), [3, 4, 5]);
+    }
+
+    #[test]
+    fn keeps_original_reading() {
+        let mut backlog = Backlog::<2>::new();
+        let original = Reading::new(Quantity::BatteryVoltage, 3950.0)
+            .with_timestamp(1234)
+            .with_quality(Quality::Degraded);
+        backlog.push(&original);
+        assert_eq!(backlog.front(), Some(original));
+    }
+
+    #[test]
+    fn labels_are_stored_as_indices() {
+        let mut backlog = Backlog::<4>::new();
+        let labels = [
+            Reading::new(Quantity::WindDirection, "NNW"),
+            Reading::new(Quantity::RainSensor, "stuck"),
+            Reading::new(Quantity::VaneMagnet, "too_weak"),
+        ];
+        for reading in &labels {
+            backlog.push(&reading.with_timestamp(7));
+        }
+        for reading in labels {
+            assert_eq!(backlog.front(), Some(reading.with_timestamp(7)));
+            backlog.pop_front();
+        }
+
+        // unknown labels, timestamps and missing values are dropped
+        assert_eq!(
+            Record::encode(&Reading::new(Quantity::WindDirection, "up")),
+            None
+        );
+        assert_eq!(
+            Record::encode(&Reading::new(
+                Quantity::RainLastTip,
+                Value::Timestamp(1_760_000_000)
+            )),
+            None
+        );
+        assert_eq!(Record::encode(&Reading::missing(Quantity::Humidity)), None);
+    }
+
+    #[test]
+    fn detects_garbage_indices() {
+        let mut backlog = Backlog::<4>::new();
+        assert!(backlog.is_valid());
+        backlog.head = 17;
+        assert!(!backlog.is_valid());
+        backlog.head = 0;
+        backlog.len = 5;
+        assert!(!backlog.is_valid());
+    }
+
+    #[test]
+    fn skips_undecodable_records() {
+        let mut backlog = Backlog::<4>::new();
+        backlog.push(&reading(1));
+        backlog.push(&reading(2));
+        backlog.records[0].quantity = 0xff;
+        assert_eq!(drain(&mut backlog), [2]);
+    }
+
+    #[test]
+    fn json_with_and_without_wall_clock() {
+        let reading = Reading::new(Quantity::Rain, 0.5).with_timestamp(100);
+
+        let mut out = String::new();
+        write_json(&mut out, &reading, Some(1_760_000_000)).unwrap();
+        assert_eq!(
+            out,
+            r#"{"quantity":"rain","value":0.5,"unit":"mm","quality":"good","timestamp":1760000100}"#
+        );
+
+        out.clear();
+        write_json(&mut out, &reading, None).unwrap();
+        assert!(out.ends_with(r#""rtc_timestamp":100}"#));
+    }
+}
@@ -0,0 +1,73 @@
+//! Exponential backoff between retries.
+
+/// `initial * 2^attempt`, capped at `max`.
+pub fn exponential(initial: u64, max: u64, attempt: u32) -> u64 {
+    initial
+        .checked_shl(attempt)
+        .filter(|&d| d >> attempt == initial)
+        .map_or(max, |d| d.min(max))
+}
+
+/// Bounded number of retries with exponentially growing delays.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial_ms: u64,
+    max_ms: u64,
+    max_attempts: u32,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub const fn new(initial_ms: u64, max_ms: u64, max_attempts: u32) -> Self {
+        Backoff {
+            initial_ms,
+            max_ms,
+            max_attempts,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next retry, `None` once every attempt has been used.
+    pub fn next_delay_ms(&mut self) -> Option<u64> {
+        if self.attempt >= self.max_attempts {
+            return None;
+        }
+        let delay = exponential(self.initial_ms, self.max_ms, self.attempt);
+        self.attempt += 1;
+        Some(delay)
+    }
+
+    /// Start over after a success.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_max() {
+        let delays: Vec<u64> = (0..5).map(|n| exponential(500, 3000, n)).collect();
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+    }
+
+    #[test]
+    fn saturates_on_overflow() {
+        assert_eq!(exponential(3, u64::MAX, 63), u64::MAX);
+        assert_eq!(exponential(1, 1000, 200), 1000);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(1000, 4000, 3);
+        assert_eq!(backoff.next_delay_ms(), Some(1000));
+        assert_eq!(backoff.next_delay_ms(), Some(2000));
+        assert_eq!(backoff.next_delay_ms(), Some(4000));
+        assert_eq!(backoff.next_delay_ms(), None);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay_ms(), Some(1000));
+    }
+}
@@ -0,0 +1,361 @@
+//! Battery state of charge estimation.
+//!
+//! The station usually runs on a single 18650 Li-ion cell, other chemistries
+//! and packs of several cells are described by a `BatteryModel`. The state of
+//! charge is derived from the resting voltage of a cell with a piecewise
+//! linear discharge curve. An INA219 measures the battery voltage and, across
+//! a shunt resistor, the current flowing in or out of it. The current corrects
+//! the voltage for the drop across the internal resistance, the temperature
+//! for the voltage lost in the cold.
+
+use heapless::Vec;
+
+/// Discharge curve of an 18650 cell as `(volts, percentage)` pairs, ordered
+/// from full to empty.
+pub const SOC_TABLE: &[(f32, f32)] = &[
+    (4.20, 100.0),
+    (4.10, 90.0),
+    (4.00, 80.0),
+    (3.90, 70.0),
+    (3.80, 55.0),
+    (3.70, 35.0),
+    (3.60, 20.0),
+    (3.50, 8.0),
+    (3.40, 0.0),
+];
+
+/// Resting voltage curve of a LiFePO4 cell, flat over most of its range.
+pub const LIFEPO4_TABLE: &[(f32, f32)] = &[
+    (3.40, 100.0),
+    (3.35, 90.0),
+    (3.32, 80.0),
+    (3.30, 70.0),
+    (3.27, 60.0),
+    (3.26, 50.0),
+    (3.25, 40.0),
+    (3.22, 30.0),
+    (3.20, 20.0),
+    (3.00, 10.0),
+    (2.50, 0.0),
+];
+
+/// Resting voltage curve of a NiMH cell.
+pub const NIMH_TABLE: &[(f32, f32)] = &[
+    (1.40, 100.0),
+    (1.35, 90.0),
+    (1.30, 70.0),
+    (1.25, 50.0),
+    (1.20, 30.0),
+    (1.15, 15.0),
+    (1.10, 5.0),
+    (1.00, 0.0),
+];
+
+/// Most points a discharge curve can have.
+pub const MAX_CURVE_POINTS: usize = 12;
+
+/// Temperature the discharge curves are given at.
+const REFERENCE_TEMPERATURE_C: f32 = 25.0;
+
+pub type Curve = Vec<(f32, f32), MAX_CURVE_POINTS>;
+
+/// Convert the voltage (in volts) of an 18650 cell to a state of charge percentage.
+pub fn voltage_to_soc(v: f32) -> f32 {
+    curve_soc(SOC_TABLE, v)
+}
+
+/// Interpolate the state of charge at `v` volts on `curve`.
+fn curve_soc(curve: &[(f32, f32)], v: f32) -> f32 {
+    let (Some(&(v_full, soc_full)), Some(&(v_empty, soc_empty))) = (curve.first(), curve.last())
+    else {
+        return 0.0;
+    };
+    if v >= v_full {
+        return soc_full;
+    }
+    if v <= v_empty {
+        return soc_empty;
+    }
+
+    // Find interval and linearly interpolate
+    for win in curve.windows(2) {
+        let (v_hi, soc_hi) = win[0];
+        let (v_lo, soc_lo) = win[1];
+        if v <= v_hi && v >= v_lo {
+            let t = (v - v_lo) / (v_hi - v_lo);
+            return soc_lo + t * (soc_hi - soc_lo);
+        }
+    }
+
+    soc_empty // fallback
+}
+
+/// Parse a custom discharge curve, `volts:percent` pairs separated by commas
+/// and ordered from full to empty, e.g. `4.2:100,3.9:70,3.4:0`.
+///
+/// Voltages must strictly decrease and percentages, between 0 and 100, must
+/// not increase.
+pub fn parse_curve(s: &str) -> Option<Curve> {
+    let mut curve = Curve::new();
+    for point in s.split(',') {
+        let (v, soc) = point.trim().split_once(':')?;
+        let point: (f32, f32) = (v.trim().parse().ok()?, soc.trim().parse().ok()?);
+        if !point.0.is_finite() || !(0.0..=100.0).contains(&point.1) {
+            return None;
+        }
+        let out_of_order = curve
+            .last()
+            .is_some_and(|&(v_prev, soc_prev)| point.0 >= v_prev || point.1 > soc_prev);
+        if out_of_order {
+            return None;
+        }
+        curve.push(point).ok()?;
+    }
+
+    (curve.len() >= 2).then_some(curve)
+}
+
+/// Battery chemistry, selects the discharge curve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chemistry {
+    LiIon,
+    LiFePo4,
+    NiMh,
+    /// Curve given in the configuration.
+    Custom,
+}
+
+impl Chemistry {
+    /// Configuration name: `li-ion`, `lifepo4`, `nimh` or `custom`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "li-ion" => Some(Chemistry::LiIon),
+            "lifepo4" => Some(Chemistry::LiFePo4),
+            "nimh" => Some(Chemistry::NiMh),
+            "custom" => Some(Chemistry::Custom),
+            _ => None,
+        }
+    }
+
+    /// Built-in curve of one cell, `None` for `Custom`.
+    pub const fn curve(self) -> Option<&'static [(f32, f32)]> {
+        match self {
+            Chemistry::LiIon => Some(SOC_TABLE),
+            Chemistry::LiFePo4 => Some(LIFEPO4_TABLE),
+            Chemistry::NiMh => Some(NIMH_TABLE),
+            Chemistry::Custom => None,
+        }
+    }
+
+    /// Approximate loss of cell voltage per °C below the reference temperature,
+    /// in volts. Custom curves are not compensated.
+    pub const fn temperature_coefficient(self) -> f32 {
+        match self {
+            Chemistry::LiIon => 0.0015,
+            Chemistry::LiFePo4 => 0.0010,
+            Chemistry::NiMh => 0.0020,
+            Chemistry::Custom => 0.0,
+        }
+    }
+}
+
+/// What the station knows of its battery.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatteryModel {
+    chemistry: Chemistry,
+    curve: Curve,
+    cells: u8,
+    internal_mohm: f32,
+}
+
+impl Default for BatteryModel {
+    /// A single Li-ion cell without load compensation.
+    fn default() -> Self {
+        BatteryModel::new(Chemistry::LiIon, None, 1, 0).unwrap()
+    }
+}
+
+impl BatteryModel {
+    /// Battery of `cells` cells in series with an internal resistance of
+    /// `internal_mohm` for the whole pack.
+    ///
+    /// `custom` is the curve of a `Custom` chemistry and is ignored otherwise.
+    /// `None` when a `Custom` chemistry has no curve or there is no cell.
+    pub fn new(
+        chemistry: Chemistry,
+        custom: Option<Curve>,
+        cells: u8,
+        internal_mohm: u16,
+    ) -> Option<Self> {
+        let curve = match chemistry.curve() {
+            Some(table) => Curve::from_slice(table).ok()?,
+            None => custom?,
+        };
+        if cells == 0 {
+            return None;
+        }
+
+        Some(BatteryModel {
+            chemistry,
+            curve,
+            cells,
+            internal_mohm: internal_mohm as f32,
+        })
+    }
+
+    pub fn chemistry(&self) -> Chemistry {
+        self.chemistry
+    }
+
+    /// State of charge of the battery measuring `voltage_mv` while `current_ma`
+    /// flows (positive while charging), at `temperature_c`.
+    ///
+    /// Unknown current or temperature are not compensated.
+    pub fn soc(&self, voltage_mv: f32, current_ma: Option<f32>, temperature_c: Option<f32>) -> f32 {
+        // mA * mΩ = µV
+        let load_drop_mv = current_ma.unwrap_or(0.0) * self.internal_mohm / 1000.0;
+        let mut cell_v = (voltage_mv - load_drop_mv) / 1000.0 / self.cells as f32;
+        if let Some(t) = temperature_c {
+            cell_v += self.chemistry.temperature_coefficient() * (REFERENCE_TEMPERATURE_C - t);
+        }
+
+        curve_soc(&self.curve, cell_v)
+    }
+}
+
+/// Calibration of the INA219 battery monitor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShuntCalibration {
+    pub shunt_mohm: u32,
+    /// Largest current expected through the shunt, sets the resolution.
+    pub max_current_ma: u32,
+    /// Added to the bus voltage to get the battery voltage, which the monitor
+    /// does not see directly.
+    pub bus_offset_mv: i32,
+}
+
+impl ShuntCalibration {
+    /// Resolution of the current register in µA: the range over its 15 bits.
+    pub fn current_lsb_ua(&self) -> i64 {
+        (u64::from(self.max_current_ma) * 1000)
+            .div_ceil(32767)
+            .max(1) as i64
+    }
+
+    pub fn shunt_uohm(&self) -> u32 {
+        self.shunt_mohm.saturating_mul(1000)
+    }
+
+    /// Battery voltage in mV from the bus voltage measured by the monitor.
+    pub fn battery_mv(&self, bus_mv: u16) -> f32 {
+        (i32::from(bus_mv) + self.bus_offset_mv) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_outside_of_the_table() {
+        assert_eq!(voltage_to_soc(4.5), 100.0);
+        assert_eq!(voltage_to_soc(4.2), 100.0);
+        assert_eq!(voltage_to_soc(3.4), 0.0);
+        assert_eq!(voltage_to_soc(2.9), 0.0);
+    }
+
+    #[test]
+    fn matches_table_points() {
+        for &(v, soc) in &SOC_TABLE[1..SOC_TABLE.len() - 1] {
+            assert!((voltage_to_soc(v) - soc).abs() < 0.01, "{v} V");
+        }
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        assert!((voltage_to_soc(3.75) - 45.0).abs() < 0.01);
+        assert!((voltage_to_soc(4.15) - 95.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn parses_custom_curves() {
+        let curve = parse_curve("4.2:100, 3.9:70,3.4:0").unwrap();
+        assert_eq!(curve, [(4.2, 100.0), (3.9, 70.0), (3.4, 0.0)]);
+
+        for invalid in [
+            "",
+            "4.2:100",
+            "4.2:100,4.3:0",
+            "4.2:50,3.4:60",
+            "4.2:101,3.4:0",
+            "4.2-100,3.4:0",
+        ] {
+            assert_eq!(parse_curve(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn custom_curve_is_required() {
+        assert_eq!(BatteryModel::new(Chemistry::Custom, None, 1, 0), None);
+        assert_eq!(BatteryModel::new(Chemistry::LiIon, None, 0, 0), None);
+
+        let curve = parse_curve("12.6:100,10.5:0").unwrap();
+        let model = BatteryModel::new(Chemistry::Custom, Some(curve), 1, 0).unwrap();
+        assert!((model.soc(11_550.0, None, Some(-10.0)) - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn default_model_matches_the_li_ion_table() {
+        let model = BatteryModel::default();
+        for v in [4.3, 4.15, 3.75, 3.45, 3.0] {
+            assert_eq!(model.soc(v * 1000.0, None, None), voltage_to_soc(v));
+        }
+    }
+
+    #[test]
+    fn packs_are_scaled_per_cell() {
+        let model = BatteryModel::new(Chemistry::NiMh, None, 3, 0).unwrap();
+        assert!((model.soc(3750.0, None, None) - 50.0).abs() < 0.01);
+
+        let model = BatteryModel::new(Chemistry::LiFePo4, None, 1, 0).unwrap();
+        assert!((model.soc(3260.0, None, None) - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn compensates_load_and_temperature() {
+        let model = BatteryModel::new(Chemistry::LiIon, None, 1, 100).unwrap();
+        // 3.70 V at rest, 3.68 V under a 200 mA load, 3.72 V while charging with 200 mA
+        assert!((model.soc(3680.0, Some(-200.0), None) - 35.0).abs() < 0.01);
+        assert!((model.soc(3720.0, Some(200.0), None) - 35.0).abs() < 0.01);
+        assert!((model.soc(3700.0, None, Some(25.0)) - 35.0).abs() < 0.01);
+
+        // 20 °C below the reference loses 30 mV
+        assert!((model.soc(3670.0, None, Some(5.0)) - 35.0).abs() < 0.01);
+        assert!(model.soc(3670.0, None, None) < 35.0);
+    }
+
+    #[test]
+    fn chemistry_names() {
+        assert_eq!(Chemistry::parse("lifepo4"), Some(Chemistry::LiFePo4));
+        assert_eq!(Chemistry::parse("custom"), Some(Chemistry::Custom));
+        assert_eq!(Chemistry::parse("lead-acid"), None);
+    }
+
+    #[test]
+    fn shunt_calibration() {
+        let calibration = ShuntCalibration {
+            shunt_mohm: 100,
+            max_current_ma: 500,
+            bus_offset_mv: 160,
+        };
+        assert_eq!(calibration.current_lsb_ua(), 16);
+        assert_eq!(calibration.shunt_uohm(), 100_000);
+        assert_eq!(calibration.battery_mv(3800), 3960.0);
+
+        let tiny = ShuntCalibration {
+            max_current_ma: 0,
+            ..calibration
+        };
+        assert_eq!(tiny.current_lsb_ua(), 1);
+    }
+}
@@ -0,0 +1,262 @@
+//! Charge accounting across wake cycles.
+//!
+//! The battery current is sampled once per window, minutes or hours apart,
+//! while the station is awake with Wi-Fi on. `ChargeCounter` integrates it
+//! between consecutive samples following the `DutyCycle`: the samples, assumed
+//! to change linearly, only stand for the time awake. In deep sleep the
+//! station draws the configured sleep current, unless the panel was charging
+//! the battery, which it then keeps doing at least at the sampled rate since
+//! the load is lighter.
+//!
+//! The charge that went in (from the solar panel) and out (to the station) is
+//! kept separately for the current day. The counter lives in RTC memory,
+//! `Sealed`, so the totals survive deep sleep.
+
+use crate::crc::Crc32;
+use crate::sealed::Seal;
+
+/// Samples further apart are not integrated, the linear model is meaningless
+/// over such a gap.
+pub const MAX_GAP_S: u64 = 8 * 3600;
+
+const SECONDS_PER_HOUR: f32 = 3600.0;
+
+/// How the station spends the time between two samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DutyCycle {
+    /// Time awake per window, when the sampled current flows.
+    pub awake_s: u64,
+    /// Current drawn from the battery in deep sleep, in mA.
+    pub sleep_ma: f32,
+}
+
+impl DutyCycle {
+    /// Battery current in deep sleep, given a current sampled awake.
+    fn asleep(&self, awake_ma: f32) -> f32 {
+        if awake_ma > 0.0 {
+            awake_ma
+        } else {
+            -self.sleep_ma
+        }
+    }
+}
+
+/// Charge that went in and out of the battery during a day.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DailyCharge {
+    pub in_mah: f32,
+    pub out_mah: f32,
+}
+
+impl DailyCharge {
+    pub fn net_mah(&self) -> f32 {
+        self.in_mah - self.out_mah
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct ChargeCounter {
+    day: u32,
+    last_sample_s: u64,
+    last_current_ma: f32,
+    totals: DailyCharge,
+}
+
+impl Default for ChargeCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChargeCounter {
+    pub const fn new() -> Self {
+        ChargeCounter {
+            day: 0,
+            last_sample_s: 0,
+            last_current_ma: 0.0,
+            totals: DailyCharge {
+                in_mah: 0.0,
+                out_mah: 0.0,
+            },
+        }
+    }
+
+    /// Totals of the current day.
+    pub fn totals(&self) -> DailyCharge {
+        self.totals
+    }
+
+    /// Account a current sample taken at `now_s` (seconds on a clock that keeps
+    /// running across deep sleep) during `day`. Positive currents charge the
+    /// battery.
+    ///
+    /// The interval since the previous sample counts towards `day`, totals are
+    /// reset when it changes.
+    pub fn record(
+        &mut self,
+        now_s: u64,
+        day: u32,
+        current_ma: f32,
+        cycle: DutyCycle,
+    ) -> DailyCharge {
+        if !current_ma.is_finite() {
+            return self.totals;
+        }
+        if day != self.day {
+            self.totals = DailyCharge::default();
+        }
+
+        let elapsed = now_s.wrapping_sub(self.last_sample_s);
+        if self.last_sample_s != 0 && now_s > self.last_sample_s && elapsed <= MAX_GAP_S {
+            let awake_s = cycle.awake_s.min(elapsed);
+            let awake = integrate(self.last_current_ma, current_ma, awake_s);
+            let asleep = integrate(
+                cycle.asleep(self.last_current_ma),
+                cycle.asleep(current_ma),
+                elapsed - awake_s,
+            );
+            self.totals.in_mah += awake.0 + asleep.0;
+            self.totals.out_mah += awake.1 + asleep.1;
+        }
+
+        self.day = day;
+        self.last_sample_s = now_s;
+        self.last_current_ma = current_ma;
+        self.totals
+    }
+}
+
+impl Seal for ChargeCounter {
+    const TAG: u8 = 0x5A;
+    const EMPTY: Self = Self::new();
+
+    fn checksum(&self, crc: &mut Crc32) {
+        crc.update(&self.day.to_le_bytes());
+        crc.update(&self.last_sample_s.to_le_bytes());
+        crc.update(&self.last_current_ma.to_le_bytes());
+        crc.update(&self.totals.in_mah.to_le_bytes());
+        crc.update(&self.totals.out_mah.to_le_bytes());
+    }
+}
+
+/// Charge in and out (mAh) over `elapsed_s` for a current going linearly from
+/// `from_ma` to `to_ma`.
+fn integrate(from_ma: f32, to_ma: f32, elapsed_s: u64) -> (f32, f32) {
+    let hours = elapsed_s as f32 / SECONDS_PER_HOUR;
+    // area of the triangle on each side of zero when the sign changes
+    let (positive, negative) = if (from_ma >= 0.0) == (to_ma >= 0.0) {
+        let area = (from_ma + to_ma) / 2.0;
+        (area.max(0.0), (-area).max(0.0))
+    } else {
+        let (pos, neg) = (from_ma.max(to_ma), -from_ma.min(to_ma));
+        let crossing = pos / (pos + neg);
+        (pos * crossing / 2.0, neg * (1.0 - crossing) / 2.0)
+    };
+
+    (positive * hours, negative * hours)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sealed::Sealed;
+
+    // always awake, the samples cover the whole interval
+    const AWAKE: DutyCycle = DutyCycle {
+        awake_s: u64::MAX,
+        sleep_ma: 0.0,
+    };
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn first_sample_only_sets_the_baseline() {
+        let mut counter = ChargeCounter::new();
+        assert_eq!(
+            counter.record(1000, 3, 100.0, AWAKE),
+            DailyCharge::default()
+        );
+    }
+
+    #[test]
+    fn integrates_between_samples() {
+        let mut counter = ChargeCounter::new();
+        counter.record(1000, 0, 100.0, AWAKE);
+        let totals = counter.record(1000 + 3600, 0, 200.0, AWAKE);
+        assert!(close(totals.in_mah, 150.0));
+        assert_eq!(totals.out_mah, 0.0);
+
+        let totals = counter.record(1000 + 2 * 3600, 0, -200.0, AWAKE);
+        assert!(close(totals.in_mah, 200.0));
+        assert!(close(totals.out_mah, 50.0));
+        assert!(close(totals.net_mah(), 150.0));
+        assert_eq!(counter.totals(), totals);
+    }
+
+    #[test]
+    fn sleep_draws_the_sleep_current() {
+        // 35 s awake at 120 mA with Wi-Fi on, then 20 minutes asleep at 1 mA
+        let cycle = DutyCycle {
+            awake_s: 35,
+            sleep_ma: 1.0,
+        };
+        let period = 35 + 1200;
+        let mut counter = ChargeCounter::new();
+        counter.record(1000, 0, -120.0, cycle);
+        for window in 1..=70 {
+            counter.record(1000 + window * period, 0, -120.0, cycle);
+        }
+        let per_window = 120.0 * 35.0 / 3600.0 + 1200.0 / 3600.0;
+        let totals = counter.totals();
+        assert!(close(totals.out_mah, 70.0 * per_window));
+        assert_eq!(totals.in_mah, 0.0);
+
+        // the panel charging awake keeps charging asleep
+        let before = counter.record(1000 + 71 * period, 0, 60.0, cycle);
+        let after = counter.record(1000 + 72 * period, 0, 60.0, cycle);
+        assert!(close(
+            after.in_mah - before.in_mah,
+            60.0 * period as f32 / 3600.0
+        ));
+        assert_eq!(after.out_mah, before.out_mah);
+    }
+
+    #[test]
+    fn resets_every_day() {
+        let mut counter = ChargeCounter::new();
+        counter.record(1000, 0, -30.0, AWAKE);
+        let totals = counter.record(1000 + 1200, 0, -30.0, AWAKE);
+        assert!(close(totals.out_mah, 10.0));
+
+        let totals = counter.record(1000 + 2400, 1, -30.0, AWAKE);
+        assert!(close(totals.out_mah, 10.0));
+    }
+
+    #[test]
+    fn long_gaps_are_skipped() {
+        let mut counter = ChargeCounter::new();
+        counter.record(1000, 0, 100.0, AWAKE);
+        let totals = counter.record(1000 + MAX_GAP_S + 1, 0, 100.0, AWAKE);
+        assert_eq!(totals, DailyCharge::default());
+
+        // the clock going backwards is not integrated either
+        assert_eq!(counter.record(500, 0, 100.0, AWAKE), DailyCharge::default());
+    }
+
+    #[test]
+    fn corrupted_counter_starts_over() {
+        let mut counter = Sealed::<ChargeCounter>::new();
+        assert!(!counter.is_valid());
+        counter.update(|c| c.record(1000, 0, 100.0, AWAKE));
+        counter.update(|c| c.record(4600, 0, 100.0, AWAKE));
+        counter.corrupt(|c| c.totals.in_mah = 1e9);
+        assert!(counter.get().is_none());
+        assert_eq!(
+            counter.update(|c| c.record(8200, 0, 100.0, AWAKE)),
+            DailyCharge::default()
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Wall clock on top of the RTC.
+//!
+//! The RTC counts seconds since power on and keeps running in deep sleep, but
+//! knows nothing about calendar time. An SNTP exchange gives the offset
+//! between both clocks, which is then kept in RTC memory.
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+pub const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Size of an SNTP packet without extensions.
+pub const SNTP_PACKET_SIZE: usize = 48;
+
+/// Standard NTP port.
+pub const NTP_PORT: u16 = 123;
+
+/// Client request: leap indicator 0, version 4, mode 3 (client).
+pub fn sntp_request() -> [u8; SNTP_PACKET_SIZE] {
+    let mut packet = [0u8; SNTP_PACKET_SIZE];
+    packet[0] = (4 << 3) | 3;
+    packet
+}
+
+/// Extract the server transmit time, as Unix seconds, from an SNTP response.
+///
+/// Returns `None` for anything that is not a valid server reply, including
+/// "kiss-o'-death" packets (stratum 0).
+pub fn parse_sntp_response(packet: &[u8]) -> Option<u64> {
+    if packet.len() < SNTP_PACKET_SIZE {
+        return None;
+    }
+    let mode = packet[0] & 0x7;
+    let stratum = packet[1];
+    if mode != 4 || stratum == 0 {
+        return None;
+    }
+
+    let seconds = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]) as u64;
+    seconds.checked_sub(NTP_UNIX_OFFSET)
+}
+
+/// Offset to add to the RTC clock to get Unix time.
+pub fn unix_offset(unix_now: u64, rtc_now: u64) -> u64 {
+    unix_now.saturating_sub(rtc_now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(stratum: u8, ntp_seconds: u32) -> [u8; SNTP_PACKET_SIZE] {
+        let mut packet = [0u8; SNTP_PACKET_SIZE];
+        packet[0] = (4 << 3) | 4;
+        packet[1] = stratum;
+        packet[40..44].copy_from_slice(&ntp_seconds.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn request_header() {
+        let request = sntp_request();
+        assert_eq!(request[0], 0x23);
+        assert!(request[1..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn parses_transmit_time() {
+        // 2025-10-09T08:53:20Z
+        let unix = 1_760_000_000u64;
+        let packet = response(2, (unix + NTP_UNIX_OFFSET) as u32);
+        assert_eq!(parse_sntp_response(&packet), Some(unix));
+    }
+
+    #[test]
+    fn rejects_invalid_replies() {
+        let ntp = (1_760_000_000 + NTP_UNIX_OFFSET) as u32;
+        assert_eq!(parse_sntp_response(&response(0, ntp)), None);
+        assert_eq!(parse_sntp_response(&response(2, ntp)[..40]), None);
+
+        let mut client_mode = response(2, ntp);
+        client_mode[0] = 0x23;
+        assert_eq!(parse_sntp_response(&client_mode), None);
+    }
+
+    #[test]
+    fn offset_between_clocks() {
+        assert_eq!(unix_offset(1_760_000_000, 3600), 1_759_996_400);
+    }
+}
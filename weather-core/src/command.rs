@@ -0,0 +1,189 @@
+//! Downlink commands sent to the station on `<topic>/cmd`.
+//!
+//! A payload holds one or more commands separated by `;` or new lines:
+//!
+//! - `ota`: check for a firmware update right after the window
+//! - `sleep <seconds>`: change the deep sleep interval
+//! - `stay_awake <minutes>`: keep the window open for debugging
+//! - `reset_rain`: clear the rain counter
+//! - `reboot`: restart right after the window
+//! - `calibrate_north`: store the current vane angle as north
+//! - `reset_settings`: drop the stored settings, back to the compiled-in ones
+//!
+//! Every command is acknowledged on `<topic>/cmd/ack`.
+
+use crate::json::write_str;
+use core::fmt::{self, Write};
+use core::ops::RangeInclusive;
+
+/// Accepted deep sleep intervals, one minute to one day.
+pub const SLEEP_RANGE_S: RangeInclusive<u64> = 60..=86_400;
+/// Accepted stay awake durations.
+pub const STAY_AWAKE_RANGE_MIN: RangeInclusive<u32> = 1..=60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    CheckOta,
+    SetSleepInterval(u64),
+    StayAwake(u32),
+    ResetRain,
+    Reboot,
+    CalibrateNorth,
+    ResetSettings,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown,
+    MissingArgument,
+    InvalidArgument,
+    OutOfRange,
+}
+
+impl CommandError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandError::Unknown => "unknown_command",
+            CommandError::MissingArgument => "missing_argument",
+            CommandError::InvalidArgument => "invalid_argument",
+            CommandError::OutOfRange => "out_of_range",
+        }
+    }
+}
+
+impl Command {
+    /// Parse a single command.
+    pub fn parse(text: &str) -> Result<Self, CommandError> {
+        let mut words = text.split_whitespace();
+        let name = words.next().ok_or(CommandError::Unknown)?;
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(CommandError::InvalidArgument);
+        }
+
+        match (name, arg) {
+            ("ota", None) => Ok(Command::CheckOta),
+            ("reset_rain", None) => Ok(Command::ResetRain),
+            ("reboot", None) => Ok(Command::Reboot),
+            ("calibrate_north", None) => Ok(Command::CalibrateNorth),
+            ("reset_settings", None) => Ok(Command::ResetSettings),
+            ("ota" | "reset_rain" | "reboot" | "calibrate_north" | "reset_settings", Some(_)) => {
+                Err(CommandError::InvalidArgument)
+            }
+            ("sleep", Some(arg)) => parse_in(arg, &SLEEP_RANGE_S).map(Command::SetSleepInterval),
+            ("stay_awake", Some(arg)) => {
+                parse_in(arg, &STAY_AWAKE_RANGE_MIN).map(Command::StayAwake)
+            }
+            ("sleep" | "stay_awake", None) => Err(CommandError::MissingArgument),
+            _ => Err(CommandError::Unknown),
+        }
+    }
+}
+
+fn parse_in<T: core::str::FromStr + PartialOrd>(
+    arg: &str,
+    range: &RangeInclusive<T>,
+) -> Result<T, CommandError> {
+    let v = arg.parse().map_err(|_| CommandError::InvalidArgument)?;
+    if range.contains(&v) {
+        Ok(v)
+    } else {
+        Err(CommandError::OutOfRange)
+    }
+}
+
+/// The non-empty, trimmed commands of a payload.
+pub fn split(payload: &str) -> impl Iterator<Item = &str> {
+    payload
+        .split([';', '\n'])
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+}
+
+/// Serialise the acknowledgement of `command`.
+pub fn write_ack<W: Write>(
+    w: &mut W,
+    command: &str,
+    result: Result<Command, CommandError>,
+) -> fmt::Result {
+    w.write_str("{\"command\":")?;
+    write_str(w, command)?;
+    match result {
+        Ok(_) => w.write_str(",\"status\":\"accepted\"}"),
+        Err(e) => {
+            w.write_str(",\"status\":\"rejected\",\"error\":")?;
+            write_str(w, e.as_str())?;
+            w.write_str("}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_command() {
+        assert_eq!(Command::parse("ota"), Ok(Command::CheckOta));
+        assert_eq!(
+            Command::parse("sleep 900"),
+            Ok(Command::SetSleepInterval(900))
+        );
+        assert_eq!(Command::parse("stay_awake 15"), Ok(Command::StayAwake(15)));
+        assert_eq!(Command::parse("reset_rain"), Ok(Command::ResetRain));
+        assert_eq!(Command::parse(" reboot "), Ok(Command::Reboot));
+        assert_eq!(
+            Command::parse("calibrate_north"),
+            Ok(Command::CalibrateNorth)
+        );
+        assert_eq!(Command::parse("reset_settings"), Ok(Command::ResetSettings));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(Command::parse("sleep"), Err(CommandError::MissingArgument));
+        assert_eq!(
+            Command::parse("sleep soon"),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(
+            Command::parse("sleep -5"),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(Command::parse("sleep 10"), Err(CommandError::OutOfRange));
+        assert_eq!(
+            Command::parse("stay_awake 0"),
+            Err(CommandError::OutOfRange)
+        );
+        assert_eq!(
+            Command::parse("reboot now"),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(
+            Command::parse("sleep 900 900"),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(Command::parse("selfdestruct"), Err(CommandError::Unknown));
+    }
+
+    #[test]
+    fn splits_payloads() {
+        let commands: Vec<&str> = split("sleep 900; reset_rain\nota\n\n").collect();
+        assert_eq!(commands, ["sleep 900", "reset_rain", "ota"]);
+        assert_eq!(split("  ").count(), 0);
+    }
+
+    #[test]
+    fn acknowledgements() {
+        let mut out = String::new();
+        write_ack(&mut out, "sleep 900", Command::parse("sleep 900")).unwrap();
+        assert_eq!(out, r#"{"command":"sleep 900","status":"accepted"}"#);
+
+        out.clear();
+        write_ack(&mut out, "fly", Command::parse("fly")).unwrap();
+        assert_eq!(
+            out,
+            r#"{"command":"fly","status":"rejected","error":"unknown_command"}"#
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! CRC-32 (IEEE 802.3) used to validate persisted data.
+
+/// CRC-32 of `data`, as computed by zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// CRC-32 computed over several pieces of data.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0 = data.iter().fold(self.0, |crc, &byte| {
+            (0..8).fold(crc ^ byte as u32, |crc, _| {
+                if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                }
+            })
+        });
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_reference_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn pieces_match_the_whole() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+}
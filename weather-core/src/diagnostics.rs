@@ -0,0 +1,192 @@
+//! Error statistics of the sensors.
+//!
+//! `SensorDiagnostics` counts the outcome of every sensor window since the
+//! last power loss: the windows that produced readings and the failures by
+//! kind. The firmware keeps it `Sealed` in RTC memory and publishes it on
+//! `<topic>/diagnostics` at the end of each window.
+
+use crate::crc::Crc32;
+use crate::json::write_str;
+use crate::sealed::Seal;
+use crate::sensor::{SensorError, SensorId};
+use core::fmt::{self, Write};
+
+const SENSORS: usize = SensorId::ALL.len();
+
+/// Outcomes of the windows of one sensor.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SensorCounts {
+    /// Windows that produced readings.
+    pub ok: u32,
+    pub init_errors: u32,
+    pub read_errors: u32,
+    pub no_data: u32,
+    /// Failed windows since the last successful one.
+    pub consecutive_failures: u32,
+}
+
+impl SensorCounts {
+    const fn new() -> Self {
+        SensorCounts {
+            ok: 0,
+            init_errors: 0,
+            read_errors: 0,
+            no_data: 0,
+            consecutive_failures: 0,
+        }
+    }
+
+    fn record(&mut self, outcome: Result<(), SensorError>) {
+        let counter = match outcome {
+            Ok(()) => {
+                self.consecutive_failures = 0;
+                &mut self.ok
+            }
+            Err(e) => {
+                self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+                match e {
+                    SensorError::Init => &mut self.init_errors,
+                    SensorError::Read => &mut self.read_errors,
+                    SensorError::NoData => &mut self.no_data,
+                }
+            }
+        };
+        *counter = counter.saturating_add(1);
+    }
+
+    /// Failed windows, whatever the error.
+    pub fn failures(&self) -> u32 {
+        self.init_errors
+            .saturating_add(self.read_errors)
+            .saturating_add(self.no_data)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SensorDiagnostics {
+    counts: [SensorCounts; SENSORS],
+}
+
+impl Default for SensorDiagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SensorDiagnostics {
+    pub const fn new() -> Self {
+        SensorDiagnostics {
+            counts: [SensorCounts::new(); SENSORS],
+        }
+    }
+
+    /// Count the outcome of a window of `sensor`.
+    pub fn record(&mut self, sensor: SensorId, outcome: Result<(), SensorError>) {
+        self.counts[sensor as usize].record(outcome);
+    }
+
+    pub fn counts(&self, sensor: SensorId) -> SensorCounts {
+        self.counts[sensor as usize]
+    }
+
+    /// Serialise the statistics as a JSON object keyed by sensor.
+    ///
+    /// ```json
+    /// {"dht22":{"ok":41,"init_errors":0,"read_errors":2,"no_data":0,"consecutive_failures":0}}
+    /// ```
+    pub fn write_json<W: Write>(&self, w: &mut W) -> fmt::Result {
+        w.write_char('{')?;
+        for (i, &sensor) in SensorId::ALL.iter().enumerate() {
+            if i > 0 {
+                w.write_char(',')?;
+            }
+            let c = self.counts(sensor);
+            write_str(w, sensor.name())?;
+            write!(
+                w,
+                ":{{\"ok\":{},\"init_errors\":{},\"read_errors\":{},\"no_data\":{},\"consecutive_failures\":{}}}",
+                c.ok, c.init_errors, c.read_errors, c.no_data, c.consecutive_failures
+            )?;
+        }
+        w.write_char('}')
+    }
+}
+
+impl Seal for SensorDiagnostics {
+    const TAG: u8 = 0x3C;
+    const EMPTY: Self = Self::new();
+
+    fn checksum(&self, crc: &mut Crc32) {
+        for c in &self.counts {
+            for word in [
+                c.ok,
+                c.init_errors,
+                c.read_errors,
+                c.no_data,
+                c.consecutive_failures,
+            ] {
+                crc.update(&word.to_le_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sealed::Sealed;
+
+    #[test]
+    fn counts_outcomes_per_sensor() {
+        let mut diagnostics = SensorDiagnostics::new();
+        diagnostics.record(SensorId::WindVane, Ok(()));
+        diagnostics.record(SensorId::WindVane, Err(SensorError::NoData));
+        diagnostics.record(SensorId::WindVane, Err(SensorError::Read));
+        diagnostics.record(SensorId::Dht22, Err(SensorError::Init));
+
+        let vane = diagnostics.counts(SensorId::WindVane);
+        assert_eq!(vane.ok, 1);
+        assert_eq!(vane.no_data, 1);
+        assert_eq!(vane.read_errors, 1);
+        assert_eq!(vane.consecutive_failures, 2);
+        assert_eq!(vane.failures(), 2);
+        assert_eq!(diagnostics.counts(SensorId::Dht22).init_errors, 1);
+        assert_eq!(
+            diagnostics.counts(SensorId::Ina219),
+            SensorCounts::default()
+        );
+
+        diagnostics.record(SensorId::WindVane, Ok(()));
+        assert_eq!(
+            diagnostics.counts(SensorId::WindVane).consecutive_failures,
+            0
+        );
+    }
+
+    #[test]
+    fn corrupted_statistics_restart_from_zero() {
+        let mut diagnostics = Sealed::<SensorDiagnostics>::new();
+        diagnostics.update(|d| d.record(SensorId::Anemometer, Ok(())));
+        diagnostics.corrupt(|d| d.counts[0].ok = 7);
+        assert!(diagnostics.get().is_none());
+
+        diagnostics.update(|d| d.record(SensorId::Anemometer, Err(SensorError::NoData)));
+        let anemometer = diagnostics.get().unwrap().counts(SensorId::Anemometer);
+        assert_eq!((anemometer.ok, anemometer.no_data), (0, 1));
+    }
+
+    #[test]
+    fn serialises_every_sensor() {
+        let mut diagnostics = SensorDiagnostics::new();
+        diagnostics.record(SensorId::Dht22, Err(SensorError::Read));
+        let mut out = String::new();
+        diagnostics.write_json(&mut out).unwrap();
+        assert!(out.starts_with(
+            r#"{"dht22":{"ok":0,"init_errors":0,"read_errors":1,"no_data":0,"consecutive_failures":1},"anemometer":{"ok":0,"#
+        ));
+        assert!(out.contains(r#""wind_vane":{"#));
+        assert!(out.ends_with(r#""ina219":{"ok":0,"init_errors":0,"read_errors":0,"no_data":0,"consecutive_failures":0}}"#));
+    }
+}
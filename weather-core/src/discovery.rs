@@ -0,0 +1,281 @@
+//! Home Assistant MQTT discovery.
+//!
+//! Home Assistant picks up sensors from retained config messages published
+//! on `<prefix>/sensor/<device>/<quantity>/config`. This module renders those
+//! topics and payloads for every quantity the station measures.
+
+use crate::json::write_str;
+use crate::reading::Quantity;
+use crate::status::{OFFLINE, ONLINE};
+use core::fmt::{self, Write};
+
+/// Quantities announced to Home Assistant.
+pub const QUANTITIES: &[Quantity] = &[
+    Quantity::Temperature,
+    Quantity::Humidity,
+    Quantity::WindSpeed,
+    Quantity::WindAngle,
+    Quantity::WindDirection,
+    Quantity::Rain,
+    Quantity::BatteryVoltage,
+    Quantity::BatteryPercentage,
+    Quantity::BatteryCurrent,
+    Quantity::BatteryPower,
+    Quantity::ShuntVoltage,
+    Quantity::ChargeIn,
+    Quantity::ChargeOut,
+    Quantity::RainRate,
+    Quantity::RainLastHour,
+    Quantity::RainLast24h,
+    Quantity::RainToday,
+    Quantity::RainLastTip,
+    Quantity::RainSensor,
+    Quantity::WindGust,
+    Quantity::WindLull,
+    Quantity::WindSpeedStddev,
+    Quantity::WindSpeedAverage,
+    Quantity::WindDirectionStddev,
+    Quantity::VaneMagnet,
+    Quantity::VaneAgc,
+];
+
+/// Identity of the station as seen by Home Assistant.
+#[derive(Clone, Copy, Debug)]
+pub struct Device<'a> {
+    /// Discovery prefix, `homeassistant` unless changed in Home Assistant.
+    pub prefix: &'a str,
+    /// Unique identifier, only `[a-zA-Z0-9_-]`.
+    pub id: &'a str,
+    pub name: &'a str,
+    /// Base topic the station publishes its readings under.
+    pub base_topic: &'a str,
+    pub firmware_version: &'a str,
+    /// Seconds after which Home Assistant marks a value as unavailable when no
+    /// update was received. 0 disables expiry.
+    pub expire_after_s: u64,
+}
+
+/// Human readable name of the entity.
+pub const fn label(quantity: Quantity) -> &'static str {
+    match quantity {
+        Quantity::Temperature => "Temperature",
+        Quantity::Humidity => "Humidity",
+        Quantity::WindSpeed => "Wind speed",
+        Quantity::WindAngle => "Wind angle",
+        Quantity::WindDirection => "Wind direction",
+        Quantity::Rain => "Rain",
+        Quantity::BatteryVoltage => "Battery voltage",
+        Quantity::BatteryPercentage => "Battery",
+        Quantity::BatteryCurrent => "Battery current",
+        Quantity::BatteryPower => "Battery power",
+        Quantity::ShuntVoltage => "Shunt voltage",
+        Quantity::ChargeIn => "Charge in today",
+        Quantity::ChargeOut => "Charge out today",
+        Quantity::RainRate => "Rain rate",
+        Quantity::RainLastHour => "Rain last hour",
+        Quantity::RainLast24h => "Rain last 24 h",
+        Quantity::RainToday => "Rain today",
+        Quantity::RainLastTip => "Last rain",
+        Quantity::RainSensor => "Rain sensor",
+        Quantity::WindGust => "Wind gust",
+        Quantity::WindLull => "Wind lull",
+        Quantity::WindSpeedStddev => "Wind speed deviation",
+        Quantity::WindSpeedAverage => "Wind speed 10 min",
+        Quantity::WindDirectionStddev => "Wind direction deviation",
+        Quantity::VaneMagnet => "Vane magnet",
+        Quantity::VaneAgc => "Vane gain",
+    }
+}
+
+/// Home Assistant sensor device class, if one applies.
+pub const fn device_class(quantity: Quantity) -> Option<&'static str> {
+    match quantity {
+        Quantity::Temperature => Some("temperature"),
+        Quantity::Humidity => Some("humidity"),
+        Quantity::WindSpeed
+        | Quantity::WindGust
+        | Quantity::WindLull
+        | Quantity::WindSpeedStddev
+        | Quantity::WindSpeedAverage => Some("wind_speed"),
+        Quantity::Rain => Some("precipitation"),
+        Quantity::BatteryVoltage => Some("voltage"),
+        Quantity::BatteryPercentage => Some("battery"),
+        Quantity::BatteryCurrent => Some("current"),
+        Quantity::BatteryPower => Some("power"),
+        Quantity::ShuntVoltage => Some("voltage"),
+        Quantity::RainRate => Some("precipitation_intensity"),
+        Quantity::RainLastHour | Quantity::RainLast24h | Quantity::RainToday => {
+            Some("precipitation")
+        }
+        Quantity::RainLastTip => Some("timestamp"),
+        Quantity::WindAngle
+        | Quantity::WindDirection
+        | Quantity::WindDirectionStddev
+        | Quantity::RainSensor
+        | Quantity::VaneMagnet
+        | Quantity::VaneAgc => None,
+        Quantity::ChargeIn | Quantity::ChargeOut => None,
+    }
+}
+
+/// Home Assistant state class, `None` for non numeric entities and timestamps.
+pub const fn state_class(quantity: Quantity) -> Option<&'static str> {
+    match quantity {
+        Quantity::WindDirection
+        | Quantity::RainSensor
+        | Quantity::VaneMagnet
+        | Quantity::RainLastTip => None,
+        // daily totals, reset at midnight
+        Quantity::ChargeIn | Quantity::ChargeOut | Quantity::RainToday => Some("total_increasing"),
+        _ => Some("measurement"),
+    }
+}
+
+/// Template turning the published value into the entity state, if needed.
+pub const fn value_template(quantity: Quantity) -> Option<&'static str> {
+    match quantity {
+        // published as a Unix timestamp, Home Assistant expects a datetime
+        Quantity::RainLastTip => Some("{{ as_datetime(value) }}"),
+        _ => None,
+    }
+}
+
+/// Availability topic shared by all entities of the device.
+pub fn write_availability_topic<W: Write>(w: &mut W, device: &Device) -> fmt::Result {
+    write!(w, "{}/status", device.base_topic)
+}
+
+/// Discovery topic of `quantity`.
+pub fn write_topic<W: Write>(w: &mut W, device: &Device, quantity: Quantity) -> fmt::Result {
+    write!(
+        w,
+        "{}/sensor/{}/{}/config",
+        device.prefix,
+        device.id,
+        quantity.name()
+    )
+}
+
+/// Discovery config payload of `quantity`.
+pub fn write_payload<W: Write>(w: &mut W, device: &Device, quantity: Quantity) -> fmt::Result {
+    w.write_str("{\"name\":")?;
+    write_str(w, label(quantity))?;
+    write!(
+        w,
+        ",\"unique_id\":\"{}_{}\",\"state_topic\":\"{}/{}\"",
+        device.id,
+        quantity.name(),
+        device.base_topic,
+        quantity.topic()
+    )?;
+    if let Some(class) = device_class(quantity) {
+        write!(w, ",\"device_class\":\"{class}\"")?;
+    }
+    if let Some(template) = value_template(quantity) {
+        w.write_str(",\"value_template\":")?;
+        write_str(w, template)?;
+    }
+    let unit = quantity.unit().symbol();
+    if !unit.is_empty() {
+        w.write_str(",\"unit_of_measurement\":")?;
+        write_str(w, unit)?;
+    }
+    if let Some(class) = state_class(quantity) {
+        write!(w, ",\"state_class\":\"{class}\"")?;
+    }
+    if device.expire_after_s > 0 {
+        write!(w, ",\"expire_after\":{}", device.expire_after_s)?;
+    }
+    w.write_str(",\"availability_topic\":\"")?;
+    write_availability_topic(w, device)?;
+    write!(
+        w,
+        "\",\"payload_available\":\"{ONLINE}\",\"payload_not_available\":\"{OFFLINE}\""
+    )?;
+    write!(
+        w,
+        ",\"device\":{{\"identifiers\":[\"{}\"],\"name\":",
+        device.id
+    )?;
+    write_str(w, device.name)?;
+    w.write_str(",\"sw_version\":")?;
+    write_str(w, device.firmware_version)?;
+    w.write_str("}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> Device<'static> {
+        Device {
+            prefix: "homeassistant",
+            id: "ws_garden",
+            name: "Garden station",
+            base_topic: "weather_station",
+            firmware_version: "0.1.0",
+            expire_after_s: 2500,
+        }
+    }
+
+    #[test]
+    fn topics() {
+        let mut out = String::new();
+        write_topic(&mut out, &device(), Quantity::WindSpeed).unwrap();
+        assert_eq!(out, "homeassistant/sensor/ws_garden/wind_speed/config");
+
+        out.clear();
+        write_availability_topic(&mut out, &device()).unwrap();
+        assert_eq!(out, "weather_station/status");
+    }
+
+    #[test]
+    fn numeric_payload() {
+        let mut out = String::new();
+        write_payload(&mut out, &device(), Quantity::Temperature).unwrap();
+        assert_eq!(
+            out,
+            concat!(
+                r#"{"name":"Temperature","unique_id":"ws_garden_temperature","#,
+                r#""state_topic":"weather_station/temperature","device_class":"temperature","#,
+                r#""unit_of_measurement":"°C","state_class":"measurement","expire_after":2500,"#,
+                r#""availability_topic":"weather_station/status","payload_available":"online","#,
+                r#""payload_not_available":"offline","device":{"identifiers":["ws_garden"],"#,
+                r#""name":"Garden station","sw_version":"0.1.0"}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn text_payload_has_no_unit_nor_state_class() {
+        let mut out = String::new();
+        let device = Device {
+            expire_after_s: 0,
+            ..device()
+        };
+        write_payload(&mut out, &device, Quantity::WindDirection).unwrap();
+        assert!(out.contains(r#""state_topic":"weather_station/anemo/wind_direction""#));
+        assert!(!out.contains("unit_of_measurement"));
+        assert!(!out.contains("state_class"));
+        assert!(!out.contains("device_class"));
+        assert!(!out.contains("expire_after"));
+    }
+
+    #[test]
+    fn timestamp_payload_converts_the_value() {
+        let mut out = String::new();
+        write_payload(&mut out, &device(), Quantity::RainLastTip).unwrap();
+        assert!(out.contains(
+            r#""device_class":"timestamp","value_template":"{{ as_datetime(value) }}","#
+        ));
+        assert!(!out.contains("unit_of_measurement"));
+        assert!(!out.contains("state_class"));
+    }
+
+    #[test]
+    fn every_quantity_has_a_label() {
+        for &q in QUANTITIES {
+            assert!(!label(q).is_empty());
+        }
+    }
+}
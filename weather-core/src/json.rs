@@ -0,0 +1,52 @@
+//! Minimal allocation free JSON helpers on top of `core::fmt::Write`.
+
+use crate::reading::Value;
+use core::fmt::{self, Write};
+
+/// Write `s` as a quoted and escaped JSON string.
+pub fn write_str<W: Write>(w: &mut W, s: &str) -> fmt::Result {
+    w.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => w.write_str("\\\"")?,
+            '\\' => w.write_str("\\\\")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => w.write_char(c)?,
+        }
+    }
+    w.write_char('"')
+}
+
+/// Write a reading value, numbers as JSON numbers and labels as strings.
+pub fn write_value<W: Write>(w: &mut W, value: &Value) -> fmt::Result {
+    match value {
+        Value::Number(v) if v.is_finite() => write!(w, "{v}"),
+        // NaN and infinities are not valid JSON numbers
+        Value::Number(_) | Value::Missing => w.write_str("null"),
+        Value::Label(s) => write_str(w, s),
+        Value::Timestamp(t) => write!(w, "{t}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        let mut out = String::new();
+        write_str(&mut out, "a\"b\\c\n").unwrap();
+        assert_eq!(out, r#""a\"b\\c\u000a""#);
+    }
+
+    #[test]
+    fn values() {
+        let mut out = String::new();
+        write_value(&mut out, &Value::Number(1.5)).unwrap();
+        write_value(&mut out, &Value::Number(f32::INFINITY)).unwrap();
+        write_value(&mut out, &Value::Label("N")).unwrap();
+        write_value(&mut out, &Value::Missing).unwrap();
+        write_value(&mut out, &Value::Timestamp(1_760_000_123)).unwrap();
+        assert_eq!(out, r#"1.5null"N"null1760000123"#);
+    }
+}
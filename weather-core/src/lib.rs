@@ -0,0 +1,33 @@
+//! Hardware independent core of the weather station firmware.
+//!
+//! Everything in this crate is plain `no_std` logic: unit conversions,
+//! aggregation of raw sensor values and the wakeup scheduling rules. The
+//! ESP32 firmware only adapts the hardware to the traits defined here, which
+//! keeps this crate buildable and testable on the host with `cargo test`.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod anemometer;
+pub mod backlog;
+pub mod backoff;
+pub mod battery;
+pub mod charge;
+pub mod clock;
+pub mod command;
+pub mod crc;
+pub mod diagnostics;
+pub mod discovery;
+pub mod json;
+pub mod ota;
+pub mod power;
+pub mod provision;
+pub mod rain;
+pub mod reading;
+pub mod rtc;
+pub mod sealed;
+pub mod sensor;
+pub mod settings;
+pub mod state;
+pub mod status;
+pub mod wifi;
+pub mod wind;
@@ -0,0 +1,40 @@
+//! OTA helpers independent of the HTTP client.
+
+/// Name of the response header carrying the CRC32 of the firmware image.
+pub const CRC_HEADER: &str = "target_crc";
+
+/// Extract the expected image CRC from the HTTP response headers.
+///
+/// Returns 0 when the header is missing or is not a valid decimal number.
+pub fn get_crc<'a>(headers: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> u32 {
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case(CRC_HEADER) {
+            let s = core::str::from_utf8(value).unwrap_or("0");
+            if let Ok(crc) = s.trim().parse::<u32>() {
+                return crc;
+            }
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_crc_header() {
+        let headers = [
+            ("Content-Length", b"1024".as_slice()),
+            ("Target_CRC", b" 3735928559 ".as_slice()),
+        ];
+        assert_eq!(get_crc(headers), 3735928559);
+    }
+
+    #[test]
+    fn missing_or_invalid_crc_is_zero() {
+        assert_eq!(get_crc([("Content-Length", b"1024".as_slice())]), 0);
+        assert_eq!(get_crc([("target_crc", b"abc".as_slice())]), 0);
+        assert_eq!(get_crc([("target_crc", [0xff, 0xfe].as_slice())]), 0);
+    }
+}
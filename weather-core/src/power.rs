@@ -0,0 +1,269 @@
+//! Power policy.
+//!
+//! At the end of every window the station picks the sleep interval before the
+//! next one from the battery measured during the window. A low state of
+//! charge stretches the interval unless the solar panel is charging the
+//! battery. Below a critical voltage the station enters survival mode: it
+//! sleeps even longer and skips Wi-Fi, the most expensive part of a wake
+//! cycle, until the battery has recovered.
+
+use core::fmt::{self, Write};
+
+/// Voltage above `survival_mv` needed to leave survival mode, so a battery
+/// hovering around the threshold does not toggle the radio every wake.
+pub const SURVIVAL_HYSTERESIS_MV: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerMode {
+    /// Regular sleep interval.
+    Normal,
+    /// Low state of charge but the solar panel is charging, regular interval.
+    Charging,
+    /// Low state of charge, longer interval.
+    Saver,
+    /// Nearly empty battery, longest interval and no Wi-Fi.
+    Survival,
+}
+
+impl PowerMode {
+    const ALL: &[PowerMode] = &[
+        PowerMode::Normal,
+        PowerMode::Charging,
+        PowerMode::Saver,
+        PowerMode::Survival,
+    ];
+
+    /// Inverse of `mode as u8`.
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|&m| m as u8 == v)
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            PowerMode::Normal => "normal",
+            PowerMode::Charging => "charging",
+            PowerMode::Saver => "saver",
+            PowerMode::Survival => "survival",
+        }
+    }
+}
+
+/// Battery measurements the policy is based on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatteryState {
+    pub voltage_mv: f32,
+    /// State of charge in percent.
+    pub soc: f32,
+    /// Battery current, positive while charging, `None` when it could not be read.
+    pub current_ma: Option<f32>,
+}
+
+/// Thresholds of the policy, from the configuration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerThresholds {
+    /// State of charge under which the station saves power.
+    pub low_soc: f32,
+    pub low_soc_sleep_s: u64,
+    /// Voltage under which the station enters survival mode.
+    pub survival_mv: f32,
+    pub survival_sleep_s: u64,
+    /// Charge current above which the battery counts as charging.
+    pub charging_ma: f32,
+}
+
+/// Mode and sleep interval chosen for the next wake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerPolicy {
+    pub mode: PowerMode,
+    pub sleep_s: u64,
+}
+
+impl PowerThresholds {
+    /// Policy for the next wake.
+    ///
+    /// `interval_s` is the regular sleep interval and `previous` the mode of the
+    /// current wake. Without a battery measurement the current mode is kept,
+    /// except for survival: a broken monitor must not keep the radio off for
+    /// good, saver mode is used instead.
+    pub fn policy(
+        &self,
+        battery: Option<BatteryState>,
+        previous: PowerMode,
+        interval_s: u64,
+    ) -> PowerPolicy {
+        let mode = match battery {
+            Some(battery) => self.mode(&battery, previous),
+            None if previous == PowerMode::Survival => PowerMode::Saver,
+            None => previous,
+        };
+        let sleep_s = match mode {
+            PowerMode::Normal | PowerMode::Charging => interval_s,
+            PowerMode::Saver => interval_s.max(self.low_soc_sleep_s),
+            PowerMode::Survival => interval_s.max(self.survival_sleep_s),
+        };
+
+        PowerPolicy { mode, sleep_s }
+    }
+
+    fn mode(&self, battery: &BatteryState, previous: PowerMode) -> PowerMode {
+        let survival_mv = match previous {
+            PowerMode::Survival => self.survival_mv + SURVIVAL_HYSTERESIS_MV,
+            _ => self.survival_mv,
+        };
+        let charging = battery.current_ma.is_some_and(|ma| ma >= self.charging_ma);
+
+        if battery.voltage_mv < survival_mv {
+            PowerMode::Survival
+        } else if battery.soc >= self.low_soc {
+            PowerMode::Normal
+        } else if charging {
+            PowerMode::Charging
+        } else {
+            PowerMode::Saver
+        }
+    }
+}
+
+/// JSON document published on `<topic>/power`.
+pub fn write_json<W: Write>(
+    w: &mut W,
+    policy: &PowerPolicy,
+    battery: Option<&BatteryState>,
+) -> fmt::Result {
+    write!(
+        w,
+        "{{\"mode\":\"{}\",\"sleep_s\":{}",
+        policy.mode.name(),
+        policy.sleep_s
+    )?;
+    if let Some(battery) = battery {
+        write!(
+            w,
+            ",\"voltage_mv\":{:.0},\"soc\":{:.1}",
+            battery.voltage_mv, battery.soc
+        )?;
+        if let Some(ma) = battery.current_ma {
+            write!(w, ",\"current_ma\":{ma:.1}")?;
+        }
+    }
+    w.write_char('}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: PowerThresholds = PowerThresholds {
+        low_soc: 30.0,
+        low_soc_sleep_s: 3600,
+        survival_mv: 3450.0,
+        survival_sleep_s: 14400,
+        charging_ma: 50.0,
+    };
+
+    fn battery(voltage_mv: f32, soc: f32, current_ma: Option<f32>) -> Option<BatteryState> {
+        Some(BatteryState {
+            voltage_mv,
+            soc,
+            current_ma,
+        })
+    }
+
+    fn policy(battery: Option<BatteryState>, previous: PowerMode) -> PowerPolicy {
+        THRESHOLDS.policy(battery, previous, 1200)
+    }
+
+    #[test]
+    fn healthy_battery_keeps_the_interval() {
+        let p = policy(battery(3950.0, 75.0, None), PowerMode::Normal);
+        assert_eq!(
+            p,
+            PowerPolicy {
+                mode: PowerMode::Normal,
+                sleep_s: 1200
+            }
+        );
+    }
+
+    #[test]
+    fn low_battery_sleeps_longer_unless_charging() {
+        let p = policy(battery(3650.0, 25.0, Some(-12.0)), PowerMode::Normal);
+        assert_eq!(
+            p,
+            PowerPolicy {
+                mode: PowerMode::Saver,
+                sleep_s: 3600
+            }
+        );
+
+        let p = policy(battery(3650.0, 25.0, Some(120.0)), PowerMode::Saver);
+        assert_eq!(
+            p,
+            PowerPolicy {
+                mode: PowerMode::Charging,
+                sleep_s: 1200
+            }
+        );
+    }
+
+    #[test]
+    fn longer_configured_interval_wins() {
+        let p = THRESHOLDS.policy(battery(3650.0, 25.0, None), PowerMode::Normal, 7200);
+        assert_eq!(p.sleep_s, 7200);
+    }
+
+    #[test]
+    fn survival_has_hysteresis() {
+        let p = policy(battery(3400.0, 0.0, Some(200.0)), PowerMode::Charging);
+        assert_eq!(
+            p,
+            PowerPolicy {
+                mode: PowerMode::Survival,
+                sleep_s: 14400
+            }
+        );
+
+        // recovered above the threshold, but not by the hysteresis margin
+        let p = policy(battery(3500.0, 8.0, None), PowerMode::Survival);
+        assert_eq!(p.mode, PowerMode::Survival);
+        let p = policy(battery(3500.0, 8.0, None), PowerMode::Saver);
+        assert_eq!(p.mode, PowerMode::Saver);
+
+        let p = policy(battery(3560.0, 14.0, None), PowerMode::Survival);
+        assert_eq!(p.mode, PowerMode::Saver);
+    }
+
+    #[test]
+    fn missing_measurement_keeps_the_mode_but_survival() {
+        assert_eq!(policy(None, PowerMode::Saver).mode, PowerMode::Saver);
+        assert_eq!(policy(None, PowerMode::Normal).sleep_s, 1200);
+        assert_eq!(policy(None, PowerMode::Survival).mode, PowerMode::Saver);
+    }
+
+    #[test]
+    fn modes_round_trip() {
+        for &mode in PowerMode::ALL {
+            assert_eq!(PowerMode::from_u8(mode as u8), Some(mode));
+        }
+        assert_eq!(PowerMode::from_u8(4), None);
+    }
+
+    #[test]
+    fn json_document() {
+        let mut out = String::new();
+        let p = PowerPolicy {
+            mode: PowerMode::Saver,
+            sleep_s: 3600,
+        };
+        write_json(&mut out, &p, battery(3650.0, 25.0, Some(-12.5)).as_ref()).unwrap();
+        assert_eq!(
+            out,
+            r#"{"mode":"saver","sleep_s":3600,"voltage_mv":3650,"soc":25.0,"current_ma":-12.5}"#
+        );
+
+        out.clear();
+        write_json(&mut out, &p, None).unwrap();
+        assert_eq!(out, r#"{"mode":"saver","sleep_s":3600}"#);
+    }
+}
@@ -0,0 +1,450 @@
+//! Captive portal used to provision the station.
+//!
+//! The firmware serves a single form over HTTP while running as an access
+//! point and answers every DNS query with its own address, so phones and
+//! laptops open the form on their own. This module holds the protocol side:
+//! request parsing, the pages, applying the submitted form to the settings
+//! and building DNS answers.
+
+use crate::crc::crc32;
+use crate::settings::Settings;
+use core::fmt::{self, Write};
+use core::net::Ipv4Addr;
+use core::str::FromStr;
+use heapless::String;
+
+/// Path the form is posted to.
+pub const SAVE_PATH: &str = "/save";
+
+/// Passphrase lengths accepted by WPA2.
+pub const PASSWORD_LEN: core::ops::RangeInclusive<usize> = 8..=63;
+
+/// Whether `pass` can protect the portal with WPA2.
+pub fn is_valid_password(pass: &str) -> bool {
+    PASSWORD_LEN.contains(&pass.len())
+}
+
+/// WPA2 passphrase of the portal: `configured` when valid, otherwise one
+/// derived from the base MAC address of the board.
+///
+/// The derived passphrase only keeps passers-by out, anyone who reads the MAC
+/// can compute it. That is why the portal only opens on its own for a station
+/// that was never configured, unless `configured` is valid.
+pub fn portal_password(configured: &str, mac: [u8; 6]) -> String<64> {
+    let mut pass = String::new();
+    if is_valid_password(configured) {
+        let _ = pass.push_str(configured);
+    } else {
+        let mut seed = [0u8; 6 + 8];
+        seed[..6].copy_from_slice(&mac);
+        seed[6..].copy_from_slice(b"ws-setup");
+        let _ = write!(pass, "{:08x}", crc32(&seed));
+    }
+    pass
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    /// More bytes are needed to complete the request
+    Incomplete,
+    Invalid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub body: &'a str,
+}
+
+/// Parse an HTTP/1.x request held in `buf`.
+pub fn parse_request(buf: &[u8]) -> Result<Request<'_>, RequestError> {
+    let header_end = buf
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(RequestError::Incomplete)?;
+    let head = core::str::from_utf8(&buf[..header_end]).map_err(|_| RequestError::Invalid)?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err(RequestError::Invalid);
+    };
+
+    let mut content_length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().map_err(|_| RequestError::Invalid)?;
+        }
+    }
+
+    // a client controls the length, it must not overflow
+    let body_end = (header_end + 4)
+        .checked_add(content_length)
+        .ok_or(RequestError::Invalid)?;
+    let body = buf
+        .get(header_end + 4..body_end)
+        .ok_or(RequestError::Incomplete)?;
+    let body = core::str::from_utf8(body).map_err(|_| RequestError::Invalid)?;
+
+    Ok(Request { method, path, body })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormError {
+    MissingSsid,
+    /// A field is not valid URL encoding or too long, with its name
+    InvalidField(&'static str),
+}
+
+impl FormError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FormError::MissingSsid => "The network name is required",
+            FormError::InvalidField("ssid") => "The network name is too long",
+            FormError::InvalidField("wifi_pass") => "The password is too long",
+            FormError::InvalidField("broker") => "The broker address is too long",
+            FormError::InvalidField(_) => "The topic is too long",
+        }
+    }
+}
+
+/// Value of `key` in an `application/x-www-form-urlencoded` body, decoded.
+///
+/// `Ok(None)` when the key is missing or empty.
+fn form_value<const N: usize>(
+    body: &str,
+    key: &'static str,
+) -> Result<Option<String<N>>, FormError> {
+    let Some(raw) = body
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(k, v)| (k == key).then_some(v))
+    else {
+        return Ok(None);
+    };
+
+    let mut bytes = heapless::Vec::<u8, N>::new();
+    let mut rest = raw.as_bytes();
+    while let [b, tail @ ..] = rest {
+        let (byte, tail) = match (b, tail) {
+            (b'+', _) => (b' ', tail),
+            (b'%', [hi, lo, tail @ ..]) => {
+                let hex = core::str::from_utf8(&[*hi, *lo])
+                    .ok()
+                    .and_then(|s| u8::from_str_radix(s, 16).ok())
+                    .ok_or(FormError::InvalidField(key))?;
+                (hex, tail)
+            }
+            (b'%', _) => return Err(FormError::InvalidField(key)),
+            (b, _) => (*b, tail),
+        };
+        bytes.push(byte).map_err(|_| FormError::InvalidField(key))?;
+        rest = tail;
+    }
+
+    let value = String::from_utf8(bytes).map_err(|_| FormError::InvalidField(key))?;
+    Ok((!value.is_empty()).then_some(value))
+}
+
+/// Apply a submitted form to `settings`.
+///
+/// `ssid` is required. An empty password selects an open network, an empty
+/// broker or topic keeps the current one. The broker may be an IPv4 address
+/// or a host name resolved through DNS.
+pub fn apply_form(settings: &mut Settings, body: &str) -> Result<(), FormError> {
+    let ssid = form_value(body, "ssid")?.ok_or(FormError::MissingSsid)?;
+    let wifi_pass = form_value(body, "wifi_pass")?.unwrap_or_default();
+    let broker: Option<String<64>> = form_value(body, "broker")?;
+    let topic = form_value(body, "topic")?;
+
+    settings.ssid = ssid;
+    settings.wifi_pass = wifi_pass;
+    if let Some(broker) = broker {
+        if Ipv4Addr::from_str(&broker).is_ok() {
+            settings.broker_ip =
+                String::try_from(broker.as_str()).map_err(|_| FormError::InvalidField("broker"))?;
+            settings.broker_url.clear();
+        } else {
+            settings.broker_url = broker;
+        }
+    }
+    if let Some(topic) = topic {
+        settings.topic = topic;
+    }
+
+    Ok(())
+}
+
+/// Write `s` with the HTML special characters escaped.
+fn write_escaped<W: Write>(w: &mut W, s: &str) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '&' => w.write_str("&amp;")?,
+            '<' => w.write_str("&lt;")?,
+            '>' => w.write_str("&gt;")?,
+            '"' => w.write_str("&quot;")?,
+            '\'' => w.write_str("&#39;")?,
+            c => w.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+const PAGE_HEAD: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+<title>Weather station setup</title></head><body>";
+
+/// Write the settings form, filled with the current values.
+///
+/// The password is never sent back.
+pub fn write_form<W: Write>(
+    w: &mut W,
+    settings: &Settings,
+    error: Option<FormError>,
+) -> fmt::Result {
+    w.write_str(PAGE_HEAD)?;
+    w.write_str("<h1>Weather station setup</h1>")?;
+    if let Some(error) = error {
+        write!(w, "<p><strong>{}</strong></p>", error.as_str())?;
+    }
+    write!(w, "<form method=\"post\" action=\"{SAVE_PATH}\">")?;
+
+    let broker = if settings.broker_url.is_empty() {
+        settings.broker_ip.as_str()
+    } else {
+        settings.broker_url.as_str()
+    };
+    let fields = [
+        ("ssid", "Wi-Fi network", settings.ssid.as_str(), "text"),
+        ("wifi_pass", "Wi-Fi password", "", "password"),
+        ("broker", "MQTT broker", broker, "text"),
+        ("topic", "MQTT topic", settings.topic.as_str(), "text"),
+    ];
+    for (name, label, value, kind) in fields {
+        write!(
+            w,
+            "<p><label>{label}<br><input name=\"{name}\" type=\"{kind}\" value=\""
+        )?;
+        write_escaped(w, value)?;
+        w.write_str("\"></label></p>")?;
+    }
+
+    w.write_str("<p><button type=\"submit\">Save and restart</button></p></form></body></html>")
+}
+
+/// Write the page confirming the settings were saved.
+pub fn write_saved<W: Write>(w: &mut W, ssid: &str) -> fmt::Result {
+    w.write_str(PAGE_HEAD)?;
+    w.write_str("<h1>Saved</h1><p>The station restarts and joins ")?;
+    write_escaped(w, ssid)?;
+    w.write_str(".</p></body></html>")
+}
+
+const DNS_HEADER_LEN: usize = 12;
+const DNS_TYPE_A: u16 = 1;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_TTL_S: u32 = 60;
+
+/// Answer a DNS query with `ip`, whatever name was asked for.
+///
+/// Queries for other record types get an empty answer. Returns the length of
+/// the response written to `out`, `None` when `query` is not a single question
+/// query or `out` is too small.
+pub fn dns_answer(query: &[u8], ip: Ipv4Addr, out: &mut [u8]) -> Option<usize> {
+    let header = query.get(..DNS_HEADER_LEN)?;
+    let is_query = header[2] & 0x80 == 0;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    if !is_query || questions != 1 {
+        return None;
+    }
+
+    // skip the name labels, then type and class
+    let mut pos = DNS_HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        pos += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let question = query.get(DNS_HEADER_LEN..pos + 4)?;
+    let qtype = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    let qclass = u16::from_be_bytes([question[question.len() - 2], question[question.len() - 1]]);
+    let answers: u16 = (qtype == DNS_TYPE_A && qclass == DNS_CLASS_IN).into();
+
+    let end = DNS_HEADER_LEN + question.len();
+    let len = end + answers as usize * 16;
+    let out = out.get_mut(..len)?;
+
+    out[..2].copy_from_slice(&header[..2]);
+    // response, recursion desired copied, recursion available, no error
+    out[2] = 0x80 | (header[2] & 0x01);
+    out[3] = 0x80;
+    out[4..6].copy_from_slice(&1u16.to_be_bytes());
+    out[6..8].copy_from_slice(&answers.to_be_bytes());
+    out[8..12].fill(0);
+    out[DNS_HEADER_LEN..end].copy_from_slice(question);
+
+    if answers == 1 {
+        let answer = &mut out[end..];
+        answer[..2].copy_from_slice(&0xC00Cu16.to_be_bytes()); // name of the question
+        answer[2..4].copy_from_slice(&DNS_TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&DNS_TTL_S.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&ip.octets());
+    }
+
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::tests::defaults as settings;
+    use crate::settings::truncated;
+
+    #[test]
+    fn parses_a_form_post() {
+        let raw = b"POST /save HTTP/1.1\r\nHost: 192.168.4.1\r\nContent-Length: 9\r\n\r\nssid=home";
+        let request = parse_request(raw).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, SAVE_PATH);
+        assert_eq!(request.body, "ssid=home");
+
+        assert_eq!(
+            parse_request(&raw[..raw.len() - 1]),
+            Err(RequestError::Incomplete)
+        );
+        assert_eq!(
+            parse_request(b"GET / HTTP/1.1\r\n"),
+            Err(RequestError::Incomplete)
+        );
+        assert_eq!(parse_request(b"\r\n\r\n"), Err(RequestError::Invalid));
+    }
+
+    #[test]
+    fn portal_is_always_protected() {
+        let mac = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+        assert_eq!(
+            portal_password("correct horse", mac).as_str(),
+            "correct horse"
+        );
+
+        let derived = portal_password("", mac);
+        assert!(is_valid_password(&derived));
+        assert_eq!(portal_password("short", mac), derived);
+        assert_ne!(
+            portal_password("", [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x57]),
+            derived
+        );
+        assert!(!is_valid_password(&"x".repeat(64)));
+    }
+
+    #[test]
+    fn rejects_an_overflowing_length() {
+        assert_eq!(
+            parse_request(
+                b"POST /save HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\nssid=home"
+            ),
+            Err(RequestError::Invalid)
+        );
+    }
+
+    #[test]
+    fn applies_the_form() {
+        let mut s = settings();
+        apply_form(
+            &mut s,
+            "ssid=My+Home%21&wifi_pass=p%26ss&broker=mqtt.lan&topic=",
+        )
+        .unwrap();
+        assert_eq!(s.ssid.as_str(), "My Home!");
+        assert_eq!(s.wifi_pass.as_str(), "p&ss");
+        assert_eq!(s.broker_url.as_str(), "mqtt.lan");
+        assert_eq!(s.broker_ip.as_str(), "192.168.1.69");
+        assert_eq!(s.topic.as_str(), "weather_station");
+
+        apply_form(&mut s, "ssid=x&broker=10.0.0.2&topic=garden").unwrap();
+        assert_eq!(s.broker_ip.as_str(), "10.0.0.2");
+        assert_eq!(s.broker_url.as_str(), "");
+        assert_eq!(s.topic.as_str(), "garden");
+        assert_eq!(s.wifi_pass.as_str(), "");
+    }
+
+    #[test]
+    fn rejects_invalid_forms() {
+        let mut s = settings();
+        assert_eq!(
+            apply_form(&mut s, "wifi_pass=x"),
+            Err(FormError::MissingSsid)
+        );
+        assert_eq!(
+            apply_form(&mut s, "ssid=a%2"),
+            Err(FormError::InvalidField("ssid"))
+        );
+        let long = "ssid=x&topic=".to_owned() + &"t".repeat(49);
+        assert_eq!(
+            apply_form(&mut s, &long),
+            Err(FormError::InvalidField("topic"))
+        );
+        assert_eq!(s, settings());
+    }
+
+    #[test]
+    fn form_escapes_values() {
+        let mut s = settings();
+        s.ssid = truncated("<a\"b>");
+        s.wifi_pass = truncated("secret");
+        let mut page = std::string::String::new();
+        write_form(&mut page, &s, Some(FormError::MissingSsid)).unwrap();
+        assert!(page.contains("value=\"&lt;a&quot;b&gt;\""));
+        assert!(page.contains("value=\"192.168.1.69\""));
+        assert!(page.contains(FormError::MissingSsid.as_str()));
+        assert!(!page.contains("secret"));
+    }
+
+    fn query(qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        q.extend_from_slice(b"\x07example\x03com\x00");
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        q
+    }
+
+    #[test]
+    fn answers_a_queries_with_the_portal() {
+        let q = query(DNS_TYPE_A);
+        let mut out = [0u8; 512];
+        let len = dns_answer(&q, Ipv4Addr::new(192, 168, 4, 1), &mut out).unwrap();
+        assert_eq!(len, q.len() + 16);
+        assert_eq!(&out[..2], &[0x12, 0x34]);
+        assert_eq!(&out[2..4], &[0x81, 0x80]);
+        assert_eq!(&out[6..8], &[0, 1]);
+        assert_eq!(&out[12..q.len()], &q[12..]);
+        assert_eq!(&out[len - 4..len], &[192, 168, 4, 1]);
+    }
+
+    #[test]
+    fn other_queries_get_no_answer() {
+        let q = query(28); // AAAA
+        let mut out = [0u8; 512];
+        let len = dns_answer(&q, Ipv4Addr::new(192, 168, 4, 1), &mut out).unwrap();
+        assert_eq!(len, q.len());
+        assert_eq!(&out[6..8], &[0, 0]);
+
+        let mut response = q.clone();
+        response[2] |= 0x80;
+        assert_eq!(dns_answer(&response, Ipv4Addr::LOCALHOST, &mut out), None);
+        assert_eq!(
+            dns_answer(&q[..q.len() - 1], Ipv4Addr::LOCALHOST, &mut out),
+            None
+        );
+    }
+}
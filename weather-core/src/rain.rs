@@ -0,0 +1,410 @@
+//! Tipping bucket rain gauge.
+//!
+//! Every tip of the bucket is timestamped in a `TipLog`, which the firmware
+//! places in RTC slow memory so it survives deep sleep. Rain rate and the
+//! totals over the last hour, the last 24 hours and since midnight are derived
+//! from it when the station publishes.
+//!
+//! The tips counted since the last window and the state of the reed switch
+//! are kept in a `RainCounter`, `Sealed` with a magic number of its own so
+//! garbage left in RTC memory by a power loss is never reported as rain.
+
+use crate::crc::Crc32;
+use crate::sealed::Seal;
+
+/// Rain collected by one tip of the bucket of the stock gauge, in millimeters.
+pub const MM_PER_TIP: f32 = 0.231;
+
+/// Tips further apart than this report no rain rate.
+pub const RATE_MAX_INTERVAL_S: u64 = 3600;
+
+const SECONDS_PER_HOUR: u64 = 3600;
+const SECONDS_PER_DAY: u64 = 24 * SECONDS_PER_HOUR;
+const COUNTER_MAGIC: u32 = 0x4E49_4152; // "RAIN"
+
+/// Calibration of the bucket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RainGauge {
+    pub mm_per_tip: f32,
+}
+
+impl Default for RainGauge {
+    fn default() -> Self {
+        RainGauge {
+            mm_per_tip: MM_PER_TIP,
+        }
+    }
+}
+
+impl RainGauge {
+    /// Gauge collecting `um_per_tip` micrometers per tip, `None` for 0.
+    pub fn from_um(um_per_tip: u16) -> Option<Self> {
+        (um_per_tip != 0).then(|| RainGauge {
+            mm_per_tip: um_per_tip as f32 / 1000.0,
+        })
+    }
+
+    /// Convert a number of bucket tips to millimeters of rain.
+    pub fn mm(&self, tips: u32) -> f32 {
+        tips as f32 * self.mm_per_tip
+    }
+}
+
+/// Tips counted since the last window, time of the last one and whether the
+/// reed switch is stuck closed.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct RainCounter {
+    magic: u32,
+    tips: u32,
+    last_tip_s: u64,
+    stuck: u32,
+}
+
+impl Default for RainCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RainCounter {
+    pub const fn new() -> Self {
+        RainCounter {
+            magic: COUNTER_MAGIC,
+            tips: 0,
+            last_tip_s: 0,
+            stuck: 0,
+        }
+    }
+
+    /// Tips counted since the last window.
+    pub fn tips(&self) -> u32 {
+        self.tips
+    }
+
+    pub fn set_tips(&mut self, tips: u32) {
+        self.tips = tips;
+    }
+
+    /// RTC time of the last counted tip, 0 when there is none.
+    pub fn last_tip_s(&self) -> u64 {
+        self.last_tip_s
+    }
+
+    pub fn set_last_tip_s(&mut self, now_s: u64) {
+        self.last_tip_s = now_s;
+    }
+
+    /// Whether the reed switch was found stuck closed.
+    pub fn stuck(&self) -> bool {
+        self.stuck != 0
+    }
+
+    /// Record the state of the switch, returning the previous one.
+    pub fn replace_stuck(&mut self, stuck: bool) -> bool {
+        core::mem::replace(&mut self.stuck, stuck as u32) != 0
+    }
+}
+
+impl Seal for RainCounter {
+    const TAG: u8 = 0x52;
+    const EMPTY: Self = Self::new();
+
+    fn checksum(&self, crc: &mut Crc32) {
+        crc.update(&self.magic.to_le_bytes());
+        crc.update(&self.tips.to_le_bytes());
+        crc.update(&self.last_tip_s.to_le_bytes());
+        crc.update(&self.stuck.to_le_bytes());
+    }
+
+    fn is_consistent(&self) -> bool {
+        self.magic == COUNTER_MAGIC
+    }
+}
+
+/// Rain figures published at the end of a window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RainSummary {
+    pub rate_mm_h: f32,
+    pub last_hour_mm: f32,
+    pub last_24h_mm: f32,
+    /// Rain since midnight (UTC), `None` while the wall clock is unknown.
+    pub today_mm: Option<f32>,
+    /// RTC time of the last tip, `None` when no tip is logged.
+    pub last_tip_s: Option<u64>,
+    /// `false` when the log overflowed during the last 24 hours, the totals
+    /// then miss the oldest tips.
+    pub complete: bool,
+}
+
+/// Timestamps of the last `N` tips, oldest first.
+///
+/// Timestamps are RTC seconds, stored on 32 bits to keep the log small. When
+/// the log is full the oldest tip is evicted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct TipLog<const N: usize> {
+    head: u32,
+    len: u32,
+    tips: [u32; N],
+}
+
+impl<const N: usize> Default for TipLog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> TipLog<N> {
+    pub const fn new() -> Self {
+        TipLog {
+            head: 0,
+            len: 0,
+            tips: [0; N],
+        }
+    }
+
+    /// Whether the indices are consistent, `false` for uninitialised memory.
+    pub fn is_valid(&self) -> bool {
+        (self.head as usize) < N && (self.len as usize) <= N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Log a tip at `now_s`, evicting the oldest one when full.
+    ///
+    /// Returns `true` when a tip was evicted to make room.
+    pub fn push(&mut self, now_s: u64) -> bool {
+        if N == 0 {
+            return true;
+        }
+
+        let tail = (self.head as usize + self.len()) % N;
+        self.tips[tail] = now_s.min(u32::MAX as u64) as u32;
+
+        if self.len() == N {
+            self.head = ((self.head as usize + 1) % N) as u32;
+            true
+        } else {
+            self.len += 1;
+            false
+        }
+    }
+
+    /// Tips from the newest to the oldest.
+    fn newest_first(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.len()).rev().map(move |i| {
+            let index = (self.head as usize + i) % N;
+            self.tips[index] as u64
+        })
+    }
+
+    pub fn last(&self) -> Option<u64> {
+        self.newest_first().next()
+    }
+
+    /// Number of tips at or after `since_s`.
+    pub fn count_since(&self, since_s: u64) -> u32 {
+        self.newest_first().take_while(|&t| t >= since_s).count() as u32
+    }
+
+    /// Whether every tip since `since_s` is still in the log.
+    pub fn complete_since(&self, since_s: u64) -> bool {
+        self.len() < N || self.newest_first().last().is_some_and(|t| t < since_s)
+    }
+
+    /// Rain rate in mm/h at `now_s`.
+    ///
+    /// It is derived from the interval between the last two tips, or the time
+    /// since the last tip once that is longer, so the rate decays when the rain
+    /// stops instead of sticking to its last value.
+    pub fn rate_mm_h(&self, gauge: &RainGauge, now_s: u64) -> f32 {
+        let mut tips = self.newest_first();
+        let (Some(last), Some(previous)) = (tips.next(), tips.next()) else {
+            return 0.0;
+        };
+
+        let interval = last
+            .saturating_sub(previous)
+            .max(now_s.saturating_sub(last))
+            .max(1);
+        if interval > RATE_MAX_INTERVAL_S {
+            return 0.0;
+        }
+        gauge.mm_per_tip * SECONDS_PER_HOUR as f32 / interval as f32
+    }
+
+    /// Rain figures at `now_s`. `midnight_s` is the RTC time of the last
+    /// midnight, when the wall clock is known.
+    pub fn summary(&self, gauge: &RainGauge, now_s: u64, midnight_s: Option<u64>) -> RainSummary {
+        let day_ago = now_s.saturating_sub(SECONDS_PER_DAY);
+
+        RainSummary {
+            rate_mm_h: self.rate_mm_h(gauge, now_s),
+            last_hour_mm: gauge.mm(self.count_since(now_s.saturating_sub(SECONDS_PER_HOUR))),
+            last_24h_mm: gauge.mm(self.count_since(day_ago)),
+            today_mm: midnight_s.map(|midnight| gauge.mm(self.count_since(midnight))),
+            last_tip_s: self.last(),
+            complete: self.complete_since(day_ago),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sealed::Sealed;
+
+    const DAY: u64 = 86_400;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    fn log_of(tips: &[u64]) -> TipLog<8> {
+        let mut log = TipLog::new();
+        for &t in tips {
+            log.push(t);
+        }
+        log
+    }
+
+    #[test]
+    fn tips_to_millimeters() {
+        let gauge = RainGauge::default();
+        assert_eq!(gauge.mm(0), 0.0);
+        assert!(close(gauge.mm(10), 2.31));
+
+        let gauge = RainGauge::from_um(200).unwrap();
+        assert!(close(gauge.mm(10), 2.0));
+        assert_eq!(RainGauge::from_um(0), None);
+    }
+
+    #[test]
+    fn totals_over_periods() {
+        let now = 3 * DAY;
+        let log = log_of(&[
+            now - DAY - 10,
+            now - 5 * 3600,
+            now - 1800,
+            now - 600,
+            now - 300,
+        ]);
+        let gauge = RainGauge::from_um(200).unwrap();
+
+        let summary = log.summary(&gauge, now, Some(now - 4 * 3600));
+        assert!(close(summary.last_hour_mm, 0.6));
+        assert!(close(summary.last_24h_mm, 0.8));
+        assert!(close(summary.today_mm.unwrap(), 0.6));
+        assert_eq!(summary.last_tip_s, Some(now - 300));
+        assert!(summary.complete);
+
+        assert_eq!(log.summary(&gauge, now, None).today_mm, None);
+    }
+
+    #[test]
+    fn rate_decays_when_the_rain_stops() {
+        let gauge = RainGauge::from_um(200).unwrap();
+        let log = log_of(&[1000, 1360]);
+
+        // 0.2 mm in 6 minutes
+        assert!(close(log.rate_mm_h(&gauge, 1400), 2.0));
+        // no tip for 12 minutes
+        assert!(close(log.rate_mm_h(&gauge, 1360 + 720), 1.0));
+        assert_eq!(log.rate_mm_h(&gauge, 1360 + RATE_MAX_INTERVAL_S + 1), 0.0);
+
+        assert_eq!(log_of(&[1000]).rate_mm_h(&gauge, 1010), 0.0);
+        assert_eq!(log_of(&[1000, 9000]).rate_mm_h(&gauge, 9010), 0.0);
+    }
+
+    #[test]
+    fn full_log_evicts_the_oldest_tips() {
+        let mut log = log_of(&[10, 20, 30, 40, 50, 60, 70, 80]);
+        assert!(log.complete_since(15));
+        assert!(log.push(90));
+        assert_eq!(log.len(), 8);
+        assert_eq!(log.last(), Some(90));
+        assert_eq!(log.count_since(0), 8);
+        assert!(!log.complete_since(15));
+        assert!(log.complete_since(25));
+    }
+
+    #[test]
+    fn empty_log() {
+        let summary = TipLog::<8>::new().summary(&RainGauge::default(), 5000, Some(0));
+        assert_eq!(
+            summary,
+            RainSummary {
+                rate_mm_h: 0.0,
+                last_hour_mm: 0.0,
+                last_24h_mm: 0.0,
+                today_mm: Some(0.0),
+                last_tip_s: None,
+                complete: true,
+            }
+        );
+    }
+
+    #[test]
+    fn counter_keeps_heavy_rain() {
+        let mut counter = RainCounter::new();
+        assert_eq!(counter.tips(), 0);
+        counter.set_tips(250);
+        counter.set_last_tip_s(1234);
+        assert_eq!(counter.tips(), 250);
+        assert_eq!(counter.last_tip_s(), 1234);
+        assert!(!counter.stuck());
+    }
+
+    #[test]
+    fn corrupted_counter_reads_as_zero() {
+        let mut counter = Sealed::<RainCounter>::new();
+        assert!(!counter.is_valid());
+        counter.update(|c| {
+            c.set_tips(12);
+            c.replace_stuck(true)
+        });
+        counter.corrupt(|c| c.tips = 0xDEAD_BEEF);
+        assert!(counter.get().is_none());
+        assert_eq!(counter.update(|c| (c.tips(), c.stuck())), (0, false));
+
+        counter.update(|c| c.set_last_tip_s(99));
+        assert_eq!(
+            counter.get().map(|c| (c.tips(), c.last_tip_s())),
+            Some((0, 99))
+        );
+
+        // a matching CRC without the magic is not enough either
+        counter.update(|c| c.magic = 0);
+        assert!(counter.get().is_none());
+    }
+
+    #[test]
+    fn stuck_flag() {
+        let mut counter = RainCounter::new();
+        assert!(!counter.replace_stuck(true));
+        assert!(counter.stuck());
+        assert!(counter.replace_stuck(false));
+        assert!(!counter.stuck());
+    }
+
+    #[test]
+    fn garbage_indices_are_detected() {
+        let mut log = TipLog::<8>::new();
+        log.len = 9;
+        assert!(!log.is_valid());
+        log.clear();
+        assert!(log.is_valid());
+    }
+}
@@ -0,0 +1,417 @@
+//! Uniform representation of a measurement.
+//!
+//! Every sensor produces `Reading`s. The MQTT layer derives topics and
+//! payloads from them, so no sensor has to know how its values end up on the
+//! wire.
+
+use crate::wind::COMPASS_POINTS;
+use core::fmt;
+
+/// Physical quantity measured by the station.
+///
+/// The discriminants are stored in RTC memory and must never change: new
+/// quantities take the next free value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Quantity {
+    Temperature = 0,
+    Humidity = 1,
+    WindSpeed = 2,
+    WindAngle = 3,
+    WindDirection = 4,
+    Rain = 5,
+    BatteryVoltage = 6,
+    BatteryPercentage = 7,
+    BatteryCurrent = 8,
+    BatteryPower = 9,
+    ShuntVoltage = 10,
+    ChargeIn = 11,
+    ChargeOut = 12,
+    RainRate = 13,
+    RainLastHour = 14,
+    RainLast24h = 15,
+    RainToday = 16,
+    RainLastTip = 17,
+    RainSensor = 18,
+    WindGust = 19,
+    WindLull = 20,
+    WindSpeedStddev = 21,
+    WindSpeedAverage = 22,
+    WindDirectionStddev = 23,
+    VaneMagnet = 24,
+    VaneAgc = 25,
+}
+
+impl Quantity {
+    pub const ALL: &[Quantity] = &[
+        Quantity::Temperature,
+        Quantity::Humidity,
+        Quantity::WindSpeed,
+        Quantity::WindAngle,
+        Quantity::WindDirection,
+        Quantity::Rain,
+        Quantity::BatteryVoltage,
+        Quantity::BatteryPercentage,
+        Quantity::BatteryCurrent,
+        Quantity::BatteryPower,
+        Quantity::ShuntVoltage,
+        Quantity::ChargeIn,
+        Quantity::ChargeOut,
+        Quantity::RainRate,
+        Quantity::RainLastHour,
+        Quantity::RainLast24h,
+        Quantity::RainToday,
+        Quantity::RainLastTip,
+        Quantity::RainSensor,
+        Quantity::WindGust,
+        Quantity::WindLull,
+        Quantity::WindSpeedStddev,
+        Quantity::WindSpeedAverage,
+        Quantity::WindDirectionStddev,
+        Quantity::VaneMagnet,
+        Quantity::VaneAgc,
+    ];
+
+    /// Inverse of `quantity as u8`.
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|&q| q as u8 == v)
+    }
+
+    /// Snake case identifier, used as JSON key.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::Humidity => "humidity",
+            Quantity::WindSpeed => "wind_speed",
+            Quantity::WindAngle => "wind_angle",
+            Quantity::WindDirection => "wind_direction",
+            Quantity::Rain => "rain",
+            Quantity::BatteryVoltage => "battery_voltage",
+            Quantity::BatteryPercentage => "battery_percentage",
+            Quantity::BatteryCurrent => "battery_current",
+            Quantity::BatteryPower => "battery_power",
+            Quantity::ShuntVoltage => "shunt_voltage",
+            Quantity::ChargeIn => "charge_in",
+            Quantity::ChargeOut => "charge_out",
+            Quantity::RainRate => "rain_rate",
+            Quantity::RainLastHour => "rain_last_hour",
+            Quantity::RainLast24h => "rain_last_24h",
+            Quantity::RainToday => "rain_today",
+            Quantity::RainLastTip => "rain_last_tip",
+            Quantity::RainSensor => "rain_sensor",
+            Quantity::WindGust => "wind_gust",
+            Quantity::WindLull => "wind_lull",
+            Quantity::WindSpeedStddev => "wind_speed_stddev",
+            Quantity::WindSpeedAverage => "wind_speed_10min",
+            Quantity::WindDirectionStddev => "wind_direction_stddev",
+            Quantity::VaneMagnet => "vane_magnet",
+            Quantity::VaneAgc => "vane_agc",
+        }
+    }
+
+    /// Topic suffix, relative to the station base topic.
+    pub const fn topic(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::Humidity => "humidity",
+            Quantity::WindSpeed => "anemo/wind_speed",
+            Quantity::WindAngle => "anemo/wind_angle",
+            Quantity::WindDirection => "anemo/wind_direction",
+            Quantity::Rain => "rain",
+            Quantity::BatteryVoltage => "battery/voltage",
+            Quantity::BatteryPercentage => "battery/percentage",
+            Quantity::BatteryCurrent => "battery/current",
+            Quantity::BatteryPower => "battery/power",
+            Quantity::ShuntVoltage => "battery/shunt_voltage",
+            Quantity::ChargeIn => "battery/charge_in",
+            Quantity::ChargeOut => "battery/charge_out",
+            Quantity::RainRate => "rain/rate",
+            Quantity::RainLastHour => "rain/last_hour",
+            Quantity::RainLast24h => "rain/last_24h",
+            Quantity::RainToday => "rain/today",
+            Quantity::RainLastTip => "rain/last_tip",
+            Quantity::RainSensor => "rain/sensor",
+            Quantity::WindGust => "anemo/wind_gust",
+            Quantity::WindLull => "anemo/wind_lull",
+            Quantity::WindSpeedStddev => "anemo/wind_speed_stddev",
+            Quantity::WindSpeedAverage => "anemo/wind_speed_10min",
+            Quantity::WindDirectionStddev => "anemo/wind_direction_stddev",
+            Quantity::VaneMagnet => "anemo/vane_magnet",
+            Quantity::VaneAgc => "anemo/vane_agc",
+        }
+    }
+
+    /// Labels a reading of the quantity takes, empty for numeric quantities.
+    pub const fn labels(self) -> &'static [&'static str] {
+        match self {
+            Quantity::WindDirection => &COMPASS_POINTS,
+            Quantity::RainSensor => &["ok", "stuck"],
+            Quantity::VaneMagnet => &["ok", "missing", "too_weak", "too_strong"],
+            _ => &[],
+        }
+    }
+
+    /// Unit the quantity is reported in.
+    pub const fn unit(self) -> Unit {
+        match self {
+            Quantity::Temperature => Unit::Celsius,
+            Quantity::Humidity => Unit::Percent,
+            Quantity::WindSpeed
+            | Quantity::WindGust
+            | Quantity::WindLull
+            | Quantity::WindSpeedStddev
+            | Quantity::WindSpeedAverage => Unit::KilometersPerHour,
+            Quantity::WindAngle | Quantity::WindDirectionStddev => Unit::Degrees,
+            Quantity::WindDirection => Unit::None,
+            Quantity::Rain => Unit::Millimeters,
+            Quantity::BatteryVoltage => Unit::Millivolts,
+            Quantity::BatteryPercentage => Unit::Percent,
+            Quantity::BatteryCurrent => Unit::Milliamps,
+            Quantity::BatteryPower => Unit::Milliwatts,
+            Quantity::ShuntVoltage => Unit::Millivolts,
+            Quantity::ChargeIn | Quantity::ChargeOut => Unit::MilliampHours,
+            Quantity::RainRate => Unit::MillimetersPerHour,
+            Quantity::RainLastHour | Quantity::RainLast24h | Quantity::RainToday => {
+                Unit::Millimeters
+            }
+            Quantity::RainLastTip => Unit::None,
+            Quantity::RainSensor | Quantity::VaneMagnet | Quantity::VaneAgc => Unit::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Celsius,
+    Percent,
+    KilometersPerHour,
+    Degrees,
+    Millimeters,
+    MillimetersPerHour,
+    Millivolts,
+    Milliamps,
+    Milliwatts,
+    MilliampHours,
+    None,
+}
+
+impl Unit {
+    pub const fn symbol(self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::KilometersPerHour => "km/h",
+            Unit::Degrees => "°",
+            Unit::Millimeters => "mm",
+            Unit::MillimetersPerHour => "mm/h",
+            Unit::Millivolts => "mV",
+            Unit::Milliamps => "mA",
+            Unit::Milliwatts => "mW",
+            Unit::MilliampHours => "mAh",
+            Unit::None => "",
+        }
+    }
+}
+
+/// Confidence in a reading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Quality {
+    /// Measured without any error.
+    Good,
+    /// Derived from an incomplete set of samples.
+    Degraded,
+    /// Not backed by any valid sample.
+    Invalid,
+}
+
+impl Quality {
+    /// Inverse of `quality as u8`.
+    pub fn from_u8(v: u8) -> Option<Self> {
+        [Quality::Good, Quality::Degraded, Quality::Invalid]
+            .into_iter()
+            .find(|&q| q as u8 == v)
+    }
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Degraded => "degraded",
+            Quality::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Number(f32),
+    Label(&'static str),
+    /// Unix time in seconds, too large for an `f32` to keep to the second.
+    Timestamp(u64),
+    /// No valid measurement, `null` in JSON documents.
+    Missing,
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Self {
+        Value::Number(v)
+    }
+}
+
+impl From<&'static str> for Value {
+    fn from(v: &'static str) -> Self {
+        Value::Label(v)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(v) => write!(f, "{v}"),
+            Value::Label(v) => f.write_str(v),
+            Value::Timestamp(v) => write!(f, "{v}"),
+            Value::Missing => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    pub quantity: Quantity,
+    pub value: Value,
+    pub unit: Unit,
+    /// Seconds on the RTC clock, which keeps running across deep sleep.
+    pub timestamp: u64,
+    pub quality: Quality,
+}
+
+impl Reading {
+    /// A good reading of `quantity` in its default unit, not yet timestamped.
+    ///
+    /// NaN and infinities are no measurement, they make a `missing` reading.
+    pub fn new(quantity: Quantity, value: impl Into<Value>) -> Self {
+        match value.into() {
+            Value::Number(v) if !v.is_finite() => Self::missing(quantity),
+            Value::Missing => Self::missing(quantity),
+            value => Reading {
+                quantity,
+                value,
+                unit: quantity.unit(),
+                timestamp: 0,
+                quality: Quality::Good,
+            },
+        }
+    }
+
+    /// An invalid reading of `quantity`, for a sensor that produced no data.
+    pub fn missing(quantity: Quantity) -> Self {
+        Reading {
+            quantity,
+            value: Value::Missing,
+            unit: quantity.unit(),
+            timestamp: 0,
+            quality: Quality::Invalid,
+        }
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Set the quality, a missing value stays invalid.
+    pub fn with_quality(mut self, quality: Quality) -> Self {
+        if self.value != Value::Missing {
+            self.quality = quality;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_reading_uses_default_unit() {
+        let reading = Reading::new(Quantity::BatteryVoltage, 3900.0);
+        assert_eq!(reading.unit, Unit::Millivolts);
+        assert_eq!(reading.quality, Quality::Good);
+        assert_eq!(reading.timestamp, 0);
+    }
+
+    #[test]
+    fn non_finite_numbers_are_missing() {
+        for v in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let reading = Reading::new(Quantity::WindAngle, v).with_quality(Quality::Good);
+            assert_eq!(reading.value, Value::Missing);
+            assert_eq!(reading.quality, Quality::Invalid);
+        }
+        let missing = Reading::missing(Quantity::Temperature);
+        assert_eq!(missing.unit, Unit::Celsius);
+        assert_eq!(missing.value.to_string(), "");
+    }
+
+    #[test]
+    fn values_format_like_the_legacy_payloads() {
+        assert_eq!(Value::from(21.5).to_string(), "21.5");
+        assert_eq!(Value::from(3900.0).to_string(), "3900");
+        assert_eq!(Value::from("NE").to_string(), "NE");
+        assert_eq!(Value::Timestamp(1_760_000_123).to_string(), "1760000123");
+    }
+
+    #[test]
+    fn quantities_round_trip_through_u8() {
+        for &q in Quantity::ALL {
+            assert_eq!(Quantity::from_u8(q as u8), Some(q));
+        }
+        assert_eq!(Quantity::from_u8(200), None);
+        assert_eq!(
+            Quality::from_u8(Quality::Degraded as u8),
+            Some(Quality::Degraded)
+        );
+        assert_eq!(Quality::from_u8(3), None);
+    }
+
+    #[test]
+    fn quantity_values_are_pinned() {
+        let pinned = [
+            (Quantity::Temperature, 0),
+            (Quantity::Humidity, 1),
+            (Quantity::WindSpeed, 2),
+            (Quantity::WindAngle, 3),
+            (Quantity::WindDirection, 4),
+            (Quantity::Rain, 5),
+            (Quantity::BatteryVoltage, 6),
+            (Quantity::BatteryPercentage, 7),
+            (Quantity::BatteryCurrent, 8),
+            (Quantity::BatteryPower, 9),
+            (Quantity::ShuntVoltage, 10),
+            (Quantity::ChargeIn, 11),
+            (Quantity::ChargeOut, 12),
+            (Quantity::RainRate, 13),
+            (Quantity::RainLastHour, 14),
+            (Quantity::RainLast24h, 15),
+            (Quantity::RainToday, 16),
+            (Quantity::RainLastTip, 17),
+            (Quantity::RainSensor, 18),
+            (Quantity::WindGust, 19),
+            (Quantity::WindLull, 20),
+            (Quantity::WindSpeedStddev, 21),
+            (Quantity::WindSpeedAverage, 22),
+            (Quantity::WindDirectionStddev, 23),
+            (Quantity::VaneMagnet, 24),
+            (Quantity::VaneAgc, 25),
+        ];
+        assert_eq!(pinned.len(), Quantity::ALL.len());
+        for (q, value) in pinned {
+            assert_eq!(q as u8, value, "{q:?}");
+        }
+    }
+
+    #[test]
+    fn topics_are_unchanged() {
+        assert_eq!(Quantity::WindSpeed.topic(), "anemo/wind_speed");
+        assert_eq!(Quantity::BatteryPercentage.topic(), "battery/percentage");
+    }
+}
@@ -0,0 +1,213 @@
+//! State persisted in RTC memory across deep sleep.
+//!
+//! The firmware keeps a handful of counters in RTC fast memory. `RtcMemory`
+//! abstracts the raw loads and stores so the rules built on top of them
+//! (rain tip debouncing, wakeup scheduling) can be exercised on the host.
+
+use crate::command::SLEEP_RANGE_S;
+
+/// Raw access to the RTC-persistent variables.
+///
+/// Implementors only provide the loads and stores, the provided methods hold
+/// the logic.
+pub trait RtcMemory {
+    fn load_rain_tips(&self) -> u32;
+    fn store_rain_tips(&self, v: u32);
+    fn load_last_tip(&self) -> u64;
+    fn store_last_tip(&self, v: u64);
+    fn load_next_full_measurement_s(&self) -> u64;
+    fn store_next_full_measurement_s(&self, v: u64);
+    fn load_boot_count(&self) -> u32;
+    fn store_boot_count(&self, v: u32);
+    fn load_unix_offset(&self) -> u64;
+    fn store_unix_offset(&self, v: u64);
+    fn load_sleep_interval_s(&self) -> u64;
+    fn store_sleep_interval_s(&self, v: u64);
+
+    /// Count a new boot and return the updated count.
+    fn inc_boot_count(&self) -> u32 {
+        let count = self.load_boot_count().wrapping_add(1);
+        self.store_boot_count(count);
+        count
+    }
+
+    /// Increment rain tips
+    ///
+    /// Increment the rain tips and store the time it was incremented, so bounces of the reed
+    /// switch within `debounce_s` are not counted. Returns `false` when the tip was ignored.
+    fn inc_rain_tips(&self, now: u64, debounce_s: u64) -> bool {
+        let cur = self.load_rain_tips();
+        let last_tip = self.load_last_tip();
+
+        if cur == 0 || (last_tip != 0 && now.saturating_sub(last_tip) > debounce_s) {
+            self.store_rain_tips(cur.saturating_add(1));
+            self.store_last_tip(now);
+            return true;
+        }
+
+        false
+    }
+
+    /// Unix time matching the RTC time `rtc_now`, once the clock has been synchronised.
+    fn unix_time(&self, rtc_now: u64) -> Option<u64> {
+        match self.load_unix_offset() {
+            0 => None,
+            offset => Some(offset + rtc_now),
+        }
+    }
+
+    /// Deep sleep interval set by a `sleep` command, `default` when there is none.
+    fn sleep_interval_s(&self, default: u64) -> u64 {
+        let interval = self.load_sleep_interval_s();
+        if SLEEP_RANGE_S.contains(&interval) {
+            interval
+        } else {
+            default
+        }
+    }
+
+    /// Schedule the first full measurement `interval_s` after `now`.
+    ///
+    /// Has no effect if a measurement is already scheduled, which is the case when the board
+    /// wakes up from the rain sensor.
+    fn init_next_full_measurement(&self, now: u64, interval_s: u64) {
+        if self.load_next_full_measurement_s() == 0 {
+            self.store_next_full_measurement_s(now + interval_s);
+        }
+    }
+
+    /// Seconds left before the next full measurement, never less than 1.
+    fn remaining_sleep_s(&self, now: u64) -> u64 {
+        core::cmp::max(self.load_next_full_measurement_s().saturating_sub(now), 1)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// In-memory stand-in for the RTC variables.
+    #[derive(Default)]
+    pub struct MockRtc {
+        pub rain_tips: Cell<u32>,
+        pub last_tip: Cell<u64>,
+        pub next_full: Cell<u64>,
+        pub boot_count: Cell<u32>,
+        pub unix_offset: Cell<u64>,
+        pub sleep_interval: Cell<u64>,
+    }
+
+    impl RtcMemory for MockRtc {
+        fn load_rain_tips(&self) -> u32 {
+            self.rain_tips.get()
+        }
+        fn store_rain_tips(&self, v: u32) {
+            self.rain_tips.set(v)
+        }
+        fn load_last_tip(&self) -> u64 {
+            self.last_tip.get()
+        }
+        fn store_last_tip(&self, v: u64) {
+            self.last_tip.set(v)
+        }
+        fn load_next_full_measurement_s(&self) -> u64 {
+            self.next_full.get()
+        }
+        fn store_next_full_measurement_s(&self, v: u64) {
+            self.next_full.set(v)
+        }
+        fn load_boot_count(&self) -> u32 {
+            self.boot_count.get()
+        }
+        fn store_boot_count(&self, v: u32) {
+            self.boot_count.set(v)
+        }
+        fn load_unix_offset(&self) -> u64 {
+            self.unix_offset.get()
+        }
+        fn store_unix_offset(&self, v: u64) {
+            self.unix_offset.set(v)
+        }
+        fn load_sleep_interval_s(&self) -> u64 {
+            self.sleep_interval.get()
+        }
+        fn store_sleep_interval_s(&self, v: u64) {
+            self.sleep_interval.set(v)
+        }
+    }
+
+    #[test]
+    fn first_tip_is_always_counted() {
+        let rtc = MockRtc::default();
+        assert!(rtc.inc_rain_tips(100, 15));
+        assert_eq!(rtc.load_rain_tips(), 1);
+        assert_eq!(rtc.load_last_tip(), 100);
+    }
+
+    #[test]
+    fn tips_inside_debounce_window_are_ignored() {
+        let rtc = MockRtc::default();
+        rtc.inc_rain_tips(100, 15);
+        assert!(!rtc.inc_rain_tips(110, 15));
+        assert!(!rtc.inc_rain_tips(115, 15));
+        assert_eq!(rtc.load_rain_tips(), 1);
+        assert_eq!(rtc.load_last_tip(), 100);
+
+        assert!(rtc.inc_rain_tips(116, 15));
+        assert_eq!(rtc.load_rain_tips(), 2);
+        assert_eq!(rtc.load_last_tip(), 116);
+    }
+
+    #[test]
+    fn clock_going_backwards_does_not_count() {
+        let rtc = MockRtc::default();
+        rtc.inc_rain_tips(100, 15);
+        assert!(!rtc.inc_rain_tips(50, 15));
+        assert_eq!(rtc.load_rain_tips(), 1);
+    }
+
+    #[test]
+    fn next_full_measurement_is_only_initialised_once() {
+        let rtc = MockRtc::default();
+        rtc.init_next_full_measurement(10, 1200);
+        assert_eq!(rtc.load_next_full_measurement_s(), 1210);
+        rtc.init_next_full_measurement(500, 1200);
+        assert_eq!(rtc.load_next_full_measurement_s(), 1210);
+    }
+
+    #[test]
+    fn remaining_sleep_never_reaches_zero() {
+        let rtc = MockRtc::default();
+        rtc.store_next_full_measurement_s(1210);
+        assert_eq!(rtc.remaining_sleep_s(210), 1000);
+        assert_eq!(rtc.remaining_sleep_s(1210), 1);
+        assert_eq!(rtc.remaining_sleep_s(5000), 1);
+    }
+
+    #[test]
+    fn boot_count_increments() {
+        let rtc = MockRtc::default();
+        assert_eq!(rtc.inc_boot_count(), 1);
+        assert_eq!(rtc.inc_boot_count(), 2);
+        assert_eq!(rtc.load_boot_count(), 2);
+    }
+
+    #[test]
+    fn unix_time_needs_a_synchronised_clock() {
+        let rtc = MockRtc::default();
+        assert_eq!(rtc.unix_time(100), None);
+        rtc.store_unix_offset(1_760_000_000);
+        assert_eq!(rtc.unix_time(100), Some(1_760_000_100));
+    }
+
+    #[test]
+    fn sleep_interval_falls_back_to_default() {
+        let rtc = MockRtc::default();
+        assert_eq!(rtc.sleep_interval_s(1200), 1200);
+        rtc.store_sleep_interval_s(600);
+        assert_eq!(rtc.sleep_interval_s(1200), 600);
+        rtc.store_sleep_interval_s(u64::MAX); // uninitialised memory
+        assert_eq!(rtc.sleep_interval_s(1200), 1200);
+    }
+}
@@ -0,0 +1,139 @@
+//! Values protected by a CRC in RTC memory.
+//!
+//! RTC memory survives deep sleep but holds garbage after a power loss.
+//! `Sealed` keeps a value next to the CRC of its bytes and a tag of its type,
+//! so that neither garbage nor zeroed memory is taken for data: a value found
+//! corrupted reads as empty and starts over from empty on the next update.
+
+use crate::crc::Crc32;
+
+/// Value that can be kept in a `Sealed`.
+///
+/// Implementors only hold integers and floats, for which any bit pattern
+/// left in memory is a valid, if meaningless, value.
+pub trait Seal: Sized {
+    /// Non-zero, so that zeroed memory is not a valid value.
+    const TAG: u8;
+    /// Value before anything was recorded, also used in place of a lost one.
+    const EMPTY: Self;
+
+    /// Feed the fields to `crc`, in a fixed order and without padding.
+    fn checksum(&self, crc: &mut Crc32);
+
+    /// Invariants the CRC cannot vouch for, such as an index in range.
+    fn is_consistent(&self) -> bool {
+        true
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct Sealed<T> {
+    value: T,
+    crc: u32,
+}
+
+impl<T: Seal> Default for Sealed<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Seal> Sealed<T> {
+    /// An empty, not yet valid, value: it reads as empty until updated.
+    pub const fn new() -> Self {
+        Sealed {
+            value: T::EMPTY,
+            crc: 0,
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut crc = Crc32::new();
+        self.value.checksum(&mut crc);
+        crc.update(&[T::TAG]);
+        crc.finish()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.crc == self.checksum() && self.value.is_consistent()
+    }
+
+    /// The value, `None` when it was lost.
+    pub fn get(&self) -> Option<&T> {
+        self.is_valid().then_some(&self.value)
+    }
+
+    /// Apply `f` to the value, starting over from empty when it was lost, and
+    /// seal the result.
+    pub fn update<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> R {
+        if !self.is_valid() {
+            self.value = T::EMPTY;
+        }
+        let result = f(&mut self.value);
+        self.crc = self.checksum();
+        result
+    }
+
+    /// Change the value behind the CRC's back, as a power loss would.
+    #[cfg(test)]
+    pub(crate) fn corrupt(&mut self, f: impl FnOnce(&mut T)) {
+        f(&mut self.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Counter {
+        count: u32,
+        index: u32,
+    }
+
+    impl Seal for Counter {
+        const TAG: u8 = 0x42;
+        const EMPTY: Self = Counter { count: 0, index: 0 };
+
+        fn checksum(&self, crc: &mut Crc32) {
+            crc.update(&self.count.to_le_bytes());
+            crc.update(&self.index.to_le_bytes());
+        }
+
+        fn is_consistent(&self) -> bool {
+            self.index < 4
+        }
+    }
+
+    #[test]
+    fn zeroed_memory_is_not_valid() {
+        let sealed = Sealed::<Counter>::new();
+        assert!(!sealed.is_valid());
+        assert_eq!(sealed.get(), None);
+    }
+
+    #[test]
+    fn updates_are_sealed() {
+        let mut sealed = Sealed::<Counter>::new();
+        assert_eq!(sealed.update(|c| c.count), 0);
+        sealed.update(|c| c.count += 3);
+        assert!(sealed.is_valid());
+        assert_eq!(sealed.get(), Some(&Counter { count: 3, index: 0 }));
+    }
+
+    #[test]
+    fn corrupted_value_starts_over() {
+        let mut sealed = Sealed::<Counter>::new();
+        sealed.update(|c| c.count = 12);
+        sealed.value.count = 0xDEAD_BEEF;
+        assert_eq!(sealed.get(), None);
+        sealed.update(|c| c.count += 1);
+        assert_eq!(sealed.get().map(|c| c.count), Some(1));
+
+        // a matching CRC does not make an out of range index valid
+        sealed.update(|c| c.index = 9);
+        assert_eq!(sealed.get(), None);
+        assert_eq!(sealed.update(|c| c.index), 0);
+    }
+}
@@ -0,0 +1,417 @@
+//! Sensor abstraction.
+//!
+//! A sensor is anything that can be initialised and sampled into `Reading`s.
+//! The retry policy is shared here so that every sensor task behaves the same
+//! way when the hardware misbehaves.
+
+use crate::reading::{Quality, Quantity, Reading};
+use core::future::Future;
+
+/// Maximum number of readings produced by one sample.
+pub const MAX_READINGS: usize = 8;
+
+/// Retries granted to a sensor after its first failed attempt.
+pub const DEFAULT_RETRIES: u32 = 5;
+
+pub type Readings = heapless::Vec<Reading, MAX_READINGS>;
+
+/// Sensors driven by the firmware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SensorId {
+    Dht22,
+    Anemometer,
+    WindVane,
+    Ina219,
+}
+
+impl SensorId {
+    pub const ALL: &[SensorId] = &[
+        SensorId::Dht22,
+        SensorId::Anemometer,
+        SensorId::WindVane,
+        SensorId::Ina219,
+    ];
+
+    /// Snake case identifier, used in logs and as JSON key.
+    pub const fn name(self) -> &'static str {
+        match self {
+            SensorId::Dht22 => "dht22",
+            SensorId::Anemometer => "anemometer",
+            SensorId::WindVane => "wind_vane",
+            SensorId::Ina219 => "ina219",
+        }
+    }
+
+    /// Every quantity the sensor can report, all of them are reported as
+    /// missing when the sensor produced no data.
+    pub const fn quantities(self) -> &'static [Quantity] {
+        match self {
+            SensorId::Dht22 => &[Quantity::Temperature, Quantity::Humidity],
+            SensorId::Anemometer => &[
+                Quantity::WindSpeed,
+                Quantity::WindGust,
+                Quantity::WindLull,
+                Quantity::WindSpeedStddev,
+                Quantity::WindSpeedAverage,
+            ],
+            SensorId::WindVane => &[
+                Quantity::WindDirection,
+                Quantity::WindAngle,
+                Quantity::WindDirectionStddev,
+                Quantity::VaneMagnet,
+                Quantity::VaneAgc,
+            ],
+            SensorId::Ina219 => &[
+                Quantity::BatteryVoltage,
+                Quantity::BatteryPercentage,
+                Quantity::BatteryCurrent,
+                Quantity::BatteryPower,
+                Quantity::ShuntVoltage,
+                Quantity::ChargeIn,
+                Quantity::ChargeOut,
+            ],
+        }
+    }
+
+    /// Quantities describing the state of the sensor rather than the weather.
+    pub const fn status_quantities(self) -> &'static [Quantity] {
+        match self {
+            SensorId::WindVane => &[Quantity::VaneMagnet, Quantity::VaneAgc],
+            _ => &[],
+        }
+    }
+
+    /// Whether `readings` hold at least one valid measurement, status
+    /// readings aside.
+    pub fn has_data(self, readings: &[Reading]) -> bool {
+        readings.iter().any(|r| {
+            r.quality != Quality::Invalid
+                && self.quantities().contains(&r.quantity)
+                && !self.status_quantities().contains(&r.quantity)
+        })
+    }
+
+    /// `missing` readings of every quantity of the sensor.
+    pub fn missing_readings(self) -> Readings {
+        self.quantities()
+            .iter()
+            .map(|&q| Reading::missing(q))
+            .take(MAX_READINGS)
+            .collect()
+    }
+
+    /// Add a `missing` reading for every quantity of the sensor absent from
+    /// `readings`.
+    pub fn fill_missing(self, readings: &mut Readings) {
+        for &q in self.quantities() {
+            if !readings.iter().any(|r| r.quantity == q) {
+                readings.push(Reading::missing(q)).ok();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SensorError {
+    /// The device did not answer or rejected its configuration.
+    Init,
+    /// A measurement could not be read.
+    Read,
+    /// The sampling window ended without any valid measurement.
+    NoData,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    /// Not initialised yet.
+    Unknown,
+    Ok,
+    Failing(SensorError),
+}
+
+impl Health {
+    /// Record the outcome of an operation and pass it through.
+    pub fn track(&mut self, res: Result<(), SensorError>) -> Result<(), SensorError> {
+        *self = match res {
+            Ok(()) => Health::Ok,
+            Err(e) => Health::Failing(e),
+        };
+        res
+    }
+}
+
+#[allow(
+    async_fn_in_trait,
+    reason = "sensors are driven by a single-threaded executor"
+)]
+pub trait Sensor {
+    const ID: SensorId;
+    /// Name used in logs.
+    const NAME: &'static str = Self::ID.name();
+
+    /// Retries after a failed `init` or `sample`. Windowed sensors that take
+    /// the whole active window to sample should not be retried.
+    const RETRIES: u32 = DEFAULT_RETRIES;
+
+    async fn init(&mut self) -> Result<(), SensorError>;
+
+    /// Take one measurement, appending its readings to `readings`.
+    async fn sample(&mut self, readings: &mut Readings) -> Result<(), SensorError>;
+
+    fn health(&self) -> Health;
+}
+
+/// Initialise `sensor`, awaiting `delay` between failed attempts.
+pub async fn init_with_retry<S, F>(
+    sensor: &mut S,
+    mut delay: impl FnMut() -> F,
+) -> Result<(), SensorError>
+where
+    S: Sensor,
+    F: Future<Output = ()>,
+{
+    let mut retry = 0;
+    loop {
+        match sensor.init().await {
+            Ok(()) => return Ok(()),
+            Err(e) if retry >= S::RETRIES => return Err(e),
+            Err(_) => {}
+        }
+        retry += 1;
+        delay().await;
+    }
+}
+
+/// Sample `sensor`, awaiting `delay` between failed attempts.
+///
+/// Readings of a failed attempt are discarded, except the status readings of
+/// the last one, which tell why it failed.
+pub async fn sample_with_retry<S, F>(
+    sensor: &mut S,
+    readings: &mut Readings,
+    mut delay: impl FnMut() -> F,
+) -> Result<(), SensorError>
+where
+    S: Sensor,
+    F: Future<Output = ()>,
+{
+    let mut retry = 0;
+    loop {
+        readings.clear();
+        match sensor.sample(readings).await {
+            Ok(()) => return Ok(()),
+            Err(e) if retry >= S::RETRIES => {
+                let status = S::ID.status_quantities();
+                readings.retain(|r| status.contains(&r.quantity));
+                return Err(e);
+            }
+            Err(_) => {}
+        }
+        retry += 1;
+        delay().await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    /// Drive a future that never actually waits to completion.
+    pub fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    /// Fails a given number of times before succeeding.
+    struct Flaky {
+        failures: u32,
+        attempts: u32,
+        health: Health,
+    }
+
+    impl Flaky {
+        fn new(failures: u32) -> Self {
+            Flaky {
+                failures,
+                attempts: 0,
+                health: Health::Unknown,
+            }
+        }
+
+        fn attempt(&mut self, err: SensorError) -> Result<(), SensorError> {
+            self.attempts += 1;
+            let res = if self.attempts <= self.failures {
+                Err(err)
+            } else {
+                Ok(())
+            };
+            self.health.track(res)
+        }
+    }
+
+    impl Sensor for Flaky {
+        const ID: SensorId = SensorId::Dht22;
+        const RETRIES: u32 = 2;
+
+        async fn init(&mut self) -> Result<(), SensorError> {
+            self.attempt(SensorError::Init)
+        }
+
+        async fn sample(&mut self, readings: &mut Readings) -> Result<(), SensorError> {
+            readings
+                .push(Reading::new(Quantity::Temperature, 20.0))
+                .unwrap();
+            self.attempt(SensorError::Read)
+        }
+
+        fn health(&self) -> Health {
+            self.health
+        }
+    }
+
+    #[test]
+    fn init_succeeds_within_retries() {
+        let mut sensor = Flaky::new(2);
+        let mut delays = 0;
+        let res = block_on(init_with_retry(&mut sensor, || {
+            delays += 1;
+            async {}
+        }));
+        assert_eq!(res, Ok(()));
+        assert_eq!(sensor.attempts, 3);
+        assert_eq!(delays, 2);
+        assert_eq!(sensor.health(), Health::Ok);
+    }
+
+    #[test]
+    fn init_gives_up_after_retries() {
+        let mut sensor = Flaky::new(10);
+        let res = block_on(init_with_retry(&mut sensor, || async {}));
+        assert_eq!(res, Err(SensorError::Init));
+        assert_eq!(sensor.attempts, 3);
+        assert_eq!(sensor.health(), Health::Failing(SensorError::Init));
+    }
+
+    #[test]
+    fn sample_keeps_only_the_successful_attempt() {
+        let mut sensor = Flaky::new(1);
+        let mut readings = Readings::new();
+        let res = block_on(sample_with_retry(&mut sensor, &mut readings, || async {}));
+        assert_eq!(res, Ok(()));
+        assert_eq!(readings.len(), 1);
+    }
+
+    #[test]
+    fn missing_readings_are_invalid() {
+        for &id in SensorId::ALL {
+            let readings = id.missing_readings();
+            assert_eq!(readings.len(), id.quantities().len());
+            assert!(readings.iter().all(|r| r.quality == Quality::Invalid));
+        }
+        assert_eq!(Flaky::NAME, "dht22");
+    }
+
+    #[test]
+    fn every_sensor_quantity_is_listed_once() {
+        let mut listed: std::vec::Vec<Quantity> = SensorId::ALL
+            .iter()
+            .flat_map(|id| id.quantities().iter().copied())
+            .collect();
+        listed.sort_by_key(|&q| q as u8);
+        // rain is counted by the RTC, not by a sensor task
+        let rtc = [
+            Quantity::Rain,
+            Quantity::RainRate,
+            Quantity::RainLastHour,
+            Quantity::RainLast24h,
+            Quantity::RainToday,
+            Quantity::RainLastTip,
+            Quantity::RainSensor,
+        ];
+        let expected: std::vec::Vec<Quantity> = Quantity::ALL
+            .iter()
+            .copied()
+            .filter(|q| !rtc.contains(q))
+            .collect();
+        assert_eq!(listed, expected);
+        for &id in SensorId::ALL {
+            assert!(id.quantities().len() <= MAX_READINGS);
+            assert!(
+                id.status_quantities()
+                    .iter()
+                    .all(|q| id.quantities().contains(q))
+            );
+        }
+    }
+
+    #[test]
+    fn status_readings_are_no_data() {
+        let vane = SensorId::WindVane;
+        let mut readings = vane.missing_readings();
+        assert!(!vane.has_data(&readings));
+        readings[3] = Reading::new(Quantity::VaneMagnet, "missing");
+        assert!(!vane.has_data(&readings));
+        readings[1] = Reading::new(Quantity::WindAngle, 90.0);
+        assert!(vane.has_data(&readings));
+        // readings of another sensor do not count
+        assert!(!SensorId::Dht22.has_data(&readings));
+    }
+
+    #[test]
+    fn failed_sample_leaves_no_readings() {
+        let mut sensor = Flaky::new(10);
+        let mut readings = Readings::new();
+        let res = block_on(sample_with_retry(&mut sensor, &mut readings, || async {}));
+        assert_eq!(res, Err(SensorError::Read));
+        assert!(readings.is_empty());
+    }
+
+    /// Vane without magnet: reports its status but no direction.
+    struct NoMagnet;
+
+    impl Sensor for NoMagnet {
+        const ID: SensorId = SensorId::WindVane;
+        const RETRIES: u32 = 0;
+
+        async fn init(&mut self) -> Result<(), SensorError> {
+            Ok(())
+        }
+
+        async fn sample(&mut self, readings: &mut Readings) -> Result<(), SensorError> {
+            readings
+                .push(Reading::new(Quantity::VaneMagnet, "missing"))
+                .unwrap();
+            readings
+                .push(Reading::missing(Quantity::WindAngle))
+                .unwrap();
+            Err(SensorError::NoData)
+        }
+
+        fn health(&self) -> Health {
+            Health::Failing(SensorError::NoData)
+        }
+    }
+
+    #[test]
+    fn failed_sample_keeps_the_status_readings() {
+        let mut readings = Readings::new();
+        let res = block_on(sample_with_retry(&mut NoMagnet, &mut readings, || async {}));
+        assert_eq!(res, Err(SensorError::NoData));
+        assert_eq!(readings.len(), 1);
+
+        let vane = SensorId::WindVane;
+        vane.fill_missing(&mut readings);
+        assert_eq!(readings.len(), vane.quantities().len());
+        assert_eq!(readings[0], Reading::new(Quantity::VaneMagnet, "missing"));
+        assert!(readings[1..].iter().all(|r| r.quality == Quality::Invalid));
+        assert!(!vane.has_data(&readings));
+    }
+}
@@ -0,0 +1,698 @@
+//! Runtime settings persisted in flash.
+//!
+//! The compile-time configuration provides the defaults, values stored in
+//! flash override them. The stored image is a header followed by key/value
+//! records:
+//!
+//! ```text
+//! magic: u32 | version: u16 | length: u16 | crc32: u32 | records...
+//! record: key: u8 | length: u8 | value
+//! ```
+//!
+//! Integers are little endian and strings UTF-8. Unknown keys are skipped and
+//! missing ones keep their default, so fields can be added without bumping
+//! `VERSION`; it only changes when the meaning of an existing key does.
+//!
+//! Only the fields that differ from the defaults are stored: the others keep
+//! following the defaults of whichever firmware reads the image, so a new
+//! build can still change them.
+
+use crate::anemometer::{AnemometerCalibration, AnemometerModel, Transfer, parse_table};
+use crate::battery::{BatteryModel, Chemistry, ShuntCalibration, parse_curve};
+use crate::charge::DutyCycle;
+use crate::crc::crc32;
+use crate::power::PowerThresholds;
+use crate::rain::RainGauge;
+use crate::wifi::MAX_NETWORKS;
+use crate::wind::VaneCalibration;
+use heapless::String;
+
+pub const MAGIC: u32 = 0x4643_5357; // "WSCF"
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = 12;
+/// Longest record: key, length and a value of up to 255 bytes.
+const RECORD_MAX_LEN: usize = 2 + u8::MAX as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingsError {
+    /// Nothing was ever stored, the flash is erased
+    Empty,
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    BadCrc,
+    /// A stored value does not fit its field
+    InvalidValue(u8),
+    /// The encoding buffer is too small
+    NoSpace,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub ssid: String<32>,
+    pub wifi_pass: String<64>,
+    pub broker_url: String<64>,
+    pub broker_ip: String<40>,
+    pub broker_port: u16,
+    pub mqtt_tls: bool,
+    pub broker_tls_port: u16,
+    pub tls_server_name: String<64>,
+    pub mqtt_user: String<32>,
+    pub mqtt_pass: String<64>,
+    pub topic: String<48>,
+    pub deep_sleep_dur_secs: u64,
+    pub main_task_dur_secs: u64,
+    pub task_dur_secs: u64,
+    pub watchdog_timeout_secs: u64,
+    pub ota_url: String<128>,
+    pub rain_debounce_s: u64,
+    pub state_json: bool,
+    pub ha_discovery: bool,
+    pub ha_prefix: String<32>,
+    pub device_id: String<32>,
+    pub device_name: String<32>,
+    pub mqtt_lwt: bool,
+    pub mqtt_keep_alive_secs: u16,
+    pub ntp_server: String<64>,
+    pub mqtt_commands: bool,
+    pub provision_pass: String<64>,
+    pub ssid_2: String<32>,
+    pub wifi_pass_2: String<64>,
+    pub ssid_3: String<32>,
+    pub wifi_pass_3: String<64>,
+    pub wifi_scan: bool,
+    /// `address/prefix_len`, DHCP is used when empty
+    pub static_ip: String<18>,
+    pub static_gateway: String<15>,
+    pub static_dns: String<15>,
+    pub low_soc_percent: u8,
+    pub low_soc_sleep_secs: u64,
+    pub survival_mv: u16,
+    pub survival_sleep_secs: u64,
+    pub charging_ma: u16,
+    pub ina_shunt_mohm: u16,
+    pub ina_max_current_ma: u16,
+    pub ina_bus_offset_mv: i16,
+    /// Current drawn from the battery in deep sleep, for the charge counter
+    pub sleep_current_ua: u16,
+    /// `li-ion`, `lifepo4`, `nimh` or `custom`
+    pub battery_chemistry: String<8>,
+    /// Discharge curve of one cell for the `custom` chemistry, see `battery::parse_curve`
+    pub battery_curve: String<128>,
+    pub battery_cells: u8,
+    pub battery_internal_mohm: u16,
+    /// Rain collected by one tip of the bucket, in micrometers
+    pub rain_um_per_tip: u16,
+    /// `stock`, `davis`, `misol` or `custom`, the other `anemo_` fields only apply to `custom`
+    pub anemo_model: String<8>,
+    pub anemo_pulses_per_rev: u8,
+    pub anemo_mm_per_rev: u16,
+    /// Speed added once the cups turn, in mm/s
+    pub anemo_offset_mms: u16,
+    /// Speed table replacing the linear calibration when set, see `anemometer::parse_table`
+    pub anemo_table: String<128>,
+    /// Highest plausible pulse frequency, faster pulses are bounces
+    pub anemo_max_hz: u16,
+    /// Weight the vane headings by the concurrent wind speed
+    pub vane_speed_weighted: bool,
+    /// Raw AS5600 angle read with the vane pointing north, set by `calibrate_north`
+    pub vane_zero_raw: u16,
+    /// The magnet angle decreases as the vane turns clockwise
+    pub vane_mirrored: bool,
+}
+
+/// A value that can be stored in a record.
+trait Field {
+    fn write(&self, out: &mut Records<'_>, key: u8) -> Result<(), SettingsError>;
+    fn read(&mut self, bytes: &[u8]) -> Option<()>;
+}
+
+impl<const N: usize> Field for String<N> {
+    fn write(&self, out: &mut Records<'_>, key: u8) -> Result<(), SettingsError> {
+        out.put(key, self.as_bytes())
+    }
+
+    fn read(&mut self, bytes: &[u8]) -> Option<()> {
+        let s = core::str::from_utf8(bytes).ok()?;
+        *self = String::try_from(s).ok()?;
+        Some(())
+    }
+}
+
+impl Field for bool {
+    fn write(&self, out: &mut Records<'_>, key: u8) -> Result<(), SettingsError> {
+        out.put(key, &[*self as u8])
+    }
+
+    fn read(&mut self, bytes: &[u8]) -> Option<()> {
+        *self = match bytes {
+            [0] => false,
+            [1] => true,
+            _ => return None,
+        };
+        Some(())
+    }
+}
+
+macro_rules! int_field {
+    ($($t:ty),*) => {$(
+        impl Field for $t {
+            fn write(&self, out: &mut Records<'_>, key: u8) -> Result<(), SettingsError> {
+                out.put(key, &self.to_le_bytes())
+            }
+
+            fn read(&mut self, bytes: &[u8]) -> Option<()> {
+                *self = <$t>::from_le_bytes(bytes.try_into().ok()?);
+                Some(())
+            }
+        }
+    )*};
+}
+
+int_field!(u8, u16, i16, u64);
+
+/// Record writer over the encoding buffer.
+struct Records<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Records<'_> {
+    fn put(&mut self, key: u8, value: &[u8]) -> Result<(), SettingsError> {
+        let value_len = u8::try_from(value.len()).map_err(|_| SettingsError::NoSpace)?;
+        let end = self.len + 2 + value.len();
+        let out = self
+            .buf
+            .get_mut(self.len..end)
+            .ok_or(SettingsError::NoSpace)?;
+
+        out[0] = key;
+        out[1] = value_len;
+        out[2..].copy_from_slice(value);
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// Visit every field with its record key.
+    ///
+    /// Keys are part of the stored format: never reuse or renumber them.
+    fn fields(
+        &mut self,
+        mut f: impl FnMut(u8, &mut dyn Field) -> Result<(), SettingsError>,
+    ) -> Result<(), SettingsError> {
+        f(1, &mut self.ssid)?;
+        f(2, &mut self.wifi_pass)?;
+        f(3, &mut self.broker_url)?;
+        f(4, &mut self.broker_ip)?;
+        f(5, &mut self.broker_port)?;
+        f(6, &mut self.mqtt_tls)?;
+        f(7, &mut self.broker_tls_port)?;
+        f(8, &mut self.tls_server_name)?;
+        f(9, &mut self.mqtt_user)?;
+        f(10, &mut self.mqtt_pass)?;
+        f(11, &mut self.topic)?;
+        f(12, &mut self.deep_sleep_dur_secs)?;
+        f(13, &mut self.main_task_dur_secs)?;
+        f(14, &mut self.task_dur_secs)?;
+        f(15, &mut self.watchdog_timeout_secs)?;
+        f(16, &mut self.ota_url)?;
+        f(17, &mut self.rain_debounce_s)?;
+        f(18, &mut self.state_json)?;
+        f(19, &mut self.ha_discovery)?;
+        f(20, &mut self.ha_prefix)?;
+        f(21, &mut self.device_id)?;
+        f(22, &mut self.device_name)?;
+        f(23, &mut self.mqtt_lwt)?;
+        f(24, &mut self.mqtt_keep_alive_secs)?;
+        f(25, &mut self.ntp_server)?;
+        f(26, &mut self.mqtt_commands)?;
+        f(27, &mut self.provision_pass)?;
+        f(28, &mut self.ssid_2)?;
+        f(29, &mut self.wifi_pass_2)?;
+        f(30, &mut self.ssid_3)?;
+        f(31, &mut self.wifi_pass_3)?;
+        f(32, &mut self.wifi_scan)?;
+        f(33, &mut self.static_ip)?;
+        f(34, &mut self.static_gateway)?;
+        f(35, &mut self.static_dns)?;
+        f(36, &mut self.low_soc_percent)?;
+        f(37, &mut self.low_soc_sleep_secs)?;
+        f(38, &mut self.survival_mv)?;
+        f(39, &mut self.survival_sleep_secs)?;
+        f(40, &mut self.charging_ma)?;
+        f(41, &mut self.ina_shunt_mohm)?;
+        f(42, &mut self.ina_max_current_ma)?;
+        f(43, &mut self.ina_bus_offset_mv)?;
+        f(44, &mut self.battery_chemistry)?;
+        f(45, &mut self.battery_curve)?;
+        f(46, &mut self.battery_cells)?;
+        f(47, &mut self.battery_internal_mohm)?;
+        f(48, &mut self.rain_um_per_tip)?;
+        f(49, &mut self.anemo_model)?;
+        f(50, &mut self.anemo_pulses_per_rev)?;
+        f(51, &mut self.anemo_mm_per_rev)?;
+        f(52, &mut self.anemo_offset_mms)?;
+        f(53, &mut self.anemo_table)?;
+        f(54, &mut self.anemo_max_hz)?;
+        f(55, &mut self.vane_speed_weighted)?;
+        f(56, &mut self.vane_zero_raw)?;
+        f(57, &mut self.vane_mirrored)?;
+        f(58, &mut self.sleep_current_ua)
+    }
+
+    /// Wi-Fi networks (SSID and password) by priority, unused ones have an empty SSID.
+    pub fn networks(&self) -> [(&str, &str); MAX_NETWORKS] {
+        [
+            (&self.ssid, &self.wifi_pass),
+            (&self.ssid_2, &self.wifi_pass_2),
+            (&self.ssid_3, &self.wifi_pass_3),
+        ]
+    }
+
+    /// Thresholds of the power policy.
+    pub fn power_thresholds(&self) -> PowerThresholds {
+        PowerThresholds {
+            low_soc: self.low_soc_percent as f32,
+            low_soc_sleep_s: self.low_soc_sleep_secs,
+            survival_mv: self.survival_mv as f32,
+            survival_sleep_s: self.survival_sleep_secs,
+            charging_ma: self.charging_ma as f32,
+        }
+    }
+
+    /// Calibration of the battery monitor.
+    pub fn shunt_calibration(&self) -> ShuntCalibration {
+        ShuntCalibration {
+            shunt_mohm: self.ina_shunt_mohm.into(),
+            max_current_ma: self.ina_max_current_ma.into(),
+            bus_offset_mv: self.ina_bus_offset_mv.into(),
+        }
+    }
+
+    /// Duty cycle the charge counter integrates the battery current over.
+    pub fn duty_cycle(&self) -> DutyCycle {
+        DutyCycle {
+            awake_s: self.main_task_dur_secs,
+            sleep_ma: self.sleep_current_ua as f32 / 1000.0,
+        }
+    }
+
+    /// Battery the state of charge is estimated for, `None` when its description is invalid.
+    pub fn battery_model(&self) -> Option<BatteryModel> {
+        let chemistry = Chemistry::parse(&self.battery_chemistry)?;
+        let custom = match chemistry {
+            Chemistry::Custom => Some(parse_curve(&self.battery_curve)?),
+            _ => None,
+        };
+        BatteryModel::new(
+            chemistry,
+            custom,
+            self.battery_cells,
+            self.battery_internal_mohm,
+        )
+    }
+
+    /// Calibration of the rain gauge, `None` when it is invalid.
+    pub fn rain_gauge(&self) -> Option<RainGauge> {
+        RainGauge::from_um(self.rain_um_per_tip)
+    }
+
+    /// Calibration of the anemometer, `None` when its description is invalid.
+    pub fn anemometer_calibration(&self) -> Option<AnemometerCalibration> {
+        let model = AnemometerModel::parse(&self.anemo_model)?;
+        if model != AnemometerModel::Custom {
+            return AnemometerCalibration::model(model);
+        }
+
+        let transfer = if self.anemo_table.is_empty() {
+            Transfer::Linear {
+                meters_per_rev: self.anemo_mm_per_rev as f32 / 1000.0,
+                offset_ms: self.anemo_offset_mms as f32 / 1000.0,
+            }
+        } else {
+            Transfer::Table(parse_table(&self.anemo_table)?)
+        };
+        AnemometerCalibration::new(
+            self.anemo_pulses_per_rev,
+            transfer,
+            self.anemo_max_hz.into(),
+        )
+    }
+
+    /// Mounting of the wind vane, `None` when the zero is out of range.
+    pub fn vane_calibration(&self) -> Option<VaneCalibration> {
+        VaneCalibration::new(self.vane_zero_raw, self.vane_mirrored)
+    }
+
+    /// Serialise the fields that differ from `defaults` into `buf`, returning
+    /// the length of the image.
+    ///
+    /// Encoding `defaults` themselves gives an image without records, which
+    /// resets the stored settings.
+    pub fn encode(&self, defaults: &Settings, buf: &mut [u8]) -> Result<usize, SettingsError> {
+        let (header, body) = buf
+            .split_at_mut_checked(HEADER_LEN)
+            .ok_or(SettingsError::NoSpace)?;
+        let mut records = Records { buf: body, len: 0 };
+
+        // `fields` needs `&mut self` to also serve `load`
+        let mut defaults = defaults.clone();
+        self.clone().fields(|key, field| {
+            let mut value = [0u8; RECORD_MAX_LEN];
+            let value = record(field, key, &mut value)?;
+            let mut unchanged = false;
+            defaults.fields(|k, default| {
+                if k == key {
+                    let mut buf = [0u8; RECORD_MAX_LEN];
+                    unchanged = record(default, k, &mut buf)? == value;
+                }
+                Ok(())
+            })?;
+            if unchanged {
+                return Ok(());
+            }
+            field.write(&mut records, key)
+        })?;
+
+        let len = records.len;
+        let body_len = u16::try_from(len).map_err(|_| SettingsError::NoSpace)?;
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        header[6..8].copy_from_slice(&body_len.to_le_bytes());
+        header[8..12].copy_from_slice(&crc32(&records.buf[..len]).to_le_bytes());
+
+        Ok(HEADER_LEN + len)
+    }
+
+    /// Override the fields found in a stored image.
+    ///
+    /// Nothing is changed when the image is invalid.
+    pub fn load(&mut self, image: &[u8]) -> Result<(), SettingsError> {
+        let header = image.get(..HEADER_LEN).ok_or(SettingsError::Truncated)?;
+        let word =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let half = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+
+        if header.iter().all(|&b| b == 0xFF) {
+            return Err(SettingsError::Empty);
+        }
+        if word(0) != MAGIC {
+            return Err(SettingsError::BadMagic);
+        }
+        if half(4) != VERSION {
+            return Err(SettingsError::UnsupportedVersion(half(4)));
+        }
+        let body = image
+            .get(HEADER_LEN..HEADER_LEN + half(6) as usize)
+            .ok_or(SettingsError::Truncated)?;
+        if crc32(body) != word(8) {
+            return Err(SettingsError::BadCrc);
+        }
+
+        let mut loaded = self.clone();
+        let mut rest = body;
+        while let [key, len, tail @ ..] = rest {
+            let value = tail.get(..*len as usize).ok_or(SettingsError::Truncated)?;
+            rest = &tail[*len as usize..];
+
+            loaded.fields(|k, field| {
+                if k == *key {
+                    field.read(value).ok_or(SettingsError::InvalidValue(k))?;
+                }
+                Ok(())
+            })?;
+        }
+        if !rest.is_empty() {
+            return Err(SettingsError::Truncated);
+        }
+
+        *self = loaded;
+        Ok(())
+    }
+}
+
+/// The record of `field` under `key`, written in `buf`.
+fn record<'a>(
+    field: &mut dyn Field,
+    key: u8,
+    buf: &'a mut [u8; RECORD_MAX_LEN],
+) -> Result<&'a [u8], SettingsError> {
+    let mut out = Records { buf, len: 0 };
+    field.write(&mut out, key)?;
+    let len = out.len;
+    Ok(&buf[..len])
+}
+
+/// `s` truncated to the capacity of the string, on a character boundary.
+pub fn truncated<const N: usize>(s: &str) -> String<N> {
+    let mut out = String::new();
+    for c in s.chars() {
+        if out.push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub fn defaults() -> Settings {
+        Settings {
+            ssid: truncated(""),
+            wifi_pass: truncated(""),
+            broker_url: truncated(""),
+            broker_ip: truncated("192.168.1.69"),
+            broker_port: 1883,
+            mqtt_tls: false,
+            broker_tls_port: 8883,
+            tls_server_name: truncated(""),
+            mqtt_user: truncated("mqtt_user"),
+            mqtt_pass: truncated(""),
+            topic: truncated("weather_station"),
+            deep_sleep_dur_secs: 1200,
+            main_task_dur_secs: 35,
+            task_dur_secs: 30,
+            watchdog_timeout_secs: 60,
+            ota_url: truncated("url"),
+            rain_debounce_s: 15,
+            state_json: false,
+            ha_discovery: false,
+            ha_prefix: truncated("homeassistant"),
+            device_id: truncated("weather_station"),
+            device_name: truncated("Weather Station"),
+            mqtt_lwt: true,
+            mqtt_keep_alive_secs: 60,
+            ntp_server: truncated("pool.ntp.org"),
+            mqtt_commands: true,
+            provision_pass: truncated(""),
+            ssid_2: truncated(""),
+            wifi_pass_2: truncated(""),
+            ssid_3: truncated(""),
+            wifi_pass_3: truncated(""),
+            wifi_scan: false,
+            static_ip: truncated(""),
+            static_gateway: truncated(""),
+            static_dns: truncated(""),
+            low_soc_percent: 30,
+            low_soc_sleep_secs: 3600,
+            survival_mv: 3450,
+            survival_sleep_secs: 14400,
+            charging_ma: 50,
+            ina_shunt_mohm: 100,
+            ina_max_current_ma: 500,
+            ina_bus_offset_mv: 160,
+            sleep_current_ua: 1000,
+            battery_chemistry: truncated("li-ion"),
+            battery_curve: truncated(""),
+            battery_cells: 1,
+            battery_internal_mohm: 80,
+            rain_um_per_tip: 231,
+            anemo_model: truncated("stock"),
+            anemo_pulses_per_rev: 1,
+            anemo_mm_per_rev: 1050,
+            anemo_offset_mms: 0,
+            anemo_table: truncated(""),
+            anemo_max_hz: 5,
+            vane_speed_weighted: false,
+            vane_zero_raw: 0,
+            vane_mirrored: true,
+        }
+    }
+
+    fn encoded(settings: &Settings) -> Vec<u8> {
+        let mut buf = [0u8; 1024];
+        let len = settings.encode(&defaults(), &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn changed() -> Settings {
+        let mut settings = defaults();
+        settings.ssid = truncated("field-hotspot");
+        settings.deep_sleep_dur_secs = 600;
+        settings.mqtt_tls = true;
+        settings
+    }
+
+    #[test]
+    fn round_trip() {
+        let stored = changed();
+        let mut loaded = defaults();
+        loaded.load(&encoded(&stored)).unwrap();
+        assert_eq!(loaded, stored);
+    }
+
+    #[test]
+    fn only_changed_fields_are_stored() {
+        // ssid (2 + 13), deep_sleep_dur_secs (2 + 8) and mqtt_tls (2 + 1)
+        assert_eq!(encoded(&changed()).len(), HEADER_LEN + 28);
+
+        // defaults of a newer firmware apply to the fields never changed
+        let mut rebuilt = defaults();
+        rebuilt.ota_url = truncated("https://example.org/fw.bin");
+        rebuilt.deep_sleep_dur_secs = 900;
+        rebuilt.load(&encoded(&changed())).unwrap();
+        assert_eq!(rebuilt.ota_url.as_str(), "https://example.org/fw.bin");
+        assert_eq!(rebuilt.deep_sleep_dur_secs, 600);
+    }
+
+    #[test]
+    fn defaults_reset_the_image() {
+        let image = encoded(&defaults());
+        assert_eq!(image.len(), HEADER_LEN);
+        let mut settings = changed();
+        settings.load(&image).unwrap();
+        assert_eq!(settings, changed());
+    }
+
+    #[test]
+    fn battery_model() {
+        let mut settings = defaults();
+        assert_eq!(
+            settings.battery_model(),
+            Some(BatteryModel::new(Chemistry::LiIon, None, 1, 80).unwrap())
+        );
+
+        settings.battery_chemistry = truncated("custom");
+        assert_eq!(settings.battery_model(), None);
+        settings.battery_curve = truncated("7.2:100,6.0:0");
+        assert_eq!(
+            settings.battery_model().unwrap().chemistry(),
+            Chemistry::Custom
+        );
+
+        settings.battery_chemistry = truncated("lead");
+        assert_eq!(settings.battery_model(), None);
+    }
+
+    #[test]
+    fn anemometer_calibration() {
+        let mut settings = defaults();
+        assert_eq!(
+            settings.anemometer_calibration(),
+            Some(AnemometerCalibration::default())
+        );
+
+        settings.anemo_model = truncated("custom");
+        settings.anemo_pulses_per_rev = 2;
+        let linear = settings.anemometer_calibration().unwrap();
+        assert!((linear.speed_kmh(2.0) - 3.78).abs() < 0.001);
+
+        settings.anemo_table = truncated("1:3,10:40");
+        let table = settings.anemometer_calibration().unwrap();
+        assert!((table.speed_kmh(2.0) - 3.0).abs() < 0.001);
+
+        settings.anemo_table = truncated("10:40,1:3");
+        assert_eq!(settings.anemometer_calibration(), None);
+        settings.anemo_model = truncated("cups");
+        assert_eq!(settings.anemometer_calibration(), None);
+    }
+
+    #[test]
+    fn vane_calibration() {
+        let mut settings = defaults();
+        assert_eq!(
+            settings.vane_calibration(),
+            Some(VaneCalibration::default())
+        );
+        settings.vane_zero_raw = 4096;
+        assert_eq!(settings.vane_calibration(), None);
+    }
+
+    #[test]
+    fn erased_flash_keeps_defaults() {
+        let mut settings = defaults();
+        assert_eq!(settings.load(&[0xFF; 64]), Err(SettingsError::Empty));
+        assert_eq!(settings, defaults());
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut image = encoded(&changed());
+        let last = image.len() - 1;
+        image[last] ^= 1;
+        assert_eq!(defaults().load(&image), Err(SettingsError::BadCrc));
+
+        let image = encoded(&changed());
+        assert_eq!(
+            defaults().load(&image[..image.len() - 1]),
+            Err(SettingsError::Truncated)
+        );
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut image = encoded(&defaults());
+        image[4] = 2;
+        assert_eq!(
+            defaults().load(&image),
+            Err(SettingsError::UnsupportedVersion(2))
+        );
+
+        image[0] = 0;
+        assert_eq!(defaults().load(&image), Err(SettingsError::BadMagic));
+    }
+
+    #[test]
+    fn unknown_and_missing_keys() {
+        let body = [
+            200, 2, 0xAB, 0xCD, // unknown key
+            12, 8, 0x58, 0x02, 0, 0, 0, 0, 0, 0, // deep_sleep_dur_secs = 600
+        ];
+        let mut image = Vec::new();
+        image.extend_from_slice(&MAGIC.to_le_bytes());
+        image.extend_from_slice(&VERSION.to_le_bytes());
+        image.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        image.extend_from_slice(&crc32(&body).to_le_bytes());
+        image.extend_from_slice(&body);
+
+        let mut settings = defaults();
+        settings.load(&image).unwrap();
+        assert_eq!(settings.deep_sleep_dur_secs, 600);
+        assert_eq!(settings.topic.as_str(), "weather_station");
+    }
+
+    #[test]
+    fn invalid_values_leave_settings_untouched() {
+        let body = [6, 1, 7]; // mqtt_tls = 7
+        let mut image = Vec::new();
+        image.extend_from_slice(&MAGIC.to_le_bytes());
+        image.extend_from_slice(&VERSION.to_le_bytes());
+        image.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        image.extend_from_slice(&crc32(&body).to_le_bytes());
+        image.extend_from_slice(&body);
+
+        let mut settings = defaults();
+        assert_eq!(settings.load(&image), Err(SettingsError::InvalidValue(6)));
+        assert_eq!(settings, defaults());
+    }
+
+    #[test]
+    fn truncates_on_char_boundary() {
+        let s: String<4> = truncated("héllo");
+        assert_eq!(s.as_str(), "hél");
+    }
+}
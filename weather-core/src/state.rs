@@ -0,0 +1,220 @@
+//! Per wake cycle state document.
+//!
+//! `Snapshot` collects the readings of one active window and serialises them,
+//! together with some context about the station, into a single JSON object.
+//! Serialisation goes through `core::fmt::Write`, so the document can be built
+//! in a fixed size buffer without allocating.
+
+use crate::json::{write_str as write_json_str, write_value as write_json_value};
+use crate::reading::{Quantity, Reading};
+use core::fmt::{self, Write};
+
+/// Maximum number of distinct quantities kept in a snapshot.
+pub const MAX_STATE_READINGS: usize = Quantity::ALL.len();
+
+/// Room for the document of a snapshot holding every quantity.
+pub const MAX_STATE_JSON_LEN: usize = 3072;
+
+/// Station context published alongside the readings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StateContext {
+    pub timestamp: u64,
+    pub boot_count: u32,
+    pub wake_reason: &'static str,
+    pub firmware_version: &'static str,
+    pub rssi: Option<i32>,
+}
+
+/// Latest reading of every quantity measured during a window.
+#[derive(Default)]
+pub struct Snapshot {
+    readings: heapless::Vec<Reading, MAX_STATE_READINGS>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a reading, replacing any previous reading of the same quantity.
+    pub fn push(&mut self, reading: Reading) {
+        match self
+            .readings
+            .iter_mut()
+            .find(|r| r.quantity == reading.quantity)
+        {
+            Some(r) => *r = reading,
+            None => {
+                // every quantity fits, a full snapshot can only repeat one of them
+                let _ = self.readings.push(reading);
+            }
+        }
+    }
+
+    pub fn get(&self, quantity: Quantity) -> Option<&Reading> {
+        self.readings.iter().find(|r| r.quantity == quantity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.readings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readings.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.readings.clear();
+    }
+
+    /// Serialise the snapshot as a JSON object.
+    ///
+    /// ```json
+    /// {"timestamp":1260,"boot_count":3,"wake_reason":"timer","firmware":"0.1.0","rssi":-61,
+    ///  "readings":{"temperature":{"value":21.5,"unit":"°C","quality":"good","timestamp":1250}}}
+    /// ```
+    pub fn write_json<W: Write>(&self, ctx: &StateContext, w: &mut W) -> fmt::Result {
+        write!(
+            w,
+            "{{\"timestamp\":{},\"boot_count\":{},\"wake_reason\":",
+            ctx.timestamp, ctx.boot_count
+        )?;
+        write_json_str(w, ctx.wake_reason)?;
+        w.write_str(",\"firmware\":")?;
+        write_json_str(w, ctx.firmware_version)?;
+        w.write_str(",\"rssi\":")?;
+        match ctx.rssi {
+            Some(rssi) => write!(w, "{rssi}")?,
+            None => w.write_str("null")?,
+        }
+
+        w.write_str(",\"readings\":{")?;
+        for (i, reading) in self.readings.iter().enumerate() {
+            if i > 0 {
+                w.write_char(',')?;
+            }
+            write_json_str(w, reading.quantity.name())?;
+            w.write_str(":{\"value\":")?;
+            write_json_value(w, &reading.value)?;
+            w.write_str(",\"unit\":")?;
+            write_json_str(w, reading.unit.symbol())?;
+            w.write_str(",\"quality\":")?;
+            write_json_str(w, reading.quality.as_str())?;
+            write!(w, ",\"timestamp\":{}}}", reading.timestamp)?;
+        }
+        w.write_str("}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reading::{Quality, Value};
+
+    fn ctx() -> StateContext {
+        StateContext {
+            timestamp: 1260,
+            boot_count: 3,
+            wake_reason: "timer",
+            firmware_version: "0.1.0",
+            rssi: Some(-61),
+        }
+    }
+
+    #[test]
+    fn empty_snapshot() {
+        let mut out = String::new();
+        Snapshot::new().write_json(&ctx(), &mut out).unwrap();
+        assert_eq!(
+            out,
+            r#"{"timestamp":1260,"boot_count":3,"wake_reason":"timer","firmware":"0.1.0","rssi":-61,"readings":{}}"#
+        );
+    }
+
+    #[test]
+    fn readings_are_serialised_with_context() {
+        let mut snapshot = Snapshot::new();
+        snapshot.push(Reading::new(Quantity::Temperature, 21.5).with_timestamp(1250));
+        snapshot.push(
+            Reading::new(Quantity::WindDirection, "NE")
+                .with_timestamp(1255)
+                .with_quality(Quality::Degraded),
+        );
+
+        let mut out = String::new();
+        let ctx = StateContext {
+            rssi: None,
+            ..ctx()
+        };
+        snapshot.write_json(&ctx, &mut out).unwrap();
+        assert_eq!(
+            out,
+            concat!(
+                r#"{"timestamp":1260,"boot_count":3,"wake_reason":"timer","firmware":"0.1.0","rssi":null,"readings":{"#,
+                r#""temperature":{"value":21.5,"unit":"°C","quality":"good","timestamp":1250},"#,
+                r#""wind_direction":{"value":"NE","unit":"","quality":"degraded","timestamp":1255}}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn later_readings_replace_earlier_ones() {
+        let mut snapshot = Snapshot::new();
+        snapshot.push(Reading::new(Quantity::Rain, 0.0));
+        snapshot.push(Reading::new(Quantity::Rain, 0.462));
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(
+            snapshot.get(Quantity::Rain).unwrap().value,
+            Value::Number(0.462)
+        );
+    }
+
+    #[test]
+    fn missing_values_become_null() {
+        let mut snapshot = Snapshot::new();
+        snapshot.push(Reading::new(Quantity::WindAngle, f32::NAN));
+        snapshot.push(Reading::missing(Quantity::WindDirection));
+        let mut out = String::new();
+        snapshot.write_json(&ctx(), &mut out).unwrap();
+        assert!(out.contains(r#""wind_angle":{"value":null,"unit":"°","quality":"invalid","#));
+        assert!(out.contains(r#""wind_direction":{"value":null,"unit":"","quality":"invalid","#));
+    }
+
+    #[test]
+    fn full_snapshot_fits_the_budget() {
+        let mut snapshot = Snapshot::new();
+        for &q in Quantity::ALL {
+            let value = match q {
+                Quantity::WindDirection => Value::Label("NNE"),
+                Quantity::RainSensor => Value::Label("stuck"),
+                Quantity::VaneMagnet => Value::Label("too_strong"),
+                Quantity::RainLastTip => Value::Timestamp(4_102_444_800),
+                _ => Value::Number(-12345.679),
+            };
+            snapshot.push(
+                Reading::new(q, value)
+                    .with_timestamp(u32::MAX as u64)
+                    .with_quality(Quality::Degraded),
+            );
+        }
+        assert_eq!(snapshot.len(), Quantity::ALL.len());
+
+        let ctx = StateContext {
+            timestamp: u32::MAX as u64,
+            boot_count: u32::MAX,
+            wake_reason: "power_on",
+            firmware_version: "10.10.10",
+            rssi: Some(-100),
+        };
+        let mut out: heapless::String<MAX_STATE_JSON_LEN> = heapless::String::new();
+        snapshot.write_json(&ctx, &mut out).unwrap();
+    }
+
+    #[test]
+    fn overflowing_buffer_reports_an_error() {
+        let mut snapshot = Snapshot::new();
+        snapshot.push(Reading::new(Quantity::Temperature, 21.5));
+        let mut out: heapless::String<32> = heapless::String::new();
+        assert!(snapshot.write_json(&ctx(), &mut out).is_err());
+    }
+}
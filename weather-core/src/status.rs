@@ -0,0 +1,40 @@
+//! Station availability reported on `<topic>/status`.
+//!
+//! The broker publishes `OFFLINE` as last will when the connection drops
+//! unexpectedly. Before a planned deep sleep the station replaces `ONLINE`
+//! with a sleeping notice, so expected silence can be told apart from a dead
+//! device.
+
+use core::fmt::{self, Write};
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+/// Published instead of a sleeping notice when the station restarts after the window.
+pub const REBOOTING: &str = "rebooting";
+
+/// Status published right before going to deep sleep.
+///
+/// `wake_at_unix` is the expected wakeup time when the wall clock is known,
+/// otherwise only the sleep duration is reported.
+pub fn write_sleeping<W: Write>(w: &mut W, wake_at_unix: Option<u64>, sleep_s: u64) -> fmt::Result {
+    match wake_at_unix {
+        Some(epoch) => write!(w, "sleeping until {epoch}"),
+        None => write!(w, "sleeping for {sleep_s} s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleeping_status() {
+        let mut out = String::new();
+        write_sleeping(&mut out, Some(1_760_000_000), 1200).unwrap();
+        assert_eq!(out, "sleeping until 1760000000");
+
+        out.clear();
+        write_sleeping(&mut out, None, 1200).unwrap();
+        assert_eq!(out, "sleeping for 1200 s");
+    }
+}
@@ -0,0 +1,290 @@
+//! Choice of the Wi-Fi network to join, and what is remembered of it.
+
+use crate::crc::Crc32;
+use crate::sealed::Seal;
+use core::net::Ipv4Addr;
+use core::str::FromStr;
+use heapless::Vec;
+
+/// Number of networks that can be configured.
+pub const MAX_NETWORKS: usize = 3;
+
+/// Order in which the configured networks are tried.
+///
+/// `ssids` lists the networks by priority, empty entries are unused. The
+/// network joined last time (`last_good`) comes first. Networks found by a
+/// scan (`seen`, SSID and RSSI) are then ranked by signal strength, the others
+/// follow in priority order since hidden networks never show up in a scan.
+pub fn connection_order(
+    ssids: &[&str],
+    last_good: Option<usize>,
+    seen: &[(&str, i8)],
+) -> Vec<usize, MAX_NETWORKS> {
+    let configured = |i: &usize| ssids.get(*i).is_some_and(|ssid| !ssid.is_empty());
+    let rssi = |i: usize| {
+        seen.iter()
+            .filter(|(ssid, _)| *ssid == ssids[i])
+            .map(|(_, rssi)| *rssi)
+            .max()
+    };
+
+    let mut order: Vec<usize, MAX_NETWORKS> = (0..ssids.len().min(MAX_NETWORKS))
+        .filter(configured)
+        .collect();
+    order.sort_unstable_by_key(|&i| (rssi(i).is_none(), core::cmp::Reverse(rssi(i)), i));
+
+    if let Some(last) = last_good.filter(configured) {
+        order.retain(|&i| i != last);
+        let _ = order.insert(0, last);
+    }
+
+    order
+}
+
+/// Access point a network was joined through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Association {
+    /// Index of the network in the configured list
+    pub network: u8,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+}
+
+/// IPv4 configuration of the station, static or obtained through DHCP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Addressing {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl Addressing {
+    /// Parse a static configuration: `address/prefix_len`, then gateway and
+    /// DNS server, which may be empty.
+    pub fn parse(cidr: &str, gateway: &str, dns: &str) -> Option<Self> {
+        let optional = |s: &str| match s {
+            "" => Some(None),
+            s => Ipv4Addr::from_str(s).ok().map(Some),
+        };
+        let (address, prefix_len) = cidr.split_once('/')?;
+        let prefix_len = prefix_len
+            .parse()
+            .ok()
+            .filter(|len| (1..=32).contains(len))?;
+
+        Some(Addressing {
+            address: Ipv4Addr::from_str(address).ok()?,
+            prefix_len,
+            gateway: optional(gateway)?,
+            dns: optional(dns)?,
+        })
+    }
+}
+
+/// Last connection, kept `Sealed` in RTC memory so the next wake can skip the
+/// scan and DHCP.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionCache {
+    association: Association,
+    address: [u8; 4],
+    prefix_len: u8,
+    gateway: [u8; 4],
+    dns: [u8; 4],
+    /// RTC time in seconds, 0 when no lease is cached
+    lease_expires_s: u64,
+}
+
+impl Default for ConnectionCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionCache {
+    pub const fn new() -> Self {
+        ConnectionCache {
+            association: Association {
+                network: 0,
+                bssid: [0; 6],
+                channel: 0,
+            },
+            address: [0; 4],
+            prefix_len: 0,
+            gateway: [0; 4],
+            dns: [0; 4],
+            lease_expires_s: 0,
+        }
+    }
+
+    /// Access point joined last time.
+    pub fn association(&self) -> Option<Association> {
+        Some(self.association).filter(|a| (1..=14).contains(&a.channel))
+    }
+
+    /// DHCP lease obtained last time, if it has not expired at `now_s`.
+    pub fn lease(&self, now_s: u64) -> Option<Addressing> {
+        if self.association().is_none() || now_s >= self.lease_expires_s {
+            return None;
+        }
+        let optional =
+            |octets: [u8; 4]| Some(Ipv4Addr::from(octets)).filter(|ip| !ip.is_unspecified());
+
+        Some(Addressing {
+            address: Ipv4Addr::from(self.address),
+            prefix_len: self.prefix_len,
+            gateway: optional(self.gateway),
+            dns: optional(self.dns),
+        })
+    }
+
+    /// Record the access point just joined.
+    ///
+    /// The cached lease is dropped when it belongs to another network.
+    pub fn set_association(&mut self, association: Association) {
+        if self.association().map(|a| a.network) != Some(association.network) {
+            self.lease_expires_s = 0;
+        }
+        self.association = association;
+    }
+
+    /// Record the lease obtained on the associated network.
+    pub fn set_lease(&mut self, addressing: &Addressing, expires_s: u64) {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        self.address = addressing.address.octets();
+        self.prefix_len = addressing.prefix_len;
+        self.gateway = addressing.gateway.unwrap_or(unspecified).octets();
+        self.dns = addressing.dns.unwrap_or(unspecified).octets();
+        self.lease_expires_s = expires_s;
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Seal for ConnectionCache {
+    const TAG: u8 = 0xC5;
+    const EMPTY: Self = Self::new();
+
+    fn checksum(&self, crc: &mut Crc32) {
+        let a = &self.association;
+        crc.update(&[a.network]);
+        crc.update(&a.bssid);
+        crc.update(&[a.channel]);
+        crc.update(&self.address);
+        crc.update(&[self.prefix_len]);
+        crc.update(&self.gateway);
+        crc.update(&self.dns);
+        crc.update(&self.lease_expires_s.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sealed::Sealed;
+
+    #[test]
+    fn priority_order_without_scan() {
+        assert_eq!(connection_order(&["home", "", "field"], None, &[]), [0, 2]);
+        assert_eq!(connection_order(&["", "", ""], Some(1), &[]), []);
+    }
+
+    #[test]
+    fn last_good_network_comes_first() {
+        assert_eq!(
+            connection_order(&["home", "field", "spare"], Some(1), &[]),
+            [1, 0, 2]
+        );
+        // no longer configured
+        assert_eq!(
+            connection_order(&["home", "", "spare"], Some(1), &[]),
+            [0, 2]
+        );
+    }
+
+    #[test]
+    fn scanned_networks_are_ranked_by_rssi() {
+        let seen = [
+            ("spare", -70),
+            ("field", -50),
+            ("spare", -40),
+            ("other", -20),
+        ];
+        assert_eq!(
+            connection_order(&["home", "field", "spare"], None, &seen),
+            [2, 1, 0]
+        );
+        assert_eq!(
+            connection_order(&["home", "field", "spare"], Some(0), &seen),
+            [0, 2, 1]
+        );
+    }
+
+    fn association(network: u8) -> Association {
+        Association {
+            network,
+            bssid: [0x24, 0x0a, 0xc4, 0, 0, 1],
+            channel: 6,
+        }
+    }
+
+    fn addressing() -> Addressing {
+        Addressing::parse("192.168.1.50/24", "192.168.1.1", "").unwrap()
+    }
+
+    #[test]
+    fn parses_static_addressing() {
+        let a = addressing();
+        assert_eq!(a.address, Ipv4Addr::new(192, 168, 1, 50));
+        assert_eq!(a.prefix_len, 24);
+        assert_eq!(a.gateway, Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(a.dns, None);
+
+        assert_eq!(Addressing::parse("192.168.1.50", "", ""), None);
+        assert_eq!(Addressing::parse("192.168.1.50/33", "", ""), None);
+        assert_eq!(Addressing::parse("192.168.1.50/24", "gateway", ""), None);
+    }
+
+    #[test]
+    fn empty_or_corrupted_cache_is_ignored() {
+        let mut cache = Sealed::<ConnectionCache>::new();
+        assert_eq!(cache.update(|c| c.association()), None);
+        assert_eq!(cache.update(|c| c.lease(0)), None);
+
+        cache.update(|c| c.set_association(association(1)));
+        assert_eq!(cache.update(|c| c.association()), Some(association(1)));
+        cache.corrupt(|c| c.association.channel = 7);
+        assert_eq!(cache.update(|c| c.association()), None);
+    }
+
+    #[test]
+    fn lease_expires() {
+        let mut cache = ConnectionCache::new();
+        cache.set_association(association(0));
+        assert_eq!(cache.lease(0), None);
+
+        cache.set_lease(&addressing(), 3600);
+        assert_eq!(cache.lease(3599), Some(addressing()));
+        assert_eq!(cache.lease(3600), None);
+    }
+
+    #[test]
+    fn lease_is_dropped_on_another_network() {
+        let mut cache = ConnectionCache::new();
+        cache.set_association(association(0));
+        cache.set_lease(&addressing(), 3600);
+
+        // another access point of the same network keeps the lease
+        let mut roamed = association(0);
+        roamed.bssid[5] = 2;
+        cache.set_association(roamed);
+        assert_eq!(cache.lease(10), Some(addressing()));
+
+        cache.set_association(association(1));
+        assert_eq!(cache.lease(10), None);
+        assert_eq!(cache.association(), Some(association(1)));
+    }
+}
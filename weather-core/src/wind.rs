@@ -0,0 +1,385 @@
+//! Wind speed and direction conversions.
+//!
+//! The stock anemometer produces one pulse per rotation (see `anemometer` for
+//! the calibration of other models) and the AS5600 encoder of the wind vane
+//! reports a 12 bit raw angle. A `VaneCalibration` turns it into a heading:
+//! the raw angle read when the vane points north is the zero, and a vane
+//! mounted mirrored (the stock one) turns the magnet the other way around.
+//! The AS5600 status register tells whether the magnet can be trusted.
+//!
+//! Headings wrap around at north, so they are averaged as unit vectors: the
+//! mean of 350° and 10° is 0°, not 180°.
+
+use core::f32::consts::PI;
+
+/// Distance travelled by the stock cups during one rotation, in meters.
+pub const METERS_PER_ROTATION: f32 = 1.05;
+
+/// Resolution of the AS5600 raw angle register.
+pub const AS5600_RESOLUTION: f32 = 4096.0;
+
+/// Convert a raw AS5600 reading to degrees.
+pub fn raw_to_degrees(raw: u16) -> f32 {
+    (raw as f32) * (360.0 / AS5600_RESOLUTION)
+}
+
+/// Compensate the mirrored mounting of the vane.
+pub fn invert_angle(avg_angle: f32) -> f32 {
+    (360.0 - avg_angle) % 360.0
+}
+
+/// Mounting of the wind vane.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VaneCalibration {
+    zero_raw: u16,
+    mirrored: bool,
+}
+
+impl Default for VaneCalibration {
+    /// The stock vane: magnet zero facing north, mounted mirrored.
+    fn default() -> Self {
+        VaneCalibration {
+            zero_raw: 0,
+            mirrored: true,
+        }
+    }
+}
+
+impl VaneCalibration {
+    /// A vane reading `zero_raw` when pointing north, whose angle decreases
+    /// clockwise when `mirrored`. `None` when `zero_raw` is not a 12 bit
+    /// angle.
+    pub fn new(zero_raw: u16, mirrored: bool) -> Option<Self> {
+        ((zero_raw as f32) < AS5600_RESOLUTION).then_some(VaneCalibration { zero_raw, mirrored })
+    }
+
+    /// Heading in degrees clockwise from north, in `[0, 360)`.
+    pub fn heading(&self, raw: u16) -> f32 {
+        // 12 bit subtraction, wrapping around at the zero
+        let from_zero = raw_to_degrees(raw.wrapping_sub(self.zero_raw) & 0x0FFF);
+        if self.mirrored {
+            invert_angle(from_zero)
+        } else {
+            from_zero
+        }
+    }
+}
+
+/// Magnet of the vane as seen by the AS5600.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MagnetStatus {
+    Ok,
+    /// No magnet in front of the sensor, the angle is meaningless.
+    Missing,
+    /// Detected, but the gain control is at its maximum.
+    TooWeak,
+    /// Detected, but the gain control is at its minimum.
+    TooStrong,
+}
+
+impl MagnetStatus {
+    /// Decode the `STATUS` register: MD (bit 5) magnet detected, ML (bit 4)
+    /// too weak, MH (bit 3) too strong.
+    pub fn from_register(status: u8) -> Self {
+        if status & 0x20 == 0 {
+            MagnetStatus::Missing
+        } else if status & 0x10 != 0 {
+            MagnetStatus::TooWeak
+        } else if status & 0x08 != 0 {
+            MagnetStatus::TooStrong
+        } else {
+            MagnetStatus::Ok
+        }
+    }
+
+    /// Whether the angle can be read at all, maybe with a reduced accuracy.
+    pub fn detected(self) -> bool {
+        self != MagnetStatus::Missing
+    }
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            MagnetStatus::Ok => "ok",
+            MagnetStatus::Missing => "missing",
+            MagnetStatus::TooWeak => "too_weak",
+            MagnetStatus::TooStrong => "too_strong",
+        }
+    }
+}
+
+/// Labels of the 16 compass points, clockwise from north.
+pub const COMPASS_POINTS: [&str; 16] = [
+    "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW", "NW",
+    "NNW",
+];
+
+/// Map a heading in degrees, clockwise from north, to the nearest compass
+/// point. Each point covers 22.5°, north spans 348.75° to 11.25°. `None` for
+/// a non finite heading.
+pub fn compass_point(heading: f32) -> Option<&'static str> {
+    if !heading.is_finite() {
+        return None;
+    }
+    let sector = libm::roundf(normalize(heading) / 22.5) as usize;
+    Some(COMPASS_POINTS[sector % COMPASS_POINTS.len()])
+}
+
+/// Bring a heading into `[0, 360)`.
+fn normalize(heading: f32) -> f32 {
+    let heading = heading % 360.0;
+    if heading < 0.0 {
+        heading + 360.0
+    } else {
+        heading
+    }
+}
+
+/// Mean heading of a window and its spread, in degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirectionStats {
+    pub mean_deg: f32,
+    /// Circular standard deviation, from 0 for a steady vane to 103.9 for
+    /// headings spread evenly around the compass.
+    pub stddev_deg: f32,
+}
+
+/// Circular mean of the headings sampled during a window.
+///
+/// Each heading is added as a unit vector, scaled by the wind speed that blew
+/// it when the average is speed weighted. A calm vane points anywhere, so
+/// weighting keeps it from dragging the mean; when no sample had any weight,
+/// the plain mean is used instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DirectionAverage {
+    count: u32,
+    sin: f32,
+    cos: f32,
+    weight: f32,
+    weighted_sin: f32,
+    weighted_cos: f32,
+}
+
+impl DirectionAverage {
+    pub const fn new() -> Self {
+        DirectionAverage {
+            count: 0,
+            sin: 0.0,
+            cos: 0.0,
+            weight: 0.0,
+            weighted_sin: 0.0,
+            weighted_cos: 0.0,
+        }
+    }
+
+    /// Add a heading in degrees, with the `weight` of the wind at that time
+    /// (e.g. the anemometer pulses since the previous sample, 1 when
+    /// unweighted).
+    pub fn push(&mut self, heading: f32, weight: f32) {
+        if !heading.is_finite() || !weight.is_finite() || weight < 0.0 {
+            return;
+        }
+        let rad = heading * PI / 180.0;
+        let (sin, cos) = (libm::sinf(rad), libm::cosf(rad));
+        self.count += 1;
+        self.sin += sin;
+        self.cos += cos;
+        self.weight += weight;
+        self.weighted_sin += weight * sin;
+        self.weighted_cos += weight * cos;
+    }
+
+    /// Number of headings added.
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Mean heading in `[0, 360)` and its spread, `None` without any heading.
+    ///
+    /// The spread is the Yamartino estimate of the standard deviation of the
+    /// wind direction, which stays bounded when the headings cancel out.
+    pub fn finish(&self) -> Option<DirectionStats> {
+        if self.count == 0 {
+            return None;
+        }
+        let (sin, cos) = if self.weight > 0.0 {
+            (
+                self.weighted_sin / self.weight,
+                self.weighted_cos / self.weight,
+            )
+        } else {
+            (self.sin / self.count as f32, self.cos / self.count as f32)
+        };
+
+        let mean_deg = normalize(libm::atan2f(sin, cos) * 180.0 / PI);
+        // rounding can push the mean resultant length slightly past 1
+        let epsilon = libm::sqrtf((1.0 - (sin * sin + cos * cos)).max(0.0));
+        let stddev_rad = libm::asinf(epsilon)
+            * (1.0 + (2.0 / libm::sqrtf(3.0) - 1.0) * epsilon * epsilon * epsilon);
+        Some(DirectionStats {
+            mean_deg,
+            stddev_deg: stddev_rad * 180.0 / PI,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reading::Quantity;
+
+    #[test]
+    fn magnet_statuses_are_labels() {
+        for status in [
+            MagnetStatus::Ok,
+            MagnetStatus::Missing,
+            MagnetStatus::TooWeak,
+            MagnetStatus::TooStrong,
+        ] {
+            assert!(Quantity::VaneMagnet.labels().contains(&status.as_str()));
+        }
+    }
+
+    #[test]
+    fn raw_angle_to_degrees() {
+        assert_eq!(raw_to_degrees(0), 0.0);
+        assert_eq!(raw_to_degrees(1024), 90.0);
+        assert_eq!(raw_to_degrees(2048), 180.0);
+    }
+
+    #[test]
+    fn inverts_angles() {
+        assert_eq!(invert_angle(0.0), 0.0);
+        assert_eq!(invert_angle(90.0), 270.0);
+        assert_eq!(invert_angle(270.0), 90.0);
+    }
+
+    #[test]
+    fn default_calibration_inverts_angles() {
+        let vane = VaneCalibration::default();
+        assert_eq!(vane.heading(0), 0.0);
+        assert_eq!(vane.heading(1024), 270.0);
+        assert_eq!(vane.heading(3072), 90.0);
+    }
+
+    #[test]
+    fn calibration_offsets_the_zero() {
+        let vane = VaneCalibration::new(1024, false).unwrap();
+        assert_eq!(vane.heading(1024), 0.0);
+        assert_eq!(vane.heading(2048), 90.0);
+        // wraps around below the zero
+        assert_eq!(vane.heading(0), 270.0);
+
+        let mirrored = VaneCalibration::new(4000, true).unwrap();
+        assert_eq!(mirrored.heading(4000), 0.0);
+        assert_eq!(mirrored.heading(3000), raw_to_degrees(1000));
+        assert_eq!(mirrored.heading(2976), 90.0);
+
+        assert_eq!(VaneCalibration::new(4096, true), None);
+    }
+
+    #[test]
+    fn decodes_magnet_status() {
+        assert_eq!(MagnetStatus::from_register(0x20), MagnetStatus::Ok);
+        assert_eq!(MagnetStatus::from_register(0x30), MagnetStatus::TooWeak);
+        assert_eq!(MagnetStatus::from_register(0x28), MagnetStatus::TooStrong);
+        assert_eq!(MagnetStatus::from_register(0x00), MagnetStatus::Missing);
+        assert_eq!(MagnetStatus::from_register(0x10), MagnetStatus::Missing);
+        // unrelated bits are ignored
+        assert_eq!(MagnetStatus::from_register(0xC7 | 0x20), MagnetStatus::Ok);
+        assert!(MagnetStatus::TooStrong.detected());
+        assert!(!MagnetStatus::Missing.detected());
+    }
+
+    #[test]
+    fn compass_points() {
+        assert_eq!(compass_point(0.0), Some("N"));
+        assert_eq!(compass_point(11.0), Some("N"));
+        assert_eq!(compass_point(12.0), Some("NNE"));
+        assert_eq!(compass_point(45.0), Some("NE"));
+        assert_eq!(compass_point(90.0), Some("E"));
+        assert_eq!(compass_point(202.5), Some("SSW"));
+        assert_eq!(compass_point(337.5), Some("NNW"));
+        assert_eq!(compass_point(349.0), Some("N"));
+        assert_eq!(compass_point(359.9), Some("N"));
+        assert_eq!(compass_point(360.0), Some("N"));
+        assert_eq!(compass_point(-90.0), Some("W"));
+        assert_eq!(compass_point(f32::NAN), None);
+    }
+
+    /// Smallest angle between two headings.
+    fn angle_between(a: f32, b: f32) -> f32 {
+        let d = normalize(a - b);
+        d.min(360.0 - d)
+    }
+
+    fn average(headings: &[(f32, f32)]) -> DirectionStats {
+        let mut average = DirectionAverage::new();
+        for &(heading, weight) in headings {
+            average.push(heading, weight);
+        }
+        average.finish().unwrap()
+    }
+
+    #[test]
+    fn averages_across_north() {
+        let stats = average(&[(350.0, 1.0), (10.0, 1.0)]);
+        assert!(angle_between(stats.mean_deg, 0.0) < 0.01);
+        assert!((0.0..360.0).contains(&stats.mean_deg));
+        assert_eq!(compass_point(stats.mean_deg), Some("N"));
+        assert!((stats.stddev_deg - 10.0).abs() < 0.2);
+
+        let stats = average(&[(355.0, 1.0), (359.0, 1.0), (3.0, 1.0), (7.0, 1.0)]);
+        assert!(angle_between(stats.mean_deg, 1.0) < 0.01);
+    }
+
+    #[test]
+    fn averages_away_from_north() {
+        let stats = average(&[(80.0, 1.0), (100.0, 1.0)]);
+        assert!(angle_between(stats.mean_deg, 90.0) < 0.01);
+        let stats = average(&[(170.0, 1.0), (190.0, 1.0), (180.0, 1.0)]);
+        assert!(angle_between(stats.mean_deg, 180.0) < 0.01);
+    }
+
+    #[test]
+    fn steady_vane_has_no_spread() {
+        let stats = average(&[(270.0, 1.0); 10]);
+        assert!(angle_between(stats.mean_deg, 270.0) < 0.01);
+        assert!(stats.stddev_deg < 0.5);
+    }
+
+    #[test]
+    fn opposite_headings_have_the_largest_spread() {
+        let stats = average(&[(0.0, 1.0), (90.0, 1.0), (180.0, 1.0), (270.0, 1.0)]);
+        assert!((stats.stddev_deg - 103.92).abs() < 0.1);
+    }
+
+    #[test]
+    fn weights_favour_the_strong_wind() {
+        let stats = average(&[(90.0, 3.0), (180.0, 1.0)]);
+        assert!(angle_between(stats.mean_deg, 108.43) < 0.05);
+        // the calm samples do not count
+        let stats = average(&[(350.0, 2.0), (10.0, 2.0), (180.0, 0.0)]);
+        assert!(angle_between(stats.mean_deg, 0.0) < 0.01);
+    }
+
+    #[test]
+    fn calm_window_falls_back_to_the_plain_mean() {
+        let stats = average(&[(350.0, 0.0), (20.0, 0.0)]);
+        assert!(angle_between(stats.mean_deg, 5.0) < 0.01);
+    }
+
+    #[test]
+    fn empty_and_invalid_headings() {
+        assert_eq!(DirectionAverage::new().finish(), None);
+        let mut average = DirectionAverage::new();
+        average.push(f32::NAN, 1.0);
+        average.push(90.0, f32::NAN);
+        assert!(average.is_empty());
+        average.push(90.0, 1.0);
+        assert_eq!(average.len(), 1);
+    }
+}